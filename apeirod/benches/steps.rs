@@ -0,0 +1,95 @@
+//! What a proc's first step (`proc_new`) and the steps after it cost, for a
+//! warm engine as started by `apeirod` and for one that loads and compiles
+//! the engine runtime on every step.
+
+use apeiro_engine::{compile_engine_runtime, db_sqlite::Db, get_engine_runtime, DEngine};
+use apeiro_internal_api::{ModuleNewRequest, ProcNewRequest, ProcSendRequest};
+use criterion::{criterion_group, criterion_main, Criterion};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use tokio::runtime::Runtime;
+
+const COUNTER_SRC: &str = r#"
+export default function counter() {
+    let count = 0;
+    while (true) {
+        $recv({});
+        count = count + 1;
+    }
+}
+"#;
+
+fn start_dengine(rt: &Runtime, warm: bool) -> (DEngine, String) {
+    let store = std::env::temp_dir().join(format!("apeiro-bench-{}.db", nanoid::nanoid!()));
+    let db = Box::new(Db {
+        pool: Pool::builder()
+            .build(SqliteConnectionManager::file(store))
+            .unwrap(),
+        retention: Default::default(),
+    });
+    let (dengine, mut event_loop) = if warm {
+        DEngine::new(Some(get_engine_runtime), db)
+    } else {
+        DEngine::new_without_base_snapshot(Some(compile_engine_runtime), db)
+    }
+    .unwrap();
+    rt.spawn(async move { event_loop.run().await });
+
+    let module_id = rt
+        .block_on(dengine.module_new(ModuleNewRequest {
+            name: Some("counter".into()),
+            src: COUNTER_SRC.into(),
+            singleton: None,
+            src_is_compiled: None,
+            limits: None,
+            on_error: None,
+            retry: None,
+        }))
+        .unwrap();
+    (dengine, module_id)
+}
+
+fn proc_new(rt: &Runtime, dengine: &DEngine, module_id: &str) -> String {
+    rt.block_on(dengine.proc_new(ProcNewRequest {
+        module_id: module_id.to_string(),
+        name: None,
+        version: None,
+        resume_mode: None,
+    }))
+    .unwrap()
+    .id
+}
+
+fn bench_proc_new(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("proc_new");
+    for (name, warm) in [("cold", false), ("warm", true)] {
+        let (dengine, module_id) = start_dengine(&rt, warm);
+        group.bench_function(name, |b| b.iter(|| proc_new(&rt, &dengine, &module_id)));
+    }
+    group.finish();
+}
+
+fn bench_step(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("step");
+    for (name, warm) in [("cold", false), ("warm", true)] {
+        let (dengine, module_id) = start_dengine(&rt, warm);
+        let proc_id = proc_new(&rt, &dengine, &module_id);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rt.block_on(dengine.proc_send_and_watch_step_result(
+                    proc_id.clone(),
+                    ProcSendRequest {
+                        msg: serde_json::json!({ "tick": true }),
+                    },
+                ))
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_proc_new, bench_step);
+criterion_main!(benches);
@@ -99,6 +99,163 @@ async fn proc_get_debug(req: HttpRequest, dengine: web::Data<DEngine>) -> impl R
     Ok::<_, actix_web::Error>(web::Json(res))
 }
 
+#[post("/proc/{proc_id}/explain")]
+async fn proc_explain(
+    req: HttpRequest,
+    body: web::Json<ProcSendRequest>,
+    dengine: web::Data<DEngine>,
+) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+
+    let res = dengine
+        .proc_explain(proc_id, body.into_inner().msg)
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[get("/proc/{proc_id}/mbox")]
+async fn proc_mbox(req: HttpRequest, dengine: web::Data<DEngine>) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+
+    let res = dengine.proc_mbox(proc_id).await.map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[delete("/proc/{proc_id}/mbox")]
+async fn proc_mbox_purge(req: HttpRequest, dengine: web::Data<DEngine>) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+
+    let res = dengine.proc_mbox_purge(proc_id).await.map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[get("/proc/{proc_id}/steps")]
+async fn proc_history(req: HttpRequest, dengine: web::Data<DEngine>) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+
+    let res = dengine.proc_history(proc_id).await.map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[get("/proc/{proc_id}/steps/{step_id}")]
+async fn proc_step(req: HttpRequest, dengine: web::Data<DEngine>) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+    let step_id: u64 = req
+        .match_info()
+        .get("step_id")
+        .ok_or(ErrorBadRequest("no step id"))?
+        .parse()
+        .map_err(ErrorBadRequest)?;
+
+    let res = dengine
+        .proc_step(proc_id, step_id)
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[post("/proc/{proc_id}/steps/{step_id}/replay")]
+async fn proc_step_replay(req: HttpRequest, dengine: web::Data<DEngine>) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+    let step_id: u64 = req
+        .match_info()
+        .get("step_id")
+        .ok_or(ErrorBadRequest("no step id"))?
+        .parse()
+        .map_err(ErrorBadRequest)?;
+
+    let res = dengine
+        .proc_step_replay(proc_id, step_id)
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[post("/proc/{proc_id}/rewind")]
+async fn proc_rewind(
+    req: HttpRequest,
+    body: web::Json<ProcRewindRequest>,
+    dengine: web::Data<DEngine>,
+) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+
+    let res = dengine
+        .proc_rewind(proc_id, body.into_inner().step_id)
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[delete("/proc/{proc_id}/flag")]
+async fn proc_unflag(req: HttpRequest, dengine: web::Data<DEngine>) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+
+    let res = dengine.proc_unflag(proc_id).await.map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[post("/proc/{proc_id}/fork")]
+async fn proc_fork(
+    req: HttpRequest,
+    body: Option<web::Json<ProcForkRequest>>,
+    dengine: web::Data<DEngine>,
+) -> impl Responder {
+    let proc_id: String = req
+        .match_info()
+        .get("proc_id")
+        .ok_or(ErrorBadRequest("no proc id"))?
+        .parse()?;
+    let at_step = body.and_then(|body| body.into_inner().at_step);
+
+    let res = dengine
+        .proc_fork(proc_id, at_step)
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
 #[put("/proc/{proc_id}")]
 async fn proc_send(
     req: HttpRequest,
@@ -144,6 +301,19 @@ async fn proc_post_send(
     Ok::<_, actix_web::Error>(web::Json(res))
 }
 
+#[post("/event/")]
+async fn publish_event(
+    body: web::Json<serde_json::Value>,
+    dengine: web::Data<DEngine>,
+) -> impl Responder {
+    let res = dengine
+        .publish_event(body.into_inner())
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
 #[get("/stats")]
 async fn stats(_req: HttpRequest, dengine: web::Data<DEngine>) -> impl Responder {
     let res = dengine.watch_stats().await;
@@ -249,6 +419,47 @@ async fn module_edit(
     Ok::<_, actix_web::Error>(web::Json(res))
 }
 
+#[post("/module/{module_id}/check")]
+async fn module_check(
+    req: HttpRequest,
+    body: web::Json<ModuleCheckRequest>,
+    dengine: web::Data<DEngine>,
+) -> impl Responder {
+    let module_id: String = req
+        .match_info()
+        .get("module_id")
+        .ok_or(ErrorBadRequest("no module_id"))?
+        .parse()?;
+
+    let res = dengine
+        .module_check(module_id, body.into_inner().src)
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
+#[post("/module/{module_id}/migrate")]
+async fn module_migrate(
+    req: HttpRequest,
+    body: Option<web::Json<ModuleMigrateRequest>>,
+    dengine: web::Data<DEngine>,
+) -> impl Responder {
+    let module_id: String = req
+        .match_info()
+        .get("module_id")
+        .ok_or(ErrorBadRequest("no module_id"))?
+        .parse()?;
+    let version = body.and_then(|body| body.into_inner().version);
+
+    let res = dengine
+        .module_migrate(module_id, version)
+        .await
+        .map_err(apeiro_err)?;
+
+    Ok::<_, actix_web::Error>(web::Json(res))
+}
+
 #[post("/module/")]
 async fn module_new(
     _req: HttpRequest,
@@ -1,7 +1,10 @@
 mod handlers;
 
 use actix_web::{middleware::Logger, App, HttpServer};
-use apeiro_engine::{get_engine_runtime, plugins::PluginConfiguration, DEngine};
+use apeiro_engine::{
+    db_sqlite::SnapshotRetention, get_engine_runtime, plugins::PluginConfiguration, DEngine,
+};
+use apeiro_internal_api::ResumeMode;
 use clap::{command, Parser};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
@@ -35,6 +38,15 @@ struct Cli {
 
     #[clap(long)]
     no_web: bool,
+
+    /// How many of each proc's latest steps keep their snapshot, all if unset
+    #[clap(long)]
+    keep_snapshots: Option<u32>,
+
+    /// What procs resume from between steps unless they pick their own,
+    /// `snapshot` (default) or `frames`
+    #[clap(long)]
+    resume_mode: Option<ResumeMode>,
 }
 
 #[cfg(not(test))]
@@ -77,10 +89,14 @@ async fn main() -> anyhow::Result<()> {
     let port = cli.port.unwrap_or(5151);
     let store = cli.store.unwrap_or("world.db".into());
 
-    let (dengine, mut event_loop) = DEngine::new(
+    let (dengine, mut event_loop) = DEngine::new_with_resume_mode(
         Some(get_engine_runtime),
+        cli.resume_mode.unwrap_or_default(),
         Box::new(apeiro_engine::db_sqlite::Db {
             pool: establish_db_connection(store)?,
+            retention: SnapshotRetention {
+                keep_last: cli.keep_snapshots,
+            },
         }),
     )?;
 
@@ -141,7 +157,7 @@ async fn main() -> anyhow::Result<()> {
         let cors = Cors::default()
             // .allowed_origin(allowed_origin.as_str())
             .allowed_origin_fn(|_origin, _req_head| true)
-            .allowed_methods(vec!["GET", "POST", "PUT"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
             .allowed_headers(vec![
                 "apeiro-wait",
                 http::header::AUTHORIZATION.as_str(),
@@ -164,14 +180,26 @@ async fn main() -> anyhow::Result<()> {
             .service(handlers::proc_list)
             .service(handlers::proc_get)
             .service(handlers::proc_get_debug)
+            .service(handlers::proc_explain)
             .service(handlers::proc_send)
             .service(handlers::proc_post_send)
+            .service(handlers::publish_event)
             .service(handlers::proc_watch)
             .service(handlers::proc_delete)
+            .service(handlers::proc_mbox)
+            .service(handlers::proc_mbox_purge)
+            .service(handlers::proc_history)
+            .service(handlers::proc_step)
+            .service(handlers::proc_step_replay)
+            .service(handlers::proc_rewind)
+            .service(handlers::proc_unflag)
+            .service(handlers::proc_fork)
             .service(handlers::module_new)
             .service(handlers::module_list)
             .service(handlers::module_get)
             .service(handlers::module_edit)
+            .service(handlers::module_check)
+            .service(handlers::module_migrate)
             .service(handlers::helper_extract_export_name)
             .service(handlers::stats)
     })
@@ -2,8 +2,10 @@ use std::{path::PathBuf, string::String};
 
 use anyhow::{Ok, Result};
 use apeiro_internal_api::{
-    ApeiroError, ModuleNewRequest, ModuleSummary, ProcListOutput, ProcNewOutput, ProcNewRequest,
-    ProcSendRequest, ProcStatus, ProcStatusDebug, StepResult, StepResultStatus,
+    ApeiroError, ErrorPolicy, ModuleCheckOutput, ModuleCheckRequest, ModuleMigrateOutput,
+    ModuleMigrateRequest, ModuleNewRequest, ModuleSummary, ProcForkRequest, ProcHistoryOutput,
+    ProcListOutput, ProcNewOutput, ProcNewRequest, ProcRewindRequest, ProcSendRequest, ProcStatus,
+    ProcStatusDebug, ResumeMode, StepDetails, StepResult, StepResultStatus,
 };
 use cli_table::format::VerticalLine;
 use futures::stream::StreamExt;
@@ -101,7 +103,145 @@ pub(crate) async fn inspect(remote: String, proc_id: &String) -> Result<()> {
 
     let funcs = serde_json::to_string_pretty(&resp.funcs).unwrap();
     let frames = serde_json::to_string_pretty(&resp.frames).unwrap();
-    println!("{}\n======\n{}\n=====\n{}", funcs, frames, "TODO");
+    println!("{}\n======\n{}\n=====", funcs, frames);
+
+    for rejected in &resp.rejected_msgs {
+        println!("message {} rejected: {}", rejected.id, rejected.msg);
+        for error in &rejected.errors {
+            println!("  - {}", error);
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn history(remote: String, proc_id: &str, step: &Option<u64>) -> Result<()> {
+    use cli_table::{Cell, Style, Table};
+
+    if let Some(step_id) = step {
+        let step = reqwest::get(format!("{}/proc/{}/steps/{}", remote, proc_id, step_id))
+            .await?
+            .json::<StepDetails>()
+            .await?;
+
+        println!("{}", step.summary.step_result);
+        let msg = serde_json::to_string_pretty(&step.summary.msg).unwrap();
+        let funcs = serde_json::to_string_pretty(&step.funcs).unwrap();
+        let frames = serde_json::to_string_pretty(&step.frames).unwrap();
+        println!("msg: {}\n======\n{}\n======\n{}\n=====", msg, funcs, frames);
+        if !step.has_snapshot {
+            println!("snapshot pruned, rewinding resumes from frames");
+        }
+        return Ok(());
+    }
+
+    let resp = reqwest::get(remote + "/proc/" + proc_id + "/steps")
+        .await?
+        .json::<ProcHistoryOutput>()
+        .await?;
+
+    let empty_border = cli_table::format::Border::builder().build();
+
+    let table = resp
+        .steps
+        .iter()
+        .map(|step| {
+            vec![
+                format!("{}{}", step.step_id, if step.current { " *" } else { "" }).cell(),
+                step.parent_step_id
+                    .map(|parent| parent.to_string())
+                    .unwrap_or_default()
+                    .cell(),
+                step.step_result.status.to_string().cell(),
+                step.msg
+                    .as_ref()
+                    .map(|msg| truncate(&msg.to_string(), 64).to_string())
+                    .unwrap_or_default()
+                    .cell(),
+            ]
+        })
+        .table()
+        .title(vec![
+            "step".cell().bold(true),
+            "parent".cell().bold(true),
+            "status".cell().bold(true),
+            "msg".cell().bold(true),
+        ])
+        .border(empty_border)
+        .separator(
+            cli_table::format::Separator::builder()
+                .column(Some(VerticalLine::default()))
+                .build(),
+        );
+
+    cli_table::print_stdout(table)?;
+
+    Ok(())
+}
+
+pub(crate) async fn replay(remote: String, proc_id: &str, step_id: u64) -> Result<()> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .post(remote + "/proc/" + proc_id + "/steps/" + &step_id.to_string() + "/replay")
+        .send()
+        .await?;
+
+    let resp = result_or_error::<StepResult>(resp).await;
+
+    match resp {
+        Result::Ok(resp) => println!("{:?}", resp),
+        Err(e) => println!("error: {:?}", e),
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn rewind(remote: String, proc_id: &str, step_id: u64) -> Result<()> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .post(remote + "/proc/" + proc_id + "/rewind")
+        .json(&ProcRewindRequest { step_id })
+        .send()
+        .await?;
+
+    let resp = result_or_error::<ProcStatus>(resp).await;
+
+    match resp {
+        Result::Ok(resp) => println!("{:?}", resp),
+        Err(e) => println!("error: {:?}", e),
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn unflag(remote: String, proc_id: &str) -> Result<()> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .delete(remote + "/proc/" + proc_id + "/flag")
+        .send()
+        .await?;
+
+    let resp = result_or_error::<ProcStatus>(resp).await;
+
+    match resp {
+        Result::Ok(resp) => println!("{:?}", resp),
+        Err(e) => println!("error: {:?}", e),
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn fork(remote: String, proc_id: &str, at_step: Option<u64>) -> Result<()> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .post(remote + "/proc/" + proc_id + "/fork")
+        .json(&ProcForkRequest { at_step })
+        .send()
+        .await?;
+
+    let resp = result_or_error::<ProcNewOutput>(resp).await;
+
+    println!("{:?}", resp);
 
     Ok(())
 }
@@ -125,7 +265,12 @@ pub(crate) async fn send(remote: String, proc_id: &String, message: &String) ->
     Ok(())
 }
 
-pub(crate) async fn new(remote: String, module_id: &String, name: &Option<String>) -> Result<()> {
+pub(crate) async fn new(
+    remote: String,
+    module_id: &String,
+    name: &Option<String>,
+    resume_mode: Option<ResumeMode>,
+) -> Result<()> {
     let client = reqwest::Client::new();
     let resp = client
         .post(remote + "/proc/")
@@ -133,6 +278,7 @@ pub(crate) async fn new(remote: String, module_id: &String, name: &Option<String
             module_id: module_id.clone(),
             name: name.clone(),
             version: None,
+            resume_mode,
         })
         .send()
         .await?;
@@ -172,6 +318,7 @@ pub(crate) async fn ps(remote: String, _output_json: bool) -> Result<()> {
                 format!("{:.3}KB",
                     (p.snapshot_v2_size as f32 / 1024.0),
                 ).cell(),
+                truncate(p.flagged.as_deref().unwrap_or(""), 64).cell(),
             ]
         })
         .table()
@@ -181,6 +328,7 @@ pub(crate) async fn ps(remote: String, _output_json: bool) -> Result<()> {
             "status".cell().bold(true),
             "snapshot".cell().bold(true),
             "scope".cell().bold(true),
+            "flagged".cell().bold(true),
         ])
         .border(empty_border)
         .separator(
@@ -210,6 +358,7 @@ pub(crate) async fn modules_list(remote: String) -> Result<()> {
             vec![
                 p.id.clone().cell(),
                 p.name.clone().cell(),
+                p.version.cell(),
                 p.src.clone().cell(),
             ]
         })
@@ -217,6 +366,7 @@ pub(crate) async fn modules_list(remote: String) -> Result<()> {
         .title(vec![
             "mid".cell().bold(true).justify(Justify::Center),
             "name".cell().bold(true),
+            "version".cell().bold(true),
             "src".cell().bold(true),
         ])
         .border(empty_border)
@@ -233,7 +383,77 @@ pub(crate) async fn modules_list(remote: String) -> Result<()> {
     Ok(())
 }
 
-pub(crate) async fn module_new_inner(remote: String, srcfile: &PathBuf) -> Result<String> {
+pub(crate) async fn module_check(remote: String, module_id: &str, srcfile: &PathBuf) -> Result<()> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .post(remote + "/module/" + module_id + "/check")
+        .json(&ModuleCheckRequest {
+            src: std::fs::read_to_string(srcfile)?,
+        })
+        .send()
+        .await?;
+
+    let resp = match result_or_error::<ModuleCheckOutput>(resp).await {
+        Result::Ok(resp) => resp,
+        Err(e) => {
+            println!("error: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    println!(
+        "compared with version {}: {} fns added, {} removed, {} changed",
+        resp.version,
+        resp.added_fns.len(),
+        resp.removed_fns.len(),
+        resp.changed_fns.len()
+    );
+    for proc in &resp.procs {
+        if proc.compatible {
+            println!(
+                "{} (version {}) can resume",
+                proc.proc_id, proc.module_version
+            );
+        } else {
+            println!(
+                "{} (version {}) can't resume:",
+                proc.proc_id, proc.module_version
+            );
+            for problem in &proc.problems {
+                println!("  - {}", problem);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn migrate(remote: String, module_id: &str, version: Option<u32>) -> Result<()> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .post(remote + "/module/" + module_id + "/migrate")
+        .json(&ModuleMigrateRequest { version })
+        .send()
+        .await?;
+
+    match result_or_error::<ModuleMigrateOutput>(resp).await {
+        Result::Ok(resp) => {
+            println!("migrated to version {}: {:?}", resp.version, resp.migrated);
+            for rejection in &resp.incompatible {
+                println!("{} not migrated: {}", rejection.proc_id, rejection.reason);
+            }
+        }
+        Err(e) => println!("error: {:?}", e),
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn module_new_inner(
+    remote: String,
+    srcfile: &PathBuf,
+    on_error: Option<ErrorPolicy>,
+) -> Result<String> {
     let client = reqwest::Client::new();
     let resp = client
         .post(remote + "/module/")
@@ -242,6 +462,9 @@ pub(crate) async fn module_new_inner(remote: String, srcfile: &PathBuf) -> Resul
             src: std::fs::read_to_string(srcfile)?,
             singleton: Some(false),
             src_is_compiled: None,
+            limits: None,
+            on_error,
+            retry: None,
         })
         .send()
         .await?
@@ -260,8 +483,12 @@ pub(crate) async fn module_new_inner(remote: String, srcfile: &PathBuf) -> Resul
     Ok(res)
 }
 
-pub(crate) async fn module_new(remote: String, srcfile: &PathBuf) -> Result<()> {
-    let resp = module_new_inner(remote, srcfile).await?;
+pub(crate) async fn module_new(
+    remote: String,
+    srcfile: &PathBuf,
+    on_error: Option<ErrorPolicy>,
+) -> Result<()> {
+    let resp = module_new_inner(remote, srcfile, on_error).await?;
 
     println!("{:?}", resp);
 
@@ -3,6 +3,7 @@ mod cmds;
 use std::{path::PathBuf, string::String};
 
 use anyhow::{Ok, Result};
+use apeiro_internal_api::{ErrorPolicy, ResumeMode};
 use clap::{command, Parser, Subcommand};
 use cmds::*;
 
@@ -40,17 +41,58 @@ enum Commands {
     Inspect {
         proc_id: String,
     },
+    /// List the steps of a process, or show one of them
+    History {
+        proc_id: String,
+        #[clap(short, long)]
+        step: Option<u64>,
+    },
+    /// Run a step of a process again, without keeping what it does
+    Replay {
+        proc_id: String,
+        step_id: u64,
+    },
+    /// Reset a process back to an earlier step
+    Rewind {
+        proc_id: String,
+        step_id: u64,
+    },
+    /// Clear the flag of a process, once it was looked into
+    Unflag {
+        proc_id: String,
+    },
+    /// Start a new process from a copy of another one
+    Fork {
+        proc_id: String,
+        /// step to fork from, the current one if unset
+        #[clap(short, long)]
+        step: Option<u64>,
+    },
     /// Send message to process
     Send {
         proc_id: String,
         message: String,
     },
     /// New module
+    #[command(args_conflicts_with_subcommands = true)]
     Module {
-        srcfile: PathBuf,
+        srcfile: Option<PathBuf>,
+        /// What happens to a process whose step fails: `suspend`, `error` or
+        /// `retry`
+        #[clap(long)]
+        on_error: Option<ErrorPolicy>,
+        #[command(subcommand)]
+        command: Option<ModuleCommands>,
     },
     /// List modules
     Modules {},
+    /// Move a module's suspended processes onto a newer version of it
+    Migrate {
+        module_id: String,
+        /// version to move them onto, the latest if unset
+        #[clap(short, long)]
+        version: Option<u32>,
+    },
     /// Start a new process
     New {
         #[clap(short, long)]
@@ -59,6 +101,9 @@ enum Commands {
         src: Option<PathBuf>,
         #[clap(short, long)]
         name: Option<String>,
+        /// Resume the process from `snapshot` or `frames` between steps
+        #[clap(long)]
+        resume_mode: Option<ResumeMode>,
     },
     /// Stream process events and logs
     Watch {
@@ -67,6 +112,13 @@ enum Commands {
     Web {},
 }
 
+#[derive(Subcommand)]
+enum ModuleCommands {
+    /// Check whether a module's suspended processes could resume under a new
+    /// source
+    Check { module_id: String, srcfile: PathBuf },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -84,12 +136,22 @@ async fn main() -> Result<()> {
         Commands::Get { proc_id, value } => get(&remote, proc_id, value, cli.output_json).await,
         Commands::Inspect { proc_id } => inspect(remote, proc_id).await,
         Commands::Send { proc_id, message } => send(remote, proc_id, message).await,
-        Commands::New { src, module, name } => {
+        Commands::History { proc_id, step } => history(remote, proc_id, step).await,
+        Commands::Replay { proc_id, step_id } => replay(remote, proc_id, *step_id).await,
+        Commands::Rewind { proc_id, step_id } => rewind(remote, proc_id, *step_id).await,
+        Commands::Unflag { proc_id } => unflag(remote, proc_id).await,
+        Commands::Fork { proc_id, step } => fork(remote, proc_id, *step).await,
+        Commands::New {
+            src,
+            module,
+            name,
+            resume_mode,
+        } => {
             if let Some(src) = src {
-                let module_id = module_new_inner(remote.clone(), src).await?;
-                new(remote, &module_id, name).await
+                let module_id = module_new_inner(remote.clone(), src, None).await?;
+                new(remote, &module_id, name, *resume_mode).await
             } else if let Some(module_id) = module {
-                new(remote, module_id, name).await
+                new(remote, module_id, name, *resume_mode).await
             } else {
                 Err(anyhow::anyhow!(
                     "either --src or --module must be specified"
@@ -98,7 +160,17 @@ async fn main() -> Result<()> {
         }
         Commands::Ps {} => ps(remote, cli.output_json).await,
         Commands::Modules {} => modules_list(remote).await,
-        Commands::Module { srcfile } => module_new(remote, srcfile).await,
+        Commands::Module {
+            command: Some(ModuleCommands::Check { module_id, srcfile }),
+            ..
+        } => module_check(remote, module_id, srcfile).await,
+        Commands::Module {
+            srcfile: Some(srcfile),
+            on_error,
+            ..
+        } => module_new(remote, srcfile, *on_error).await,
+        Commands::Module { .. } => Err(anyhow::anyhow!("a source file must be specified")),
+        Commands::Migrate { module_id, version } => migrate(remote, module_id, *version).await,
         Commands::Web {} => {
             println!("Listening on 127.0.0.1:3030");
             apeiro_frontend_rs::web(([127, 0, 0, 1], 3030)).await;
@@ -0,0 +1,131 @@
+//! Whether procs suspended under one compiled version of a module can be
+//! resumed under another. A frame only resumes into a fn with the same
+//! `fnhash`, at a `$pc` that fn still has.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::ProgramCounterToSourceLocation;
+
+/// The program counters of every instrumented fn of a compiled module.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FnLayouts {
+    pcs: BTreeMap<u64, BTreeSet<i32>>,
+    /// set if fns may have more program counters than `pcs` has
+    partial: bool,
+}
+
+impl FnLayouts {
+    pub fn new(mapping: &[ProgramCounterToSourceLocation]) -> Self {
+        let mut pcs: BTreeMap<u64, BTreeSet<i32>> = BTreeMap::new();
+        for entry in mapping {
+            pcs.entry(entry.fnhash).or_default().insert(entry.pc);
+        }
+        FnLayouts {
+            pcs,
+            partial: false,
+        }
+    }
+
+    /// The layouts of a mapping in an older `PC_MAP_FORMAT`, which may lack
+    /// some of a fn's program counters. Only the fns are to be relied on.
+    pub fn partial(mapping: &[ProgramCounterToSourceLocation]) -> Self {
+        FnLayouts {
+            partial: true,
+            ..FnLayouts::new(mapping)
+        }
+    }
+
+    pub fn fnhashes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.pcs.keys().copied()
+    }
+
+    /// How `new` differs from `self`, fn by fn. A fn of partial layouts
+    /// only counts as changed if the other side lacks some of its pcs.
+    pub fn diff(&self, new: &FnLayouts) -> LayoutDiff {
+        let mut diff = LayoutDiff::default();
+        for (fnhash, pcs) in &self.pcs {
+            match new.pcs.get(fnhash) {
+                None => diff.removed.push(*fnhash),
+                Some(new_pcs) => {
+                    let changed = match (self.partial, new.partial) {
+                        (false, false) => new_pcs != pcs,
+                        (true, false) => !pcs.is_subset(new_pcs),
+                        (false, true) => !new_pcs.is_subset(pcs),
+                        (true, true) => false,
+                    };
+                    if changed {
+                        diff.changed.push(*fnhash);
+                    }
+                }
+            }
+        }
+        diff.added = new
+            .fnhashes()
+            .filter(|fnhash| !self.pcs.contains_key(fnhash))
+            .collect();
+        diff
+    }
+
+    /// Why `frames`, outermost first, can't be resumed into these fns. Empty
+    /// if they can.
+    pub fn check_frames(&self, frames: &[LiveFrame]) -> Vec<FrameIncompatibility> {
+        frames
+            .iter()
+            .enumerate()
+            .filter_map(|(depth, frame)| match self.pcs.get(&frame.fnhash) {
+                None => Some(FrameIncompatibility::MissingFn {
+                    depth,
+                    fnhash: frame.fnhash,
+                }),
+                Some(pcs) if !self.partial && !pcs.contains(&frame.pc) => {
+                    Some(FrameIncompatibility::MissingPc {
+                        depth,
+                        fnhash: frame.fnhash,
+                        pc: frame.pc,
+                    })
+                }
+                Some(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LayoutDiff {
+    pub added: Vec<u64>,
+    pub removed: Vec<u64>,
+    /// fns with the same hash but other program counters, e.g. compiled by
+    /// another version of the compiler
+    pub changed: Vec<u64>,
+}
+
+/// A frame of a suspended proc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveFrame {
+    pub fnhash: u64,
+    pub pc: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameIncompatibility {
+    MissingFn { depth: usize, fnhash: u64 },
+    MissingPc { depth: usize, fnhash: u64, pc: i32 },
+}
+
+impl fmt::Display for FrameIncompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameIncompatibility::MissingFn { depth, fnhash } => {
+                write!(f, "frame {} is in fn {}, which is gone", depth, fnhash)
+            }
+            FrameIncompatibility::MissingPc { depth, fnhash, pc } => write!(
+                f,
+                "frame {} is at $pc {} of fn {}, which it no longer has",
+                depth, pc, fnhash
+            ),
+        }
+    }
+}
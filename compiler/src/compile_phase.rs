@@ -35,7 +35,7 @@ use tracing::{event, instrument, trace, Level};
 use crate::{
     either_param_to_closure, fn_decl_to_fn_expr, fn_instrument, helpers,
     helpers::{Helpers, HELPERS},
-    now_as_millis, stmt_exploder, CompilationResult, BASELINE_ES_VERSION,
+    loop_lowering, now_as_millis, stmt_exploder, CompilationResult, BASELINE_ES_VERSION,
 };
 
 pub struct ApeiroCompiler {
@@ -393,6 +393,7 @@ impl ApeiroCompiler {
                     chain!(
                         either_param_to_closure::folder(),
                         fn_decl_to_fn_expr::folder(),
+                        loop_lowering::folder(),
                         stmt_exploder::folder(),
                         fn_instrument::folder(),
                     )
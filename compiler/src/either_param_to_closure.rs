@@ -31,7 +31,12 @@ impl VisitMut for VisitorEitherParamToClosure {
                     let r = &mut *event.as_mut().unwrap().expr;
                     if let Expr::Array(event_to_fnspec) = r {
                         if let Some(first) = event_to_fnspec.elems.get_mut(0).unwrap() {
-                            *first.expr = first.expr.take().into_lazy_arrow(vec![]).into();
+                            let mut spec = first.expr.take();
+                            // `() => { ... }` would be a block
+                            if spec.is_object() {
+                                spec = spec.wrap_with_paren().into();
+                            }
+                            *first.expr = spec.into_lazy_arrow(vec![]).into();
                         }
                     }
                 }
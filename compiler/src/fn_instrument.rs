@@ -1,10 +1,8 @@
-use std::borrow::BorrowMut;
-
 use swc_core::{
     common::{util::take::Take, BytePos, Span, Spanned, SyntaxContext, DUMMY_SP},
     ecma::{
         ast::{
-            AssignPatProp, BlockStmt, CallExpr, Callee, Decl, Expr, ExprOrSpread, ExprStmt, FnExpr, Function, Id, Ident, IfStmt, ImportDecl, ImportSpecifier, KeyValueProp, Lit, MemberExpr, MemberProp, Module, ModuleDecl, ModuleItem, Null, ObjectLit, ObjectPat, ObjectPatProp, Param, Pat, Prop, ReturnStmt, SimpleAssignTarget, Stmt, SwitchCase, SwitchStmt, ThrowStmt, TryStmt, VarDecl, VarDeclKind, VarDeclarator
+            ArrowExpr, AssignPatProp, BlockStmt, BreakStmt, CallExpr, Callee, Constructor, ContinueStmt, Decl, Expr, ExprOrSpread, ExprStmt, FnExpr, Function, Id, Ident, IfStmt, ImportDecl, LabeledStmt, ImportSpecifier, KeyValueProp, Lit, MemberExpr, MemberProp, Module, ModuleDecl, ModuleItem, Null, ObjectLit, ObjectPat, ObjectPatProp, Param, Pat, Prop, ReturnStmt, SimpleAssignTarget, Stmt, SwitchCase, SwitchStmt, ThrowStmt, TryStmt, UnaryExpr, UnaryOp, VarDecl, VarDeclKind, VarDeclarator
         },
         utils::{private_ident, quote_ident, ExprFactory},
         visit::{as_folder, Fold, VisitMut, VisitMutWith},
@@ -30,6 +28,11 @@ struct WrapFunctions {
     wrapping_function: String,
     params: Vec<Vec<Id>>,
     leaky_closure: Vec<Vec<Id>>,
+    /// depth of the first frame of each function being instrumented
+    fn_frame_base: Vec<i32>,
+    /// labels in scope, with the depth of the frame the labeled statement
+    /// runs in
+    labels: Vec<(Ident, i32)>,
 }
 
 fn penultimate(data: &Vec<u64>) -> Option<&u64> {
@@ -41,14 +44,18 @@ fn penultimate(data: &Vec<u64>) -> Option<&u64> {
 }
 
 impl WrapFunctions {
-    fn current_frame_identifier(&self) -> Option<Ident> {
-        if self.frame_depth >= 1 {
-            Some(private_ident!(format!("$f{}", self.frame_depth)))
+    fn frame_identifier(&self, depth: i32) -> Option<Ident> {
+        if depth >= 1 {
+            Some(private_ident!(format!("$f{}", depth)))
         } else {
             None
         }
     }
 
+    fn current_frame_identifier(&self) -> Option<Ident> {
+        self.frame_identifier(self.frame_depth)
+    }
+
     fn previous_scope_identifier(&self) -> Option<Ident> {
         if self.frame_depth >= 2 {
             Some(private_ident!(format!("$sc{}", self.frame_depth - 1)))
@@ -156,18 +163,53 @@ impl WrapFunctions {
     }
 
     fn expr_end_frame(&mut self) -> Stmt {
+        self.expr_end_frame_at(self.frame_depth)
+    }
+
+    fn expr_end_frame_at(&self, depth: i32) -> Stmt {
         Stmt::Expr(ExprStmt {
             span: DUMMY_SP,
             expr: CallExpr {
                 span: DUMMY_SP,
                 type_args: None,
                 callee: Callee::Expr(Box::new(Expr::Ident(quote_ident!("$frame_end")))),
-                args: vec![self.current_frame_identifier().unwrap().as_arg()],
+                args: vec![self.frame_identifier(depth).unwrap().as_arg()],
             }
             .into(),
         })
     }
 
+    /// Ends the current frame and every enclosing one down to `depth`,
+    /// innermost first, for control flow leaving several blocks at once.
+    fn expr_end_frames_down_to(&self, depth: i32) -> Vec<Stmt> {
+        (depth..=self.frame_depth)
+            .rev()
+            .map(|depth| self.expr_end_frame_at(depth))
+            .collect()
+    }
+
+    /// Frames to end on `return`: all of the current function's.
+    fn expr_end_fn_frames(&self) -> Vec<Stmt> {
+        let base = *self.fn_frame_base.last().unwrap_or(&self.frame_depth);
+        self.expr_end_frames_down_to(base)
+    }
+
+    /// Drops whatever frames were left above the current one by an
+    /// exception, so the current block can go on ending its own frame.
+    fn expr_frame_unwind(&self) -> Option<Stmt> {
+        let frame = self.current_frame_identifier()?;
+        Some(Stmt::Expr(ExprStmt {
+            span: DUMMY_SP,
+            expr: CallExpr {
+                span: DUMMY_SP,
+                type_args: None,
+                callee: Callee::Expr(Box::new(Expr::Ident(quote_ident!("$frame_unwind")))),
+                args: vec![frame.as_arg()],
+            }
+            .into(),
+        }))
+    }
+
     fn expr_set_frame_pc(&mut self, pc: i32) -> Stmt {
         ExprStmt {
             span: DUMMY_SP,
@@ -257,6 +299,9 @@ impl WrapFunctions {
                     }
                 } else {
                     if let Some(assignee_name) = decl.name.as_ident() {
+                        if let Some(moved_vars) = self.moved_vars.last_mut() {
+                            moved_vars.push(assignee_name.to_id());
+                        }
                         new_stmts.push(
                             ExprStmt {
                                 span: DUMMY_SP,
@@ -300,6 +345,13 @@ impl WrapFunctions {
         let mut cases = Vec::with_capacity(stmts.len());
         let mut pc = 0;
         for (index, stmt) in stmts.iter_mut().enumerate() {
+            // every pc a frame of this fn can be at, including returns and
+            // the last statement, so that they can be looked up later
+            HELPERS.with(|helpers| {
+                let fnhash = self.fn_hash.last().copied().unwrap_or(1);
+                let orig_span: swc_core::common::Span = stmt.span();
+                helpers.add_pc_to_src(fnhash, pc, orig_span.lo.0, orig_span.hi.0);
+            });
             cases.push(SwitchCase {
                 span: DUMMY_SP,
                 test: Some(
@@ -313,7 +365,7 @@ impl WrapFunctions {
                 cons: if let Stmt::Return(ReturnStmt { arg, .. }) = stmt {
                     if let Some(return_val) = arg {
                         let temp = private_ident!("__return_val");
-                        vec![
+                        let mut res: Vec<Stmt> = vec![
                             VarDecl {
                                 span: DUMMY_SP,
                                 kind: VarDeclKind::Let,
@@ -326,15 +378,18 @@ impl WrapFunctions {
                                 }],
                             }
                             .into(),
-                            self.expr_end_frame(),
+                        ];
+                        res.append(&mut self.expr_end_fn_frames());
+                        res.push(
                             ReturnStmt {
                                 span: stmt.span(),
                                 arg: Some(temp.into()),
                             }
                             .into(),
-                        ]
+                        );
+                        res
                     } else {
-                        let mut res = vec![self.expr_end_frame()];
+                        let mut res = self.expr_end_fn_frames();
                         res.append(&mut self.move_var_assignments(stmt));
                         res
                     }
@@ -344,12 +399,6 @@ impl WrapFunctions {
                     res.push(self.expr_end_frame());
                     res
                 } else {
-                    HELPERS.with(|mut helpers| {
-                        let helpers = helpers.borrow_mut();
-                        let fnhash = *self.fn_hash.last().unwrap();
-                        let orig_span: swc_core::common::Span = stmt.span();
-                        helpers.add_pc_to_src(fnhash, pc, orig_span.lo.0, orig_span.hi.0);
-                    });
                     let mut res = vec![];
                     res.append(&mut self.move_var_assignments(stmt));
                     res.push(self.expr_set_frame_pc(pc + 1));
@@ -504,6 +553,11 @@ impl VisitMut for WrapFunctions {
         try_stmt.visit_mut_children_with(self);
 
         if let Some(catch_clause) = &mut try_stmt.handler {
+            // suspension signals have to reach $step, so catch blocks get a
+            // param to rethrow them even when the user didn't name one
+            if catch_clause.param.is_none() {
+                catch_clause.param = Some(private_ident!("$e").into());
+            }
             if let Some(catch_param) = &mut catch_clause.param {
                 if let Pat::Ident(catch_ident) = catch_param {
                     let error_ident: Ident = catch_ident.to_id().into();
@@ -529,9 +583,41 @@ impl VisitMut for WrapFunctions {
                         }
                         .into(),
                     );
+                    // a real error leaves the frames of the blocks it was
+                    // thrown from on the stack
+                    if let Some(unwind) = self.expr_frame_unwind() {
+                        catch_clause.body.stmts.insert(1, unwind);
+                    }
                 }
             }
         }
+
+        // a suspension unwinds through the finally block without leaving the
+        // try for good, it will be re-entered when the proc resumes
+        if let Some(finalizer) = &mut try_stmt.finalizer {
+            let mut stmts = vec![];
+            stmts.extend(self.expr_frame_unwind());
+            stmts.push(Stmt::Block(finalizer.take()));
+            finalizer.stmts = vec![IfStmt {
+                span: DUMMY_SP,
+                test: Box::new(Expr::Unary(UnaryExpr {
+                    span: DUMMY_SP,
+                    op: UnaryOp::Bang,
+                    arg: Box::new(Expr::Call(CallExpr {
+                        span: DUMMY_SP,
+                        callee: Callee::Expr(Expr::Ident(quote_ident!("$isSuspending")).into()),
+                        args: vec![],
+                        type_args: None,
+                    })),
+                })),
+                cons: Box::new(Stmt::Block(BlockStmt {
+                    span: DUMMY_SP,
+                    stmts,
+                })),
+                alt: None,
+            }
+            .into()];
+        }
     }
 
     // wrap either event specs into closure
@@ -625,6 +711,59 @@ impl VisitMut for WrapFunctions {
         self.frame_depth = self.frame_depth - 1;
     }
 
+    fn visit_mut_arrow_expr(&mut self, arrow: &mut ArrowExpr) {
+        self.fn_frame_base.push(self.frame_depth + 1);
+        arrow.visit_mut_children_with(self);
+        self.fn_frame_base.pop();
+    }
+
+    fn visit_mut_constructor(&mut self, constructor: &mut Constructor) {
+        self.fn_frame_base.push(self.frame_depth + 1);
+        constructor.visit_mut_children_with(self);
+        self.fn_frame_base.pop();
+    }
+
+    fn visit_mut_labeled_stmt(&mut self, labeled: &mut LabeledStmt) {
+        self.labels.push((labeled.label.clone(), self.frame_depth));
+        labeled.visit_mut_children_with(self);
+        self.labels.pop();
+    }
+
+    // a labeled `break`/`continue` leaves the frames of every block between
+    // it and the labeled statement
+    fn visit_mut_stmt(&mut self, stmt: &mut Stmt) {
+        let target = match stmt {
+            Stmt::Break(BreakStmt {
+                label: Some(label), ..
+            })
+            | Stmt::Continue(ContinueStmt {
+                label: Some(label), ..
+            }) => label.sym.clone(),
+            _ => {
+                stmt.visit_mut_children_with(self);
+                return;
+            }
+        };
+
+        let label_depth = self
+            .labels
+            .iter()
+            .rev()
+            .find(|(label, _)| label.sym == target)
+            .map(|(_, depth)| *depth);
+        if let Some(label_depth) = label_depth {
+            if self.frame_depth > label_depth {
+                let mut stmts = self.expr_end_frames_down_to(label_depth + 1);
+                stmts.push(stmt.take());
+                *stmt = BlockStmt {
+                    span: DUMMY_SP,
+                    stmts,
+                }
+                .into();
+            }
+        }
+    }
+
     fn visit_mut_function(&mut self, function: &mut Function) {
         match function.body {
             Some(ref mut _block_stmt) => {
@@ -636,7 +775,9 @@ impl VisitMut for WrapFunctions {
                 }
                 self.moved_vars.push(vec![]);
                 self.params.push(params);
+                self.fn_frame_base.push(self.frame_depth + 1);
                 function.visit_mut_children_with(self);
+                _ = self.fn_frame_base.pop();
                 _ = self.moved_vars.pop();
                 _ = self.params.pop()
             }
@@ -4,14 +4,17 @@ mod tests;
 #[allow(dead_code)]
 pub mod helpers;
 
+mod compat;
 mod compile_phase;
 mod either_param_to_closure;
 mod fn_decl_to_fn_expr;
 mod fn_instrument;
+mod loop_lowering;
 mod stmt_exploder;
 mod utils;
 
 use anyhow::Result;
+pub use compat::{FnLayouts, FrameIncompatibility, LayoutDiff, LiveFrame};
 pub use compile_phase::custom_apeiro_compile;
 use compile_phase::ApeiroCompiler;
 use swc_core::{
@@ -23,6 +26,11 @@ pub fn engine_runtime_compile(input: String) -> Result<String> {
     Ok(custom_apeiro_compile(input, |_| noop(), false, false, false)?.compiled_src)
 }
 
+/// Which program counters `CompilationResult::program_counter_mapping` has,
+/// to be stored along with it. Format 1 left out returns and the last
+/// statement of every fn, format 2 has all of them.
+pub const PC_MAP_FORMAT: u32 = 2;
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ProgramCounterToSourceLocation {
     pub fnhash: u64,
@@ -101,6 +109,7 @@ pub fn apeiro_compile(input: String) -> Result<CompilationResult> {
             chain!(
                 either_param_to_closure::folder(),
                 fn_decl_to_fn_expr::folder(),
+                loop_lowering::folder(),
                 stmt_exploder::folder(),
                 fn_instrument::folder(),
             )
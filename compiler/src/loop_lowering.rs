@@ -0,0 +1,644 @@
+use swc_core::{
+    common::{util::take::Take, Spanned, DUMMY_SP},
+    ecma::{
+        ast::{
+            ArrowExpr, AssignExpr, AssignOp, AssignPat, AssignPatProp, AssignTarget,
+            AssignTargetPat, BlockStmt, Bool, BreakStmt, CallExpr, Callee, Class, ComputedPropName,
+            ContinueStmt, Decl, DoWhileStmt, Expr, ExprStmt, ForHead, ForOfStmt, ForStmt, Function,
+            Ident, IfStmt, KeyValuePatProp, LabeledStmt, Lit, MemberExpr, MemberProp,
+            ObjectPatProp, ParenExpr, Pat, PropName, Stmt, UnaryExpr, UnaryOp, VarDecl,
+            VarDeclKind, VarDeclOrExpr, VarDeclarator, WhileStmt,
+        },
+        utils::{find_pat_ids, private_ident, quote_ident},
+        visit::{as_folder, Fold, Visit, VisitMut, VisitMutWith, VisitWith},
+    },
+};
+
+/// Rewrites `for`, `for...of`, `while` and `do...while` loops inside
+/// functions into labeled `while (true)` loops whose state (counters,
+/// iterators, first-iteration flags) lives in `let`s of an enclosing block,
+/// so that `fn_instrument` moves it into the frame scope.
+///
+/// The loop test is evaluated as the first statement of the body rather than
+/// in the `while` itself: when a suspended loop is resumed, the body's frame
+/// picks up where it left off without the test being evaluated again.
+/// Unlabeled `break`s and `continue`s are given the loop's label, as the body
+/// ends up inside the `switch` of its frame.
+///
+/// Destructuring loop heads become plain `let`s followed by a destructuring
+/// assignment, which `fn_instrument` can move into the frame scope. Every
+/// iteration gets its own bindings: those of a `for...of` are declared in
+/// the body, and the `let`s of a `for` whose body creates closures are copied
+/// into fresh bindings at the start of each iteration.
+pub fn folder() -> impl Fold {
+    as_folder(LoopLowering {
+        ..Default::default()
+    })
+}
+
+#[derive(Default)]
+struct LoopLowering {
+    count: u32,
+    fn_depth: u32,
+}
+
+fn is_lowerable(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::While(_) | Stmt::DoWhile(_) | Stmt::For(_) => true,
+        Stmt::ForOf(for_of) => !for_of.is_await && !matches!(for_of.left, ForHead::UsingDecl(_)),
+        _ => false,
+    }
+}
+
+fn negate(test: Box<Expr>) -> Box<Expr> {
+    Box::new(Expr::Unary(UnaryExpr {
+        span: DUMMY_SP,
+        op: UnaryOp::Bang,
+        arg: Box::new(Expr::Paren(ParenExpr {
+            span: DUMMY_SP,
+            expr: test,
+        })),
+    }))
+}
+
+fn break_stmt(label: &Ident) -> Stmt {
+    Stmt::Break(BreakStmt {
+        span: DUMMY_SP,
+        label: Some(label.clone()),
+    })
+}
+
+/// `if (test) break label;`
+fn exit_if(test: Box<Expr>, label: &Ident) -> Stmt {
+    Stmt::If(IfStmt {
+        span: DUMMY_SP,
+        test,
+        cons: Box::new(break_stmt(label)),
+        alt: None,
+    })
+}
+
+/// `if (!(test)) break label;`
+fn exit_unless(test: Box<Expr>, label: &Ident) -> Stmt {
+    exit_if(negate(test), label)
+}
+
+fn declare(kind: VarDeclKind, name: Pat, init: Option<Box<Expr>>) -> Stmt {
+    Stmt::Decl(Decl::Var(Box::new(VarDecl {
+        span: DUMMY_SP,
+        kind,
+        declare: false,
+        decls: vec![VarDeclarator {
+            span: DUMMY_SP,
+            name,
+            init,
+            definite: false,
+        }],
+    })))
+}
+
+fn let_decl(name: &Ident, init: Box<Expr>) -> Stmt {
+    declare(VarDeclKind::Let, name.clone().into(), Some(init))
+}
+
+/// `let name;`
+fn let_uninit(name: &Ident) -> Stmt {
+    declare(VarDeclKind::Let, name.clone().into(), None)
+}
+
+fn assign(left: AssignTarget, right: Box<Expr>) -> Stmt {
+    // `({ a } = b);` needs the parens to not be parsed as a block
+    let needs_parens = matches!(left, AssignTarget::Pat(AssignTargetPat::Object(_)));
+    let mut expr = Box::new(Expr::Assign(AssignExpr {
+        span: DUMMY_SP,
+        op: AssignOp::Assign,
+        left,
+        right,
+    }));
+    if needs_parens {
+        expr = Box::new(Expr::Paren(ParenExpr {
+            span: DUMMY_SP,
+            expr,
+        }));
+    }
+    Stmt::Expr(ExprStmt {
+        span: DUMMY_SP,
+        expr,
+    })
+}
+
+/// `obj.prop`
+fn member(obj: Box<Expr>, prop: &str) -> Box<Expr> {
+    Box::new(Expr::Member(MemberExpr {
+        span: DUMMY_SP,
+        obj,
+        prop: MemberProp::Ident(quote_ident!(prop)),
+    }))
+}
+
+/// `callee()`
+fn call(callee: Box<Expr>) -> Box<Expr> {
+    Box::new(Expr::Call(CallExpr {
+        span: DUMMY_SP,
+        callee: Callee::Expr(callee),
+        args: vec![],
+        type_args: None,
+    }))
+}
+
+/// Turns a binding pattern into the equivalent assignment target, spelling
+/// out shorthand properties (`{a = 1}` as `{a: a = 1}`) so that every bound
+/// name ends up as a plain identifier pattern.
+fn pattern_target(mut pat: Pat) -> AssignTarget {
+    pat.visit_mut_with(&mut ShorthandExpander);
+    AssignTarget::try_from(pat).expect("binding patterns are assignment targets")
+}
+
+struct ShorthandExpander;
+
+impl VisitMut for ShorthandExpander {
+    fn visit_mut_object_pat_prop(&mut self, prop: &mut ObjectPatProp) {
+        prop.visit_mut_children_with(self);
+        if let ObjectPatProp::Assign(AssignPatProp { span, key, value }) = prop {
+            let binding = Pat::Ident(key.clone());
+            *prop = ObjectPatProp::KeyValue(KeyValuePatProp {
+                key: PropName::Ident(key.id.clone()),
+                value: Box::new(match value.take() {
+                    Some(default) => Pat::Assign(AssignPat {
+                        span: *span,
+                        left: Box::new(binding),
+                        right: default,
+                    }),
+                    None => binding,
+                }),
+            });
+        }
+    }
+}
+
+/// Declares the names bound by `pat` and initializes them with `init`. A
+/// destructuring pattern is declared one `let` per name and then assigned,
+/// as `fn_instrument` only moves plain identifiers into the frame scope.
+fn bind_pattern(kind: VarDeclKind, pat: Pat, init: Box<Expr>) -> Vec<Stmt> {
+    if let Pat::Ident(_) = pat {
+        return vec![declare(kind, pat, Some(init))];
+    }
+
+    let kind = match kind {
+        VarDeclKind::Var => VarDeclKind::Var,
+        VarDeclKind::Let | VarDeclKind::Const => VarDeclKind::Let,
+    };
+    let mut stmts: Vec<Stmt> = find_pat_ids::<_, Ident>(&pat)
+        .into_iter()
+        .map(|name| declare(kind, name.into(), None))
+        .collect();
+    stmts.push(assign(pattern_target(pat), init));
+    stmts
+}
+
+/// Whether `node` creates functions or classes that may capture bindings.
+fn has_closures<T: VisitWith<ClosureFinder>>(node: &T) -> bool {
+    let mut finder = ClosureFinder { found: false };
+    node.visit_with(&mut finder);
+    finder.found
+}
+
+struct ClosureFinder {
+    found: bool,
+}
+
+impl Visit for ClosureFinder {
+    fn visit_function(&mut self, _function: &Function) {
+        self.found = true;
+    }
+
+    fn visit_arrow_expr(&mut self, _arrow: &ArrowExpr) {
+        self.found = true;
+    }
+
+    fn visit_class(&mut self, _class: &Class) {
+        self.found = true;
+    }
+}
+
+fn bool_lit(value: bool) -> Box<Expr> {
+    Box::new(Expr::Lit(Lit::Bool(Bool {
+        span: DUMMY_SP,
+        value,
+    })))
+}
+
+/// `if (first) first = false; else otherwise;`
+fn unless_first(first: &Ident, otherwise: Stmt) -> Stmt {
+    Stmt::If(IfStmt {
+        span: DUMMY_SP,
+        test: Box::new(Expr::Ident(first.clone())),
+        cons: Box::new(Stmt::Expr(ExprStmt {
+            span: DUMMY_SP,
+            expr: Box::new(Expr::Assign(AssignExpr {
+                span: DUMMY_SP,
+                op: AssignOp::Assign,
+                left: first.clone().into(),
+                right: bool_lit(false),
+            })),
+        })),
+        alt: Some(Box::new(otherwise)),
+    })
+}
+
+fn body_stmts(body: Stmt) -> Vec<Stmt> {
+    match body {
+        Stmt::Block(block) => block.stmts,
+        Stmt::Empty(_) => vec![],
+        stmt => vec![stmt],
+    }
+}
+
+fn labeled_loop(label: Ident, span: swc_core::common::Span, stmts: Vec<Stmt>) -> Stmt {
+    Stmt::Labeled(LabeledStmt {
+        span,
+        label,
+        body: Box::new(Stmt::While(WhileStmt {
+            span,
+            test: bool_lit(true),
+            body: Box::new(Stmt::Block(BlockStmt {
+                span: DUMMY_SP,
+                stmts,
+            })),
+        })),
+    })
+}
+
+fn block(span: swc_core::common::Span, stmts: Vec<Stmt>) -> Stmt {
+    Stmt::Block(BlockStmt { span, stmts })
+}
+
+impl LoopLowering {
+    fn next_ident(&mut self, name: &str) -> Ident {
+        private_ident!(format!("_{}${}", name, self.count))
+    }
+
+    fn lower(&mut self, stmt: Stmt, label: Option<Ident>) -> Stmt {
+        self.count += 1;
+        let label = label.unwrap_or_else(|| self.next_ident("loop"));
+
+        match stmt {
+            Stmt::While(WhileStmt { span, test, body }) => {
+                let mut stmts = vec![exit_unless(test, &label)];
+                stmts.extend(body_stmts(*labeled_body(body, &label)));
+                labeled_loop(label, span, stmts)
+            }
+            Stmt::DoWhile(DoWhileStmt { span, test, body }) => {
+                let first = self.next_ident("first");
+                let mut stmts = vec![unless_first(&first, exit_unless(test, &label))];
+                stmts.extend(body_stmts(*labeled_body(body, &label)));
+                block(
+                    span,
+                    vec![
+                        let_decl(&first, bool_lit(true)),
+                        labeled_loop(label, span, stmts),
+                    ],
+                )
+            }
+            Stmt::For(ForStmt {
+                span,
+                init: Some(VarDeclOrExpr::VarDecl(var_decl)),
+                test,
+                update,
+                body,
+            }) if var_decl.kind == VarDeclKind::Let
+                && (has_closures(&var_decl)
+                    || has_closures(&test)
+                    || has_closures(&update)
+                    || has_closures(&body)) =>
+            {
+                self.lower_for_per_iteration(span, label, *var_decl, test, update, body)
+            }
+            Stmt::For(ForStmt {
+                span,
+                init,
+                test,
+                update,
+                body,
+            }) => {
+                let mut outer = vec![];
+                match init {
+                    Some(VarDeclOrExpr::VarDecl(var_decl)) => {
+                        if var_decl.decls.iter().all(|decl| decl.name.is_ident()) {
+                            outer.push(Stmt::Decl(Decl::Var(var_decl)))
+                        } else {
+                            for decl in var_decl.decls {
+                                match decl.init {
+                                    Some(init) => {
+                                        outer.extend(bind_pattern(var_decl.kind, decl.name, init))
+                                    }
+                                    None => outer.push(declare(var_decl.kind, decl.name, None)),
+                                }
+                            }
+                        }
+                    }
+                    Some(VarDeclOrExpr::Expr(expr)) => outer.push(Stmt::Expr(ExprStmt {
+                        span: expr.span(),
+                        expr,
+                    })),
+                    None => {}
+                }
+
+                let mut stmts = vec![];
+                if let Some(update) = update {
+                    let first = self.next_ident("first");
+                    outer.push(let_decl(&first, bool_lit(true)));
+                    stmts.push(unless_first(
+                        &first,
+                        Stmt::Expr(ExprStmt {
+                            span: update.span(),
+                            expr: update,
+                        }),
+                    ));
+                }
+                if let Some(test) = test {
+                    stmts.push(exit_unless(test, &label));
+                }
+                stmts.extend(body_stmts(*labeled_body(body, &label)));
+
+                outer.push(labeled_loop(label, span, stmts));
+                block(span, outer)
+            }
+            Stmt::ForOf(ForOfStmt {
+                span,
+                left,
+                right,
+                body,
+                ..
+            }) => {
+                let iter = self.next_ident("iter");
+                let step = self.next_ident("step");
+
+                let value = member(Box::new(Expr::Ident(step.clone())), "value");
+                let bind_elem = match left {
+                    ForHead::VarDecl(var_decl) => {
+                        let VarDecl { kind, decls, .. } = *var_decl;
+                        match decls.into_iter().next() {
+                            Some(decl) => bind_pattern(kind, decl.name, value),
+                            None => vec![],
+                        }
+                    }
+                    ForHead::Pat(pat) => match AssignTarget::try_from(pat) {
+                        Ok(target) => vec![assign(target, value)],
+                        Err(pat) => {
+                            return Stmt::Labeled(LabeledStmt {
+                                span,
+                                label: label.clone(),
+                                body: Box::new(Stmt::ForOf(ForOfStmt {
+                                    span,
+                                    is_await: false,
+                                    left: ForHead::Pat(pat),
+                                    right,
+                                    body: labeled_body(body, &label),
+                                })),
+                            })
+                        }
+                    },
+                    ForHead::UsingDecl(_) => unreachable!("not lowerable"),
+                };
+
+                // `right[Symbol.iterator]()`, stepped with `.next()` one
+                // iteration at a time so lazy and infinite iterators work
+                let iterator = call(Box::new(Expr::Member(MemberExpr {
+                    span: right.span(),
+                    obj: right,
+                    prop: MemberProp::Computed(ComputedPropName {
+                        span: DUMMY_SP,
+                        expr: member(Box::new(Expr::Ident(quote_ident!("Symbol"))), "iterator"),
+                    }),
+                })));
+
+                let mut stmts = vec![
+                    let_decl(
+                        &step,
+                        call(member(Box::new(Expr::Ident(iter.clone())), "next")),
+                    ),
+                    exit_if(member(Box::new(Expr::Ident(step.clone())), "done"), &label),
+                ];
+                stmts.extend(bind_elem);
+                stmts.extend(body_stmts(*labeled_body(body, &label)));
+
+                block(
+                    span,
+                    vec![let_decl(&iter, iterator), labeled_loop(label, span, stmts)],
+                )
+            }
+            stmt => stmt,
+        }
+    }
+
+    /// Lowers a `for (let ...)` whose bindings may be captured by closures,
+    /// giving every iteration its own copy of them as JS does:
+    ///
+    /// ```text
+    /// let _first$1 = true;
+    /// let _i$1;
+    /// _loop$1: while (true) {
+    ///     let i;
+    ///     if (_first$1) { _first$1 = false; i = 0; } else { i = _i$1; i++; }
+    ///     if (!(i < n)) break _loop$1;
+    ///     _body$1: { ... }
+    ///     _i$1 = i;
+    /// }
+    /// ```
+    ///
+    /// `continue`s of the loop break out of the body instead, so that the
+    /// bindings are still carried over to the next iteration.
+    fn lower_for_per_iteration(
+        &mut self,
+        span: swc_core::common::Span,
+        label: Ident,
+        var_decl: VarDecl,
+        test: Option<Box<Expr>>,
+        update: Option<Box<Expr>>,
+        body: Box<Stmt>,
+    ) -> Stmt {
+        let first = self.next_ident("first");
+        let body_label = self.next_ident("body");
+        let bindings: Vec<Ident> = find_pat_ids(&var_decl.decls);
+        let carriers: Vec<Ident> = bindings
+            .iter()
+            .map(|binding| self.next_ident(&binding.sym))
+            .collect();
+
+        let mut outer = vec![let_decl(&first, bool_lit(true))];
+        outer.extend(carriers.iter().map(let_uninit));
+
+        let mut stmts: Vec<Stmt> = bindings.iter().map(let_uninit).collect();
+
+        let mut on_first = vec![assign(first.clone().into(), bool_lit(false))];
+        for decl in var_decl.decls {
+            if let Some(init) = decl.init {
+                on_first.push(assign(pattern_target(decl.name), init));
+            }
+        }
+        let mut on_next: Vec<Stmt> = bindings
+            .iter()
+            .zip(&carriers)
+            .map(|(binding, carrier)| {
+                assign(
+                    binding.clone().into(),
+                    Box::new(Expr::Ident(carrier.clone())),
+                )
+            })
+            .collect();
+        if let Some(update) = update {
+            on_next.push(Stmt::Expr(ExprStmt {
+                span: update.span(),
+                expr: update,
+            }));
+        }
+        stmts.push(Stmt::If(IfStmt {
+            span: DUMMY_SP,
+            test: Box::new(Expr::Ident(first.clone())),
+            cons: Box::new(block(DUMMY_SP, on_first)),
+            alt: Some(Box::new(block(DUMMY_SP, on_next))),
+        }));
+
+        if let Some(test) = test {
+            stmts.push(exit_unless(test, &label));
+        }
+
+        let mut body = labeled_body(body, &label);
+        body.visit_mut_with(&mut ContinueToBreak {
+            label: label.clone(),
+            target: body_label.clone(),
+        });
+        stmts.push(Stmt::Labeled(LabeledStmt {
+            span: DUMMY_SP,
+            label: body_label,
+            body: Box::new(block(DUMMY_SP, body_stmts(*body))),
+        }));
+        stmts.extend(bindings.iter().zip(&carriers).map(|(binding, carrier)| {
+            assign(
+                carrier.clone().into(),
+                Box::new(Expr::Ident(binding.clone())),
+            )
+        }));
+
+        outer.push(labeled_loop(label, span, stmts));
+        block(span, outer)
+    }
+}
+
+/// Turns `continue label` into `break target`.
+struct ContinueToBreak {
+    label: Ident,
+    target: Ident,
+}
+
+impl VisitMut for ContinueToBreak {
+    fn visit_mut_function(&mut self, _function: &mut Function) {
+        // no-op
+    }
+
+    fn visit_mut_arrow_expr(&mut self, _arrow: &mut ArrowExpr) {
+        // no-op
+    }
+
+    fn visit_mut_class(&mut self, _class: &mut Class) {
+        // no-op
+    }
+
+    fn visit_mut_stmt(&mut self, stmt: &mut Stmt) {
+        if let Stmt::Continue(ContinueStmt {
+            label: Some(label), ..
+        }) = stmt
+        {
+            if label.sym == self.label.sym {
+                *stmt = break_stmt(&self.target);
+            }
+            return;
+        }
+
+        stmt.visit_mut_children_with(self);
+    }
+}
+
+/// Points the unlabeled `break`s and `continue`s of a loop body at `label`.
+fn labeled_body(mut body: Box<Stmt>, label: &Ident) -> Box<Stmt> {
+    body.visit_mut_with(&mut BreakContinueLabeler {
+        label: label.clone(),
+        switch_depth: 0,
+    });
+    body
+}
+
+struct BreakContinueLabeler {
+    label: Ident,
+    switch_depth: u32,
+}
+
+impl VisitMut for BreakContinueLabeler {
+    fn visit_mut_function(&mut self, _function: &mut Function) {
+        // no-op
+    }
+
+    fn visit_mut_arrow_expr(&mut self, _arrow: &mut ArrowExpr) {
+        // no-op
+    }
+
+    fn visit_mut_class(&mut self, _class: &mut Class) {
+        // no-op
+    }
+
+    fn visit_mut_stmt(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Break(break_stmt) => {
+                if break_stmt.label.is_none() && self.switch_depth == 0 {
+                    break_stmt.label = Some(self.label.clone());
+                }
+            }
+            Stmt::Continue(continue_stmt) => {
+                if continue_stmt.label.is_none() {
+                    continue_stmt.label = Some(self.label.clone());
+                }
+            }
+            // nested loops own their unlabeled breaks and continues
+            Stmt::While(_) | Stmt::DoWhile(_) | Stmt::For(_) | Stmt::ForIn(_) | Stmt::ForOf(_) => {}
+            Stmt::Switch(_) => {
+                self.switch_depth += 1;
+                stmt.visit_mut_children_with(self);
+                self.switch_depth -= 1;
+            }
+            _ => stmt.visit_mut_children_with(self),
+        }
+    }
+}
+
+impl VisitMut for LoopLowering {
+    fn visit_mut_function(&mut self, function: &mut Function) {
+        self.fn_depth += 1;
+        function.visit_mut_children_with(self);
+        self.fn_depth -= 1;
+    }
+
+    fn visit_mut_arrow_expr(&mut self, arrow: &mut ArrowExpr) {
+        self.fn_depth += 1;
+        arrow.visit_mut_children_with(self);
+        self.fn_depth -= 1;
+    }
+
+    fn visit_mut_stmt(&mut self, stmt: &mut Stmt) {
+        if let Stmt::Labeled(labeled) = stmt {
+            if self.fn_depth > 0 && is_lowerable(&labeled.body) {
+                // lower the loop itself, not the statement it is labeled
+                // with, so that `continue label` keeps pointing at a loop
+                labeled.body.visit_mut_children_with(self);
+                let label = labeled.label.clone();
+                *stmt = self.lower(*labeled.body.take(), Some(label));
+                return;
+            }
+        }
+
+        stmt.visit_mut_children_with(self);
+        if self.fn_depth > 0 && is_lowerable(stmt) {
+            *stmt = self.lower(stmt.take(), None);
+        }
+    }
+}
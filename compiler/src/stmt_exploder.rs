@@ -1,7 +1,10 @@
 use swc_core::{
     common::{util::take::Take, BytePos, Span, Spanned, SyntaxContext},
     ecma::{
-        ast::{BlockStmt, Decl, Expr, ExprStmt, Function, Ident, Stmt, VarDecl, VarDeclarator},
+        ast::{
+            ArrowExpr, BlockStmt, Decl, Expr, ExprStmt, Function, Ident, Stmt, VarDecl,
+            VarDeclarator,
+        },
         visit::{as_folder, Fold, VisitMut, VisitMutWith},
     },
 };
@@ -31,6 +34,10 @@ impl VisitMut for CallExprExploder {
         // no-op
     }
 
+    fn visit_mut_arrow_expr(&mut self, _arrow: &mut ArrowExpr) {
+        // no-op, calls in an expression body only run when the arrow does
+    }
+
     fn visit_mut_return_stmt(&mut self, n: &mut swc_core::ecma::ast::ReturnStmt) {
         self.depth += 1;
         n.visit_mut_children_with(self);
@@ -109,6 +109,7 @@ let secondary = $fn(function secondary(a) {
                 }
             } catch (e) {
                 if ($isSuspendSignal(e)) throw e;
+                $frame_unwind($f1);
                 let $f2 = $new_frame("1", null);
                 let $sc2 = $scope($sc1, $f2);
                 switch($f2.$pc){
@@ -1,7 +1,10 @@
 mod test_bundle;
+mod test_compat;
 mod test_either_param_to_closure;
 mod test_fn_decl_to_fn_expr;
+mod test_fn_hash;
 mod test_fn_instrument;
+mod test_loop_lowering;
 mod test_stmt_exploder;
 
 use crate::{self as compiler, extract_export_name};
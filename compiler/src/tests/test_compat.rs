@@ -0,0 +1,113 @@
+use crate::{
+    apeiro_compile, FnLayouts, FrameIncompatibility, LayoutDiff, LiveFrame,
+    ProgramCounterToSourceLocation,
+};
+
+fn mapping(fns: &[(u64, &[i32])]) -> Vec<ProgramCounterToSourceLocation> {
+    fns.iter()
+        .flat_map(|(fnhash, pcs)| {
+            pcs.iter().map(|pc| ProgramCounterToSourceLocation {
+                fnhash: *fnhash,
+                pc: *pc,
+                start_loc: 0,
+                end_loc: 0,
+            })
+        })
+        .collect()
+}
+
+fn layouts(fns: &[(u64, &[i32])]) -> FnLayouts {
+    FnLayouts::new(&mapping(fns))
+}
+
+#[test]
+fn test_every_statement_has_a_pc() {
+    let compiled = apeiro_compile(
+        r#"export default function main() {
+    let a = $recv();
+    log(a);
+    return a;
+}"#
+        .to_string(),
+    )
+    .unwrap();
+
+    // returns and the last statement can be suspended in too
+    let frames = [LiveFrame { fnhash: 1, pc: 2 }];
+    assert_eq!(
+        FnLayouts::new(&compiled.program_counter_mapping).check_frames(&frames),
+        vec![]
+    );
+}
+
+#[test]
+fn test_check_frames() {
+    let new = layouts(&[(10, &[0, 1, 2]), (20, &[0])]);
+
+    let frames = [
+        LiveFrame { fnhash: 10, pc: 2 },
+        LiveFrame { fnhash: 20, pc: 0 },
+    ];
+    assert_eq!(new.check_frames(&frames), vec![]);
+
+    let frames = [
+        LiveFrame { fnhash: 10, pc: 3 },
+        LiveFrame { fnhash: 30, pc: 0 },
+    ];
+    assert_eq!(
+        new.check_frames(&frames),
+        vec![
+            FrameIncompatibility::MissingPc {
+                depth: 0,
+                fnhash: 10,
+                pc: 3
+            },
+            FrameIncompatibility::MissingFn {
+                depth: 1,
+                fnhash: 30
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_diff() {
+    let old = layouts(&[(10, &[0, 1, 2]), (20, &[0]), (30, &[0, 1])]);
+    let new = layouts(&[(10, &[0, 1, 2]), (20, &[0, 1]), (40, &[0])]);
+
+    assert_eq!(
+        old.diff(&new),
+        LayoutDiff {
+            added: vec![40],
+            removed: vec![30],
+            changed: vec![20],
+        }
+    );
+}
+
+#[test]
+fn test_partial_layouts() {
+    // stored before returns and last statements were mapped
+    let old = FnLayouts::partial(&mapping(&[(10, &[0, 1]), (20, &[0, 1])]));
+
+    let frames = [LiveFrame { fnhash: 10, pc: 2 }];
+    assert_eq!(old.check_frames(&frames), vec![]);
+    let frames = [LiveFrame { fnhash: 30, pc: 0 }];
+    assert_eq!(
+        old.check_frames(&frames),
+        vec![FrameIncompatibility::MissingFn {
+            depth: 0,
+            fnhash: 30
+        }]
+    );
+
+    let new = layouts(&[(10, &[0, 1, 2]), (20, &[0, 2])]);
+    assert_eq!(
+        old.diff(&new),
+        LayoutDiff {
+            added: vec![],
+            removed: vec![],
+            changed: vec![20],
+        }
+    );
+}
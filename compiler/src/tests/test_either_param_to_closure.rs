@@ -10,6 +10,7 @@ fn test_either_param_to_closure() {
         [suspend(3, 4), function (received) {
             return console.log(received);
         }],
+        [{ type: "cancel" }, () => null],
     ]);
 }"#,
         |_| either_param_to_closure::folder(),
@@ -24,6 +25,12 @@ fn test_either_param_to_closure() {
             function(received) {
                 return console.log(received);
             }
+        ],
+        [
+            ()=>({
+                    type: "cancel"
+                }),
+            ()=>null
         ]
     ]);
 }
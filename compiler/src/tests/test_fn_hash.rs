@@ -0,0 +1,97 @@
+use crate::{apeiro_compile, utils::REAL_HASHES};
+
+/// The fnhashes of the compiled module, in the order their fns' program
+/// counters were mapped.
+fn fn_hashes(src: &str) -> Vec<u64> {
+    REAL_HASHES.with(|real| real.set(true));
+    let compiled = apeiro_compile(src.to_string());
+    REAL_HASHES.with(|real| real.set(false));
+
+    let mut hashes = vec![];
+    for entry in compiled.unwrap().program_counter_mapping {
+        if !hashes.contains(&entry.fnhash) {
+            hashes.push(entry.fnhash);
+        }
+    }
+    hashes
+}
+
+const ORIGINAL: &str = r#"function sum(a, b) {
+    return a + b * 2;
+}
+
+export default function main() {
+    const msg = $recv();
+    log("got " + msg, 0x10);
+    return sum(1, 2);
+}"#;
+
+#[test]
+fn test_reformatting_keeps_hashes() {
+    let reformatted = r#"// adds things up
+function sum(a,b){ return a + (b * 2) }
+
+/* the entry point */
+export default function main()
+{
+    const msg = $recv() // wait for one
+    log('got ' + (msg), 16);
+
+    return (sum(1.0, 2));
+}"#;
+
+    let original = fn_hashes(ORIGINAL);
+    assert_eq!(original.len(), 2);
+    assert_eq!(fn_hashes(reformatted), original);
+}
+
+#[test]
+fn test_semantic_edits_change_hashes() {
+    let original = fn_hashes(ORIGINAL);
+
+    // precedence changed by the parentheses
+    let edited = fn_hashes(&ORIGINAL.replace("a + b * 2", "(a + b) * 2"));
+    assert_ne!(edited[0], original[0]);
+    assert_eq!(edited[1], original[1]);
+
+    let edited = fn_hashes(&ORIGINAL.replace("\"got \"", "\"received \""));
+    assert_eq!(edited[0], original[0]);
+    assert_ne!(edited[1], original[1]);
+
+    let edited = fn_hashes(&ORIGINAL.replace("const msg", "let msg"));
+    assert_ne!(edited[1], original[1]);
+
+    let edited = fn_hashes(&ORIGINAL.replace("sum(1, 2)", "sum(2, 1)"));
+    assert_ne!(edited[1], original[1]);
+}
+
+#[test]
+fn test_generated_names_keep_hashes() {
+    let src = r#"function sum(a, b) {
+    return f(a) + g(b);
+}
+
+export default function main() {
+    for (const msg of $recv()) {
+        log(msg);
+    }
+    let i = 0;
+    while (i < 3) {
+        i = sum(i, 1);
+    }
+    return log(i);
+}"#;
+    // the temporaries and loop state of `main` are numbered after those of
+    // `sum`, which now has one more
+    let edited = src.replace("f(a) + g(b)", "f(a) + g(h(b))");
+
+    let original = fn_hashes(src);
+    let edited = fn_hashes(&edited);
+    assert_eq!(original.len(), 2);
+    assert_ne!(edited[0], original[0]);
+    assert_eq!(edited[1], original[1]);
+
+    // swapping which temporary is used where is still an edit
+    let swapped = fn_hashes(&src.replace("f(a) + g(b)", "g(b) + f(a)"));
+    assert_ne!(swapped[0], original[0]);
+}
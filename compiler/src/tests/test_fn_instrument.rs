@@ -309,6 +309,8 @@ fn test_fn_while() {
                                 case 0:
                                     let __return_val = $sc3.state.value.value;
                                     $frame_end($f3);
+                                    $frame_end($f2);
+                                    $frame_end($f1);
                                     return __return_val;
                             }
                         }
@@ -322,6 +324,40 @@ fn test_fn_while() {
     );
 }
 
+// a `return` ends the frames of every block it leaves, not just the
+// innermost one, so that the caller finds its own frame on top
+#[test]
+fn test_return_ends_enclosing_frames() {
+    compiler_test(
+        "function first(xs) {
+    if (xs.length) {
+        return xs[0];
+    }
+}",
+        folder_chain!(),
+        r#"let first = $fn(function first(xs) {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            if (xs.length) {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        let __return_val = xs[0];
+                        $frame_end($f2);
+                        $frame_end($f1);
+                        return __return_val;
+                }
+            }
+            $frame_end($f1);
+    }
+}, "1", null);
+"#,
+    );
+}
+
 #[test]
 fn test_fn_instrument() {
     compiler_test(
@@ -361,3 +397,60 @@ fn test_rewrite_vars() {
 }, "1", null);"#,
     );
 }
+
+#[test]
+fn test_fn_try_finally() {
+    compiler_test(
+        "function f() {
+    try {
+        work();
+    } catch {
+        recover();
+    } finally {
+        cleanup();
+    }
+}",
+        folder_chain!(),
+        r#"let f = $fn(function f() {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            try {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        work();
+                        $frame_end($f2);
+                }
+            } catch (e) {
+                if ($isSuspendSignal(e)) throw e;
+                $frame_unwind($f1);
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        recover();
+                        $frame_end($f2);
+                }
+            } finally{
+                if (!$isSuspending()) {
+                    $frame_unwind($f1);
+                    {
+                        let $f2 = $new_frame("1", null);
+                        let $sc2 = $scope($sc1, $f2);
+                        switch($f2.$pc){
+                            case 0:
+                                cleanup();
+                                $frame_end($f2);
+                        }
+                    }
+                }
+            }
+            $frame_end($f1);
+    }
+}, "1", null);
+"#,
+    );
+}
@@ -0,0 +1,607 @@
+use swc_core::common::chain;
+
+use super::compiler_test;
+use crate::{fn_decl_to_fn_expr, fn_instrument, loop_lowering, stmt_exploder};
+
+macro_rules! folder_chain {
+    () => {
+        |_| {
+            chain!(
+                fn_decl_to_fn_expr::folder(),
+                loop_lowering::folder(),
+                stmt_exploder::folder(),
+                fn_instrument::folder(),
+            )
+        }
+    };
+}
+
+#[test]
+fn test_for() {
+    compiler_test(
+        r#"function count() {
+    for (let i = 0; i < 3; i++) {
+        $recv();
+    }
+}"#,
+        folder_chain!(),
+        r#"let count = $fn(function count() {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        $sc2.i = {
+                            value: 0
+                        };
+                        $f2.$pc = 1;
+                    case 1:
+                        $sc2._first$1 = {
+                            value: true
+                        };
+                        $f2.$pc = 2;
+                    case 2:
+                        _loop$1: while(true){
+                            let $f3 = $new_frame("1", null);
+                            let $sc3 = $scope($sc2, $f3);
+                            switch($f3.$pc){
+                                case 0:
+                                    if ($sc3._first$1.value) $sc3._first$1.value = false;
+                                    else $sc3.i.value++;
+                                    $f3.$pc = 1;
+                                case 1:
+                                    if (!($sc3.i.value < 3)) {
+                                        $frame_end($f3);
+                                        break _loop$1;
+                                    }
+                                    $f3.$pc = 2;
+                                case 2:
+                                    $recv();
+                                    $frame_end($f3);
+                            }
+                        }
+                        $frame_end($f2);
+                }
+            }
+            $frame_end($f1);
+    }
+}, "1", null);
+"#,
+    );
+}
+
+#[test]
+fn test_for_of() {
+    compiler_test(
+        r#"function each(items) {
+    for (const item of items) {
+        log(item);
+        $recv();
+    }
+}"#,
+        folder_chain!(),
+        r#"let each = $fn(function each(items) {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        $sc2._temp$1 = {
+                            value: items[Symbol.iterator]()
+                        };
+                        $f2.$pc = 1;
+                    case 1:
+                        $sc2._iter$1 = {
+                            value: $sc2._temp$1.value
+                        };
+                        $f2.$pc = 2;
+                    case 2:
+                        delete $sc2._temp$1.value;
+                        $f2.$pc = 3;
+                    case 3:
+                        _loop$1: while(true){
+                            let $f3 = $new_frame("1", null);
+                            let $sc3 = $scope($sc2, $f3);
+                            switch($f3.$pc){
+                                case 0:
+                                    $sc3._temp$2 = {
+                                        value: $sc3._iter$1.value.next()
+                                    };
+                                    $f3.$pc = 1;
+                                case 1:
+                                    $sc3._step$1 = {
+                                        value: $sc3._temp$2.value
+                                    };
+                                    $f3.$pc = 2;
+                                case 2:
+                                    delete $sc3._temp$2.value;
+                                    $f3.$pc = 3;
+                                case 3:
+                                    if ($sc3._step$1.value.done) {
+                                        $frame_end($f3);
+                                        break _loop$1;
+                                    }
+                                    $f3.$pc = 4;
+                                case 4:
+                                    $sc3.item = {
+                                        value: $sc3._step$1.value.value
+                                    };
+                                    $f3.$pc = 5;
+                                case 5:
+                                    log($sc3.item.value);
+                                    $f3.$pc = 6;
+                                case 6:
+                                    $recv();
+                                    $frame_end($f3);
+                            }
+                        }
+                        $frame_end($f2);
+                }
+            }
+            $frame_end($f1);
+    }
+}, "1", null);
+"#,
+    );
+}
+
+#[test]
+fn test_while() {
+    compiler_test(
+        r#"function until_done() {
+    let done = false;
+    while (!done) {
+        done = $recv().done;
+    }
+}"#,
+        folder_chain!(),
+        r#"let until_done = $fn(function until_done() {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            $sc1.done = {
+                value: false
+            };
+            $f1.$pc = 1;
+        case 1:
+            _loop$1: while(true){
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        if (!(!$sc2.done.value)) {
+                            $frame_end($f2);
+                            break _loop$1;
+                        }
+                        $f2.$pc = 1;
+                    case 1:
+                        $sc2.done.value = $recv().done;
+                        $frame_end($f2);
+                }
+            }
+            $frame_end($f1);
+    }
+}, "1", null);
+"#,
+    );
+}
+
+#[test]
+fn test_do_while() {
+    compiler_test(
+        r#"function at_least_once() {
+    let msg;
+    do {
+        msg = $recv();
+    } while (msg.again);
+}"#,
+        folder_chain!(),
+        r#"let at_least_once = $fn(function at_least_once() {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            $sc1.msg = {};
+            $f1.$pc = 1;
+        case 1:
+            {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        $sc2._first$1 = {
+                            value: true
+                        };
+                        $f2.$pc = 1;
+                    case 1:
+                        _loop$1: while(true){
+                            let $f3 = $new_frame("1", null);
+                            let $sc3 = $scope($sc2, $f3);
+                            switch($f3.$pc){
+                                case 0:
+                                    if ($sc3._first$1.value) $sc3._first$1.value = false;
+                                    else if (!($sc3.msg.value.again)) {
+                                        $frame_end($f3);
+                                        break _loop$1;
+                                    }
+                                    $f3.$pc = 1;
+                                case 1:
+                                    $sc3.msg.value = $recv();
+                                    $frame_end($f3);
+                            }
+                        }
+                        $frame_end($f2);
+                }
+            }
+            $frame_end($f1);
+    }
+}, "1", null);
+"#,
+    );
+}
+
+#[test]
+fn test_break_continue() {
+    compiler_test(
+        r#"function skip() {
+    outer: for (const x of xs) {
+        while (true) {
+            if (x.skip) {
+                continue outer;
+            }
+            if (x.last) {
+                break;
+            }
+            $recv();
+        }
+    }
+}"#,
+        folder_chain!(),
+        r#"let skip = $fn(function skip() {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        $sc2._temp$1 = {
+                            value: xs[Symbol.iterator]()
+                        };
+                        $f2.$pc = 1;
+                    case 1:
+                        $sc2._iter$2 = {
+                            value: $sc2._temp$1.value
+                        };
+                        $f2.$pc = 2;
+                    case 2:
+                        delete $sc2._temp$1.value;
+                        $f2.$pc = 3;
+                    case 3:
+                        outer: while(true){
+                            let $f3 = $new_frame("1", null);
+                            let $sc3 = $scope($sc2, $f3);
+                            switch($f3.$pc){
+                                case 0:
+                                    $sc3._temp$2 = {
+                                        value: $sc3._iter$2.value.next()
+                                    };
+                                    $f3.$pc = 1;
+                                case 1:
+                                    $sc3._step$2 = {
+                                        value: $sc3._temp$2.value
+                                    };
+                                    $f3.$pc = 2;
+                                case 2:
+                                    delete $sc3._temp$2.value;
+                                    $f3.$pc = 3;
+                                case 3:
+                                    if ($sc3._step$2.value.done) {
+                                        $frame_end($f3);
+                                        break outer;
+                                    }
+                                    $f3.$pc = 4;
+                                case 4:
+                                    $sc3.x = {
+                                        value: $sc3._step$2.value.value
+                                    };
+                                    $f3.$pc = 5;
+                                case 5:
+                                    _loop$1: while(true){
+                                        let $f4 = $new_frame("1", null);
+                                        let $sc4 = $scope($sc3, $f4);
+                                        switch($f4.$pc){
+                                            case 0:
+                                                if (!(true)) {
+                                                    $frame_end($f4);
+                                                    break _loop$1;
+                                                }
+                                                $f4.$pc = 1;
+                                            case 1:
+                                                if ($sc4.x.value.skip) {
+                                                    let $f5 = $new_frame("1", null);
+                                                    let $sc5 = $scope($sc4, $f5);
+                                                    switch($f5.$pc){
+                                                        case 0:
+                                                            {
+                                                                $frame_end($f5);
+                                                                $frame_end($f4);
+                                                                $frame_end($f3);
+                                                                continue outer;
+                                                            }
+                                                            $frame_end($f5);
+                                                    }
+                                                }
+                                                $f4.$pc = 2;
+                                            case 2:
+                                                if ($sc4.x.value.last) {
+                                                    let $f5 = $new_frame("1", null);
+                                                    let $sc5 = $scope($sc4, $f5);
+                                                    switch($f5.$pc){
+                                                        case 0:
+                                                            {
+                                                                $frame_end($f5);
+                                                                $frame_end($f4);
+                                                                break _loop$1;
+                                                            }
+                                                            $frame_end($f5);
+                                                    }
+                                                }
+                                                $f4.$pc = 3;
+                                            case 3:
+                                                $recv();
+                                                $frame_end($f4);
+                                        }
+                                    }
+                                    $frame_end($f3);
+                            }
+                        }
+                        $frame_end($f2);
+                }
+            }
+            $frame_end($f1);
+    }
+}, "1", null);
+"#,
+    );
+}
+
+#[test]
+fn test_for_of_destructuring() {
+    compiler_test(
+        r#"function sum(counts) {
+    let total = 0;
+    for (const [name, { count, weight = 1 }] of Object.entries(counts)) {
+        total += count * weight;
+        $recv();
+    }
+    return total;
+}"#,
+        folder_chain!(),
+        r#"let sum = $fn(function sum(counts) {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            $sc1.total = {
+                value: 0
+            };
+            $f1.$pc = 1;
+        case 1:
+            {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        $sc2._temp$2 = {
+                            value: Object.entries(counts)
+                        };
+                        $f2.$pc = 1;
+                    case 1:
+                        $sc2._temp$1 = {
+                            value: $sc2._temp$2.value[Symbol.iterator]()
+                        };
+                        $f2.$pc = 2;
+                    case 2:
+                        $sc2._iter$1 = {
+                            value: $sc2._temp$1.value
+                        };
+                        $f2.$pc = 3;
+                    case 3:
+                        delete $sc2._temp$2.value;
+                        $f2.$pc = 4;
+                    case 4:
+                        delete $sc2._temp$1.value;
+                        $f2.$pc = 5;
+                    case 5:
+                        _loop$1: while(true){
+                            let $f3 = $new_frame("1", null);
+                            let $sc3 = $scope($sc2, $f3);
+                            switch($f3.$pc){
+                                case 0:
+                                    $sc3._temp$3 = {
+                                        value: $sc3._iter$1.value.next()
+                                    };
+                                    $f3.$pc = 1;
+                                case 1:
+                                    $sc3._step$1 = {
+                                        value: $sc3._temp$3.value
+                                    };
+                                    $f3.$pc = 2;
+                                case 2:
+                                    delete $sc3._temp$3.value;
+                                    $f3.$pc = 3;
+                                case 3:
+                                    if ($sc3._step$1.value.done) {
+                                        $frame_end($f3);
+                                        break _loop$1;
+                                    }
+                                    $f3.$pc = 4;
+                                case 4:
+                                    $sc3.name = {};
+                                    $f3.$pc = 5;
+                                case 5:
+                                    $sc3.count = {};
+                                    $f3.$pc = 6;
+                                case 6:
+                                    $sc3.weight = {};
+                                    $f3.$pc = 7;
+                                case 7:
+                                    [$sc3.name.value, { count: $sc3.count.value, weight: $sc3.weight.value = 1 }] = $sc3._step$1.value.value;
+                                    $f3.$pc = 8;
+                                case 8:
+                                    $sc3.total.value += $sc3.count.value * $sc3.weight.value;
+                                    $f3.$pc = 9;
+                                case 9:
+                                    $recv();
+                                    $frame_end($f3);
+                            }
+                        }
+                        $frame_end($f2);
+                }
+            }
+            $f1.$pc = 2;
+        case 2:
+            let __return_val = $sc1.total.value;
+            $frame_end($f1);
+            return __return_val;
+    }
+}, "1", null);
+"#,
+    );
+}
+
+#[test]
+fn test_for_binding_per_iteration() {
+    compiler_test(
+        r#"function handlers() {
+    const fns = [];
+    for (let i = 0; i < 3; i++) {
+        if (i == 1) {
+            continue;
+        }
+        fns.push(() => i);
+        $recv();
+    }
+    return fns;
+}"#,
+        folder_chain!(),
+        r#"let handlers = $fn(function handlers() {
+    let $f1 = $new_frame("1", null);
+    let $sc1 = $scope(undefined, $f1);
+    switch($f1.$pc){
+        case 0:
+            $sc1.fns = {
+                value: []
+            };
+            $f1.$pc = 1;
+        case 1:
+            {
+                let $f2 = $new_frame("1", null);
+                let $sc2 = $scope($sc1, $f2);
+                switch($f2.$pc){
+                    case 0:
+                        $sc2._first$1 = {
+                            value: true
+                        };
+                        $f2.$pc = 1;
+                    case 1:
+                        $sc2._i$1 = {};
+                        $f2.$pc = 2;
+                    case 2:
+                        _loop$1: while(true){
+                            let $f3 = $new_frame("1", null);
+                            let $sc3 = $scope($sc2, $f3);
+                            switch($f3.$pc){
+                                case 0:
+                                    $sc3.i = {};
+                                    $f3.$pc = 1;
+                                case 1:
+                                    if ($sc3._first$1.value) {
+                                        let $f4 = $new_frame("1", null);
+                                        let $sc4 = $scope($sc3, $f4);
+                                        switch($f4.$pc){
+                                            case 0:
+                                                $sc4._first$1.value = false;
+                                                $f4.$pc = 1;
+                                            case 1:
+                                                $sc4.i.value = 0;
+                                                $frame_end($f4);
+                                        }
+                                    } else {
+                                        let $f4 = $new_frame("1", null);
+                                        let $sc4 = $scope($sc3, $f4);
+                                        switch($f4.$pc){
+                                            case 0:
+                                                $sc4.i.value = $sc4._i$1.value;
+                                                $f4.$pc = 1;
+                                            case 1:
+                                                $sc4.i.value++;
+                                                $frame_end($f4);
+                                        }
+                                    }
+                                    $f3.$pc = 2;
+                                case 2:
+                                    if (!($sc3.i.value < 3)) {
+                                        $frame_end($f3);
+                                        break _loop$1;
+                                    }
+                                    $f3.$pc = 3;
+                                case 3:
+                                    _body$1: {
+                                        let $f4 = $new_frame("1", null);
+                                        let $sc4 = $scope($sc3, $f4);
+                                        switch($f4.$pc){
+                                            case 0:
+                                                if ($sc4.i.value == 1) {
+                                                    let $f5 = $new_frame("1", null);
+                                                    let $sc5 = $scope($sc4, $f5);
+                                                    switch($f5.$pc){
+                                                        case 0:
+                                                            {
+                                                                $frame_end($f5);
+                                                                $frame_end($f4);
+                                                                break _body$1;
+                                                            }
+                                                            $frame_end($f5);
+                                                    }
+                                                }
+                                                $f4.$pc = 1;
+                                            case 1:
+                                                $sc4.fns.value.push(()=>$sc4.i.value);
+                                                $f4.$pc = 2;
+                                            case 2:
+                                                $recv();
+                                                $frame_end($f4);
+                                        }
+                                    }
+                                    $f3.$pc = 4;
+                                case 4:
+                                    $sc3._i$1.value = $sc3.i.value;
+                                    $frame_end($f3);
+                            }
+                        }
+                        $frame_end($f2);
+                }
+            }
+            $f1.$pc = 2;
+        case 2:
+            let __return_val = $sc1.fns.value;
+            $frame_end($f1);
+            return __return_val;
+    }
+}, "1", null);
+"#,
+    );
+}
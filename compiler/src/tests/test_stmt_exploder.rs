@@ -112,3 +112,20 @@ fn test_console_log_with_expr() {
 "#,
     );
 }
+
+#[test]
+fn test_arrow_expr_body() {
+    compiler_test(
+        r#"function gof(xs) {
+    return xs.map((x) => f(x));
+}
+"#,
+        folder_chain!(),
+        r#"function gof(xs) {
+    const _temp$1 = xs.map((x)=>f(x));
+    return _temp$1;
+    delete _temp$1;
+}
+"#,
+    );
+}
@@ -1,10 +1,16 @@
+use std::collections::HashMap;
+
 use swc_core::{
-    common::{sync::Lrc, SourceMap},
-    ecma::ast::{Expr, ExprStmt, Lit, Stmt},
+    common::{sync::Lrc, util::take::Take, SourceMap, Span, DUMMY_SP},
+    ecma::{
+        ast::{BigInt, Expr, ExprStmt, FnExpr, Ident, Lit, Number, Stmt, Str},
+        atoms::JsWord,
+        transforms::base::fixer::fixer,
+        visit::{VisitMut, VisitMutWith},
+    },
 };
 use swc_ecma_codegen::{text_writer::WriteJs, Emitter};
 
-#[allow(dead_code)]
 pub fn ast_to_str<T: swc_ecma_codegen::Node>(node: &T) -> Vec<u8> {
     let cm: Lrc<SourceMap> = Default::default();
     let mut buf = vec![];
@@ -29,13 +35,92 @@ pub fn ast_to_str<T: swc_ecma_codegen::Node>(node: &T) -> Vec<u8> {
 }
 
 #[cfg(test)]
-pub fn ast_to_hash<T: swc_ecma_codegen::Node>(_node: &T) -> u64 {
-    1
+thread_local! {
+    /// Set by tests of the hashes themselves, the others expect every fn
+    /// to hash to 1.
+    pub static REAL_HASHES: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+#[cfg(test)]
+pub fn ast_to_hash(fn_expr: &FnExpr) -> u64 {
+    if REAL_HASHES.with(|real| real.get()) {
+        normalized_hash(fn_expr)
+    } else {
+        1
+    }
 }
 
 #[cfg(not(test))]
-pub fn ast_to_hash<T: swc_ecma_codegen::Node>(node: &T) -> u64 {
-    xxhash_rust::xxh3::xxh3_64(&ast_to_str(node))
+pub fn ast_to_hash(fn_expr: &FnExpr) -> u64 {
+    normalized_hash(fn_expr)
+}
+
+/// Hash of `fn_expr` that only changes with what it does. Spans, comments,
+/// quoting, how numbers are spelled, redundant parentheses and the numbers
+/// of the identifiers earlier passes generated are left out, so reformatting
+/// a module or editing its other fns keeps the hash.
+fn normalized_hash(fn_expr: &FnExpr) -> u64 {
+    let mut expr = Expr::Fn(fn_expr.clone());
+    expr.visit_mut_with(&mut Normalize::default());
+    // puts back the parentheses precedence requires, and only those
+    expr.visit_mut_with(&mut fixer(None));
+    xxhash_rust::xxh3::xxh3_64(&ast_to_str(&expr))
+}
+
+/// Strips what the source's formatting and the numbering of generated
+/// identifiers leave in the AST.
+#[derive(Default)]
+struct Normalize {
+    /// generated identifiers in the order they were first seen
+    generated: HashMap<JsWord, usize>,
+}
+
+/// Splits identifiers like `_temp$3` or `_loop$12` into their name and
+/// number.
+fn generated_name(sym: &str) -> Option<&str> {
+    let (name, count) = sym.rsplit_once('$')?;
+    let generated = name.len() > 1
+        && name.starts_with('_')
+        && !count.is_empty()
+        && count.bytes().all(|b| b.is_ascii_digit());
+    generated.then_some(name)
+}
+
+impl VisitMut for Normalize {
+    fn visit_mut_span(&mut self, span: &mut Span) {
+        *span = DUMMY_SP;
+    }
+
+    fn visit_mut_ident(&mut self, ident: &mut Ident) {
+        if let Some(name) = generated_name(&ident.sym) {
+            let next = self.generated.len();
+            let n = *self.generated.entry(ident.sym.clone()).or_insert(next);
+            ident.sym = format!("{}${}", name, n).into();
+        }
+        ident.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_expr(&mut self, expr: &mut Expr) {
+        while let Expr::Paren(paren) = expr {
+            *expr = *paren.expr.take();
+        }
+        expr.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_str(&mut self, str: &mut Str) {
+        str.raw = None;
+        str.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_number(&mut self, number: &mut Number) {
+        number.raw = None;
+        number.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_big_int(&mut self, big_int: &mut BigInt) {
+        big_int.raw = None;
+        big_int.visit_mut_children_with(self);
+    }
 }
 
 pub fn is_use_strict(first_stmt: &Stmt) -> bool {
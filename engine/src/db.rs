@@ -1,12 +1,30 @@
 use std::fmt::Debug;
 
-use apeiro_compiler::CompilationResult;
+use apeiro_compiler::{CompilationResult, ProgramCounterToSourceLocation};
 use apeiro_internal_api::{
-    EngineStatus, ModuleSummary, ProcDetails, ProcGetResponse, ProcStatusDebug, ProcSummary,
-    StepResult,
+    EngineStatus, ErrorPolicy, MboxMessage, ModuleLimits, ModuleSummary, ProcDetails,
+    ProcGetResponse, ProcStatusDebug, ProcSummary, ResumeMode, RetryPolicy, StepDetails,
+    StepReplay, StepResult, StepResultStatus, StepSummary,
 };
 use serde_json;
 
+use crate::outbox::OutboundPost;
+
+/// What a step did besides moving its proc on, stored in the same
+/// transaction as the step so that a crash can't keep one without the other.
+#[derive(Debug, Default)]
+pub struct StepEffects<'a> {
+    /// the mailbox messages it received
+    pub mbox_read: &'a [String],
+    /// the timers it started, with their deadlines
+    pub timers_started: &'a [(String, u64)],
+    pub timers_cancelled: &'a [String],
+    /// the timers delivered to it, which are spent
+    pub timers_fired: &'a [String],
+    /// the posts it queued with `$http_post`
+    pub posts: &'a [OutboundPost],
+}
+
 pub trait ApeiroPersistence: Sync + Send + Debug + 'static {
     fn init(&self) -> Result<(), anyhow::Error>;
 
@@ -15,13 +33,21 @@ pub trait ApeiroPersistence: Sync + Send + Debug + 'static {
     fn plugin_set_state(&self, name: &String, val: &serde_json::Value)
         -> Result<(), anyhow::Error>;
 
-    fn proc_new(&self, module_id: &String, name: &Option<String>) -> Result<String, anyhow::Error>;
+    fn proc_new(
+        &self,
+        module_id: &String,
+        name: &Option<String>,
+        resume_mode: Option<ResumeMode>,
+        module_version: u32,
+    ) -> Result<String, anyhow::Error>;
 
-    fn proc_subscription_new(
+    /// Makes `subscription` the proc's only one, or drops the one it has if
+    /// `None`.
+    fn proc_subscription_set(
         &self,
         proc_id: &String,
-        subscription: &serde_json::Value,
-    ) -> Result<String, anyhow::Error>;
+        subscription: Option<&serde_json::Value>,
+    ) -> Result<(), anyhow::Error>;
 
     fn proc_subscriptions_get_all(&self)
         -> Result<Vec<(String, serde_json::Value)>, anyhow::Error>;
@@ -32,15 +58,106 @@ pub trait ApeiroPersistence: Sync + Send + Debug + 'static {
         new_name: &String,
     ) -> Result<(), anyhow::Error>;
 
+    /// Stores a new step of the proc, continuing from its current one, and
+    /// makes it current, along with its `effects`. `msg` is what triggered
+    /// the step. A proc that didn't suspend is left without timers.
     fn proc_update(
         &self,
         id: &String,
+        msg: Option<&serde_json::Value>,
         state: &StepResult,
         engine_status: &EngineStatus,
+        effects: &StepEffects,
     ) -> Result<(), anyhow::Error>;
 
+    /// Stores a failed step of the proc that ran at `clock`, continuing from
+    /// its current one, without making it current. Returns its step id. A
+    /// retry is stored with `retry_of`, the failed step it first retried. Of
+    /// its `effects`, only the messages and timers it spent are kept.
+    fn proc_step_failed(
+        &self,
+        id: &str,
+        msg: Option<&serde_json::Value>,
+        state: &StepResult,
+        clock: u64,
+        retry_of: Option<u64>,
+        effects: &StepEffects,
+    ) -> Result<u64, anyhow::Error>;
+
+    /// Stores a failed step of the proc like `proc_step_failed`, and makes it
+    /// current: the proc is done, in error. Returns its step id.
+    fn proc_fail(
+        &self,
+        id: &str,
+        msg: Option<&serde_json::Value>,
+        state: &StepResult,
+        clock: u64,
+        retry_of: Option<u64>,
+        effects: &StepEffects,
+    ) -> Result<u64, anyhow::Error>;
+
+    /// The steps continuing from the proc's current one that failed, i.e.
+    /// since it last stepped, oldest first. Only the attempts of the failed
+    /// step `retry_of` and its retries if it is set.
+    fn proc_failed_steps(
+        &self,
+        id: &str,
+        retry_of: Option<u64>,
+    ) -> Result<Vec<StepSummary>, anyhow::Error>;
+
+    /// What stepping the proc from its current step takes. Fails if that
+    /// step can't be read, see `proc_repair`.
     fn proc_get_details(&self, id: &String) -> Result<ProcDetails, anyhow::Error>;
 
+    /// The status of the proc's current step, the frames it left the proc
+    /// in and the version of its module the proc runs. Unlike
+    /// `proc_get_details`, its snapshot isn't loaded.
+    fn proc_frames(
+        &self,
+        id: &str,
+    ) -> Result<(StepResultStatus, Option<serde_json::Value>, u32), anyhow::Error>;
+
+    /// Readies a proc whose current step can't be read in full to step
+    /// again: it is flagged for an operator to look into, and continues from
+    /// that step's frames if its snapshot is what's unreadable, or else from
+    /// the latest readable step before it, as if rewound to it.
+    fn proc_repair(&self, id: &str) -> Result<(), anyhow::Error>;
+
+    /// Every step of a proc, oldest first, including those no longer on the
+    /// path to its current step after a rewind.
+    fn proc_history(&self, id: &str) -> Result<Vec<StepSummary>, anyhow::Error>;
+
+    fn proc_step_get(&self, id: &str, step_id: u64) -> Result<StepDetails, anyhow::Error>;
+
+    /// What running `step_id` of the proc again takes.
+    fn proc_step_replay(&self, id: &str, step_id: u64) -> Result<StepReplay, anyhow::Error>;
+
+    /// Makes `step_id` the proc's current step, which the next step continues
+    /// from. Steps after it are kept.
+    fn proc_rewind(&self, id: &str, step_id: u64) -> Result<(), anyhow::Error>;
+
+    /// Starts a new proc named `name` from a copy of one of `id`'s steps, its
+    /// current one if `step_id` is unset. Returns the new proc's id.
+    fn proc_fork(
+        &self,
+        id: &str,
+        step_id: Option<u64>,
+        name: &str,
+    ) -> Result<String, anyhow::Error>;
+
+    /// Moves the proc onto another version of its module, provided it is
+    /// still at `step_id`, the step its frames were checked at. Its next
+    /// step is resumed from its frames under that version.
+    fn proc_migrate(
+        &self,
+        id: &str,
+        step_id: u64,
+        module_version: u32,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Clears the reason the proc was flagged for, once it was looked into.
+    fn proc_unflag(&self, id: &str) -> Result<(), anyhow::Error>;
+
     fn proc_get(&self, id: &String) -> Result<ProcGetResponse, anyhow::Error>;
 
     fn proc_list(&self) -> Result<Vec<ProcSummary>, anyhow::Error>;
@@ -49,12 +166,57 @@ pub trait ApeiroPersistence: Sync + Send + Debug + 'static {
 
     fn proc_delete(&self, id: &String) -> Result<(), anyhow::Error>;
 
+    fn mbox_push(&self, proc_id: &String, msg: &serde_json::Value)
+        -> Result<String, anyhow::Error>;
+
+    /// Pending (unread) messages of a proc, oldest first.
+    fn mbox_list(&self, proc_id: &String) -> Result<Vec<MboxMessage>, anyhow::Error>;
+
+    /// Drops all pending messages of a proc, returning how many were removed.
+    fn mbox_purge(&self, proc_id: &String) -> Result<usize, anyhow::Error>;
+
+    /// Timers whose deadline is at or before `now`, as `(proc_id, timer_id)`,
+    /// that weren't fired or whose firing is claimed by no one anymore.
+    fn timers_due(&self, now: u64) -> Result<Vec<(String, String)>, anyhow::Error>;
+
+    /// Marks a due timer fired, claiming it until `until`: if the step it is
+    /// sent to hasn't spent it by then, it is due again. Returns false if it
+    /// wasn't due anymore, e.g. because someone else claimed it.
+    fn timer_claim(&self, id: &str, now: u64, until: u64) -> Result<bool, anyhow::Error>;
+
+    /// Re-arms timers that were fired but never consumed by their proc,
+    /// e.g. because the daemon went down before the proc could step.
+    fn timers_rearm(&self) -> Result<(), anyhow::Error>;
+
+    /// Queues a post for delivery, unless one with the same key already is.
+    fn outbox_push(&self, post: &OutboundPost, not_before: u64) -> Result<(), anyhow::Error>;
+
+    /// Queued posts whose next attempt is due at `now`.
+    fn outbox_due(&self, now: u64) -> Result<Vec<OutboundPost>, anyhow::Error>;
+
+    /// Pushes the next attempt of a due post back to `until`, so that no one
+    /// else picks it up meanwhile. Returns false if it wasn't due anymore.
+    fn outbox_claim(&self, id: &str, now: u64, until: u64) -> Result<bool, anyhow::Error>;
+
+    fn outbox_retry(
+        &self,
+        id: &str,
+        attempts: u32,
+        next_attempt_at: u64,
+    ) -> Result<(), anyhow::Error>;
+
+    fn outbox_delete(&self, id: &str) -> Result<(), anyhow::Error>;
+
+    #[allow(clippy::too_many_arguments)]
     fn module_new(
         &self,
         name: &String,
         src: &String,
         compiled_src: &CompilationResult,
         singleton: Option<u32>,
+        limits: &ModuleLimits,
+        on_error: ErrorPolicy,
+        retry: &RetryPolicy,
     ) -> Result<String, anyhow::Error>;
 
     fn module_find_by_hash(&self, hash_sha256: &String) -> Result<Option<String>, anyhow::Error>;
@@ -63,12 +225,28 @@ pub trait ApeiroPersistence: Sync + Send + Debug + 'static {
 
     fn module_get(&self, module_id: &String) -> Result<ModuleSummary, anyhow::Error>;
 
+    fn module_version_get(
+        &self,
+        module_id: &str,
+        version: u32,
+    ) -> Result<ModuleSummary, anyhow::Error>;
+
+    /// Where each fn of the version's compiled source can be suspended, and
+    /// the `PC_MAP_FORMAT` it was stored in.
+    fn module_version_pc_map(
+        &self,
+        module_id: &str,
+        version: u32,
+    ) -> Result<(Vec<ProgramCounterToSourceLocation>, u32), anyhow::Error>;
+
+    /// Adds a new version of the module, which becomes its latest, and
+    /// returns its number. Earlier versions are kept for the procs on them.
     fn module_edit(
         &self,
         module_id: &String,
         new_src: &String,
-        compiled_src: &String,
-    ) -> Result<(), anyhow::Error>;
+        compiled_src: &CompilationResult,
+    ) -> Result<u32, anyhow::Error>;
 }
 
 pub fn is_proc_id(s: &String) -> bool {
@@ -1,25 +1,46 @@
 use std::fmt::Debug;
 
 use anyhow::{anyhow, Context};
-use apeiro_compiler::CompilationResult;
+use apeiro_compiler::{CompilationResult, ProgramCounterToSourceLocation, PC_MAP_FORMAT};
 use apeiro_internal_api::{
-    EngineStatus, ModuleSummary, ProcDetails, ProcGetResponse, ProcStatusDebug, ProcSummary,
-    StepResult,
+    EngineStatus, ErrorPolicy, MboxMessage, ModuleLimits, ModuleSummary, ProcDetails,
+    ProcGetResponse, ProcStatusDebug, ProcSummary, ResumeMode, RetryPolicy, StepDetails,
+    StepReplay, StepResult, StepSummary,
 };
 use nanoid::nanoid;
 use r2d2::Pool;
-use r2d2_sqlite::{rusqlite::params, SqliteConnectionManager};
+use r2d2_sqlite::{
+    rusqlite::{params, OptionalExtension},
+    SqliteConnectionManager,
+};
 use serde_json;
 
-use crate::{db::ApeiroPersistence, StepResultStatus};
+use crate::{
+    db::{ApeiroPersistence, StepEffects},
+    eventloop::now_as_millis,
+    outbox::OutboundPost,
+    snapshot_store, StepResultStatus,
+};
 
 pub struct Db {
     pub pool: Pool<SqliteConnectionManager>,
+    pub retention: SnapshotRetention,
+}
+
+/// Which steps keep their snapshot. Steps past it keep everything else
+/// (status, frames, funcs, journal), only the snapshot is pruned.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SnapshotRetention {
+    /// how many of a proc's latest steps keep their snapshot, all if `None`
+    pub keep_last: Option<u32>,
 }
 
 impl Debug for Db {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Db").field("pool", &self.pool).finish()
+        f.debug_struct("Db")
+            .field("pool", &self.pool)
+            .field("retention", &self.retention)
+            .finish()
     }
 }
 
@@ -46,10 +67,40 @@ impl ApeiroPersistence for Db {
                 pc_to_map TEXT,
                 hash_sha256 TEXT,
                 singleton_version INTEGER,
+                max_heap_mb INTEGER,
+                max_step_ms INTEGER,
                 created_at DATATIME not null default (datetime('now'))
             );",
             (),
         )?;
+        add_column_if_missing(&conn, "modules", "max_heap_mb", "INTEGER")?;
+        add_column_if_missing(&conn, "modules", "max_step_ms", "INTEGER")?;
+        add_column_if_missing(&conn, "modules", "on_error", "TEXT")?;
+        add_column_if_missing(&conn, "modules", "retry", "TEXT")?;
+
+        // `modules` holds the latest version of each module, every version
+        // including it is kept here for the procs pinned to it
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS module_versions (
+                module_id TEXT,
+                version INTEGER NOT NULL,
+                src TEXT,
+                compiled_src TEXT,
+                source_map TEXT,
+                pc_to_map TEXT,
+                hash_sha256 TEXT,
+                created_at DATATIME not null default (datetime('now')),
+                PRIMARY KEY (module_id, version)
+            );",
+            (),
+        )?;
+        // NULL for versions stored before it, which are in format 1
+        add_column_if_missing(&conn, "module_versions", "pc_map_format", "INTEGER")?;
+        // modules created before versions were kept only have their latest
+        conn.execute(
+            "INSERT INTO module_versions (module_id, version, src, compiled_src, source_map, pc_to_map, hash_sha256) SELECT id, 1, src, compiled_src, source_map, pc_to_map, hash_sha256 FROM modules WHERE id NOT IN (SELECT module_id FROM module_versions)",
+            (),
+        )?;
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS plugins (
@@ -71,6 +122,13 @@ impl ApeiroPersistence for Db {
             );",
             (),
         )?;
+        add_column_if_missing(&conn, "procs", "resume_mode", "TEXT")?;
+        add_column_if_missing(&conn, "procs", "flagged", "TEXT")?;
+        add_column_if_missing(&conn, "procs", "module_version", "INTEGER")?;
+        conn.execute(
+            "UPDATE procs SET module_version = (SELECT MAX(version) FROM module_versions WHERE module_id = procs.module_id) WHERE module_version IS NULL",
+            (),
+        )?;
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS steps (
@@ -82,10 +140,52 @@ impl ApeiroPersistence for Db {
                 snapshot BLOB,
                 frames TEXT,
                 funcs TEXT,
+                clock INTEGER,
+                seed INTEGER,
+                journal TEXT,
                 PRIMARY KEY (proc_id, step_id)
             );",
             (),
         )?;
+        add_column_if_missing(&conn, "steps", "clock", "INTEGER")?;
+        add_column_if_missing(&conn, "steps", "seed", "INTEGER")?;
+        add_column_if_missing(&conn, "steps", "journal", "TEXT")?;
+        add_column_if_missing(&conn, "steps", "snapshot_size", "INTEGER")?;
+        add_column_if_missing(&conn, "steps", "snapshot_format", "INTEGER")?;
+        add_column_if_missing(&conn, "steps", "snapshot_checksum", "TEXT")?;
+        add_column_if_missing(&conn, "steps", "v8_version", "TEXT")?;
+        add_column_if_missing(&conn, "steps", "parent_step_id", "INTEGER")?;
+        add_column_if_missing(&conn, "steps", "msg", "TEXT")?;
+        add_column_if_missing(&conn, "steps", "module_version", "INTEGER")?;
+        add_column_if_missing(&conn, "steps", "retry_of", "INTEGER")?;
+
+        // snapshots are stored as compressed pages, shared by every step
+        // that contains them; `steps.snapshot` is only read for steps stored
+        // before that
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS snapshot_pages (
+                hash TEXT PRIMARY KEY,
+                data BLOB NOT NULL,
+                size INTEGER NOT NULL
+            );",
+            (),
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS step_snapshot_pages (
+                proc_id TEXT,
+                step_id INTEGER NOT NULL,
+                idx INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                PRIMARY KEY (proc_id, step_id, idx)
+            );",
+            (),
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS step_snapshot_pages_hash ON step_snapshot_pages (hash);",
+            (),
+        )?;
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS mbox (
@@ -97,6 +197,35 @@ impl ApeiroPersistence for Db {
             (),
         )?;
 
+        // read messages used to be kept around
+        conn.execute("DELETE FROM mbox WHERE read = 1", ())?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS timers (
+                id TEXT PRIMARY KEY,
+                proc_id TEXT,
+                deadline INTEGER NOT NULL,
+                fired BOOL
+            );",
+            (),
+        )?;
+        add_column_if_missing(&conn, "timers", "claimed_until", "INTEGER")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS outbox (
+                id TEXT PRIMARY KEY,
+                proc_id TEXT,
+                url TEXT NOT NULL,
+                headers TEXT,
+                body TEXT,
+                attempts INTEGER NOT NULL,
+                max_attempts INTEGER NOT NULL,
+                backoff_ms INTEGER NOT NULL,
+                next_attempt_at INTEGER NOT NULL
+            );",
+            (),
+        )?;
+
         conn.execute(
             "CREATE TABLE IF NOT EXISTS proc_subscriptions (
                 id TEXT PRIMARY KEY,
@@ -139,14 +268,27 @@ impl ApeiroPersistence for Db {
         Ok(())
     }
 
-    fn proc_new(&self, module_id: &String, name: &Option<String>) -> Result<String, anyhow::Error> {
+    fn proc_new(
+        &self,
+        module_id: &String,
+        name: &Option<String>,
+        resume_mode: Option<ResumeMode>,
+        module_version: u32,
+    ) -> Result<String, anyhow::Error> {
         let id = nanoid!();
 
         let conn = self.pool.get()?;
 
         conn.execute(
-            "INSERT INTO procs (id, name, module_id, current_step_id) VALUES (?, ?, ?, ?)",
-            params![&id, name, module_id, 0],
+            "INSERT INTO procs (id, name, module_id, current_step_id, resume_mode, module_version) VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                &id,
+                name,
+                module_id,
+                0,
+                resume_mode.map(|mode| mode.as_str()),
+                module_version
+            ],
         )
         .unwrap();
 
@@ -156,26 +298,32 @@ impl ApeiroPersistence for Db {
     fn proc_update(
         &self,
         id: &String,
+        msg: Option<&serde_json::Value>,
         state: &StepResult,
         engine_status: &EngineStatus,
+        effects: &StepEffects,
     ) -> Result<(), anyhow::Error> {
         let frames_json = serde_json::to_string(&engine_status.frames).unwrap();
         let funcs_json = serde_json::to_string(&engine_status.funcs).unwrap();
 
-        let conn = self.pool.get()?;
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
 
-        let step_id = conn
-            .prepare("SELECT current_step_id FROM procs WHERE id = ?")?
-            .query_row(&[id], |row| {
-                let current_step_id: i64 = row.get(0)?;
-                Ok(current_step_id + 1)
+        // after a rewind the current step isn't the latest one, new steps are
+        // numbered after every step so far and point back at their parent
+        let (step_id, parent_step_id) = tx
+            .prepare("SELECT (SELECT COALESCE(MAX(step_id), 0) + 1 FROM steps WHERE proc_id = procs.id), NULLIF(current_step_id, 0) FROM procs WHERE id = ?")?
+            .query_row([id], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?))
             })?;
 
-        conn.execute(
-            "INSERT INTO steps (proc_id, step_id, status, val, suspension, frames, funcs, snapshot) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        tx.execute(
+            "INSERT INTO steps (proc_id, step_id, parent_step_id, msg, status, val, suspension, frames, funcs, snapshot_size, snapshot_format, snapshot_checksum, v8_version, clock, seed, journal, module_version) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, (SELECT module_version FROM procs WHERE id = ?1))",
             params![
                 id,
                 step_id,
+                parent_step_id,
+                msg.map(|msg| msg.to_string()),
                 serde_json::to_string(&state.status).unwrap(),
                 state
                     .val
@@ -187,52 +335,487 @@ impl ApeiroPersistence for Db {
                     .map(|v| serde_json::to_string(&v).unwrap_or("error".to_string())),
                 frames_json,
                 funcs_json,
-                engine_status.snapshot,
+                engine_status.snapshot.as_ref().map(Vec::len),
+                engine_status
+                    .snapshot
+                    .as_ref()
+                    .map(|_| snapshot_store::FORMAT),
+                engine_status
+                    .snapshot
+                    .as_ref()
+                    .map(|snapshot| sha256::digest(&snapshot[..])),
+                engine_status.v8_version,
+                engine_status.clock,
+                engine_status.seed,
+                engine_status
+                    .journal
+                    .as_ref()
+                    .map(|v| serde_json::to_string(&v).unwrap_or("error".to_string())),
             ],
         )?;
 
-        conn.execute(
+        if let Some(snapshot) = &engine_status.snapshot {
+            let mut page_exists = tx.prepare("SELECT 1 FROM snapshot_pages WHERE hash = ?")?;
+            // another step may store the same page in the meantime
+            let mut page_insert = tx.prepare(
+                "INSERT OR IGNORE INTO snapshot_pages (hash, data, size) VALUES (?, ?, ?)",
+            )?;
+            let mut link_insert = tx.prepare(
+                "INSERT INTO step_snapshot_pages (proc_id, step_id, idx, hash) VALUES (?, ?, ?, ?)",
+            )?;
+            for (idx, page) in snapshot_store::pages(snapshot).iter().enumerate() {
+                let exists = page_exists
+                    .query_row([&page.hash], |_| Ok(()))
+                    .optional()?
+                    .is_some();
+                if !exists {
+                    page_insert.execute(params![
+                        page.hash,
+                        snapshot_store::compress(page.data)?,
+                        page.data.len()
+                    ])?;
+                }
+                link_insert.execute(params![id, step_id, idx, page.hash])?;
+            }
+        }
+
+        tx.execute(
             "UPDATE procs SET current_step_id=? WHERE id=?",
             params![step_id, id],
         )?;
 
+        mbox_mark_read(&tx, effects.mbox_read)?;
+        timer_delete(&tx, effects.timers_fired)?;
+        if state.status == StepResultStatus::SUSPEND {
+            let mut timer_insert = tx
+                .prepare("INSERT INTO timers (id, proc_id, deadline, fired) VALUES (?, ?, ?, ?)")?;
+            for (timer_id, deadline) in effects.timers_started {
+                timer_insert.execute(params![timer_id, id, deadline, false])?;
+            }
+            timer_delete(&tx, effects.timers_cancelled)?;
+        } else {
+            tx.execute("DELETE FROM timers WHERE proc_id = ?", params![id])?;
+        }
+
+        for post in effects.posts {
+            outbox_insert(&tx, post, now_as_millis())?;
+        }
+
+        // what this and earlier steps read is kept with those steps
+        tx.execute(
+            "DELETE FROM mbox WHERE proc_id = ? AND read = 1",
+            params![id],
+        )?;
+
+        if let Some(keep_last) = self.retention.keep_last {
+            // the current step always keeps its snapshot, procs resume from it
+            let last_pruned = step_id - i64::from(keep_last.max(1));
+            let pruned = tx.execute(
+                "DELETE FROM step_snapshot_pages WHERE proc_id = ? AND step_id <= ?",
+                params![id, last_pruned],
+            )? + tx.execute(
+                "UPDATE steps SET snapshot = NULL, snapshot_checksum = NULL WHERE proc_id = ? AND step_id <= ? AND (snapshot IS NOT NULL OR snapshot_checksum IS NOT NULL)",
+                params![id, last_pruned],
+            )?;
+            if pruned > 0 {
+                delete_unused_snapshot_pages(&tx)?;
+            }
+        }
+
+        tx.commit()?;
+
         Ok(())
     }
 
+    fn proc_step_failed(
+        &self,
+        id: &str,
+        msg: Option<&serde_json::Value>,
+        state: &StepResult,
+        clock: u64,
+        retry_of: Option<u64>,
+        effects: &StepEffects,
+    ) -> Result<u64, anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        // numbered like any other step, but left off the proc's path: it
+        // continues from its current step as if this one never ran
+        let step_id = failed_step_insert(&tx, id, msg, state, clock, retry_of, effects)?;
+
+        tx.commit()?;
+
+        Ok(step_id)
+    }
+
+    fn proc_fail(
+        &self,
+        id: &str,
+        msg: Option<&serde_json::Value>,
+        state: &StepResult,
+        clock: u64,
+        retry_of: Option<u64>,
+        effects: &StepEffects,
+    ) -> Result<u64, anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        let step_id = failed_step_insert(&tx, id, msg, state, clock, retry_of, effects)?;
+        tx.execute(
+            "UPDATE procs SET current_step_id = ? WHERE id = ?",
+            params![step_id, id],
+        )?;
+        // nothing wakes a proc in error
+        tx.execute("DELETE FROM timers WHERE proc_id = ?", params![id])?;
+        tx.execute(
+            "DELETE FROM mbox WHERE proc_id = ? AND read = 1",
+            params![id],
+        )?;
+
+        tx.commit()?;
+
+        Ok(step_id)
+    }
+
+    fn proc_failed_steps(
+        &self,
+        id: &str,
+        retry_of: Option<u64>,
+    ) -> Result<Vec<StepSummary>, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM steps JOIN procs ON (procs.id = steps.proc_id) WHERE steps.proc_id = ?1 AND steps.parent_step_id IS procs.current_step_id AND steps.status = ?2 AND (?3 IS NULL OR steps.step_id = ?3 OR steps.retry_of = ?3) ORDER BY steps.step_id",
+            step_summary_columns()
+        ))?;
+
+        let failed = stmt
+            .query_map(
+                params![
+                    id,
+                    serde_json::to_string(&StepResultStatus::ERROR)?,
+                    retry_of
+                ],
+                step_summary,
+            )?
+            .collect::<Result<_, _>>()?;
+
+        Ok(failed)
+    }
+
     fn proc_get_details(&self, proc_id_or_name: &String) -> Result<ProcDetails, anyhow::Error> {
         let conn = self.pool.get()?;
 
         let proc = self.proc_get(proc_id_or_name)?;
 
         let mut stmt =
-            conn.prepare("SELECT modules.compiled_src, steps.frames, steps.funcs, steps.snapshot FROM procs JOIN steps ON (steps.step_id = procs.current_step_id AND procs.id = steps.proc_id) JOIN modules ON (modules.id = procs.module_id) WHERE procs.id = ?")
+            conn.prepare("SELECT module_versions.compiled_src, modules.max_heap_mb, modules.max_step_ms, procs.resume_mode, procs.current_step_id, procs.module_version, modules.on_error, modules.retry FROM procs JOIN modules ON (modules.id = procs.module_id) JOIN module_versions ON (module_versions.module_id = procs.module_id AND module_versions.version = procs.module_version) WHERE procs.id = ?")
                 .context("proc_get_details query failed")?;
 
-        let result = stmt.query_row(&[&proc.proc_id.clone()], |row| {
-            let compiled_src: String = row.get(0)?;
-            let frames: String = row.get(1)?;
-            let frames: Option<serde_json::Value> = serde_json::from_str(&frames).unwrap();
-            let funcs: String = row.get(2)?;
-            let funcs: Option<serde_json::Value> = serde_json::from_str(&funcs).unwrap();
-            let snapshot: Option<Vec<u8>> = row.get(3)?;
-            let engine_status = EngineStatus {
-                frames,
-                funcs,
-                snapshot,
-            };
-            Ok(ProcDetails {
-                pid: proc.proc_id,
-                module_id: proc.module_id,
-                name: proc.name,
-                compiled_src,
-                engine_status,
-                state: proc.step_result,
+        let (compiled_src, limits, resume_mode, step_id, module_version, on_error, retry) = stmt
+            .query_row([&proc.proc_id], |row| {
+                let limits = ModuleLimits {
+                    max_heap_mb: row.get(1)?,
+                    max_step_ms: row.get(2)?,
+                };
+                let resume_mode: Option<ResumeMode> = row
+                    .get::<_, Option<String>>(3)?
+                    .and_then(|mode| mode.parse().ok());
+                Ok((
+                    row.get::<_, String>(0)?,
+                    limits,
+                    resume_mode,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, u32>(5)?,
+                    error_policy(row.get(6)?),
+                    retry_policy(row.get(7)?),
+                ))
+            })?;
+
+        // a snapshot that can't be read is left out, the step is resumed
+        // from its frames
+        let mut engine_status = step_engine_status(&conn, &proc.proc_id, step_id)
+            .with_context(|| format!("step {} is unreadable", step_id))?
+            .0;
+
+        // a snapshot holds the code of the version that made it, a proc
+        // migrated since is resumed from its frames under its new version
+        let step_module_version: Option<u32> = conn.query_row(
+            "SELECT module_version FROM steps WHERE proc_id = ? AND step_id = ?",
+            params![proc.proc_id, step_id],
+            |row| row.get(0),
+        )?;
+        if step_module_version.is_some_and(|version| version != module_version) {
+            engine_status.snapshot = None;
+        }
+
+        Ok(ProcDetails {
+            pid: proc.proc_id,
+            module_id: proc.module_id,
+            name: proc.name,
+            compiled_src,
+            engine_status,
+            state: proc.step_result,
+            step_id: step_id as u64,
+            limits,
+            resume_mode,
+            module_version,
+            on_error,
+            retry,
+        })
+    }
+
+    fn proc_frames(
+        &self,
+        id: &str,
+    ) -> Result<(StepResultStatus, Option<serde_json::Value>, u32), anyhow::Error> {
+        let conn = self.pool.get()?;
+        let (status, frames, module_version) = conn.query_row(
+            "SELECT steps.status, steps.frames, procs.module_version FROM procs JOIN steps ON (steps.step_id = procs.current_step_id AND steps.proc_id = procs.id) WHERE procs.id = ?",
+            [id],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u32>(2)?,
+                ))
+            },
+        )?;
+
+        Ok((
+            serde_json::from_str(&status).unwrap_or(StepResultStatus::CRASHED),
+            serde_json::from_str(&frames).context("corrupt frames")?,
+            module_version,
+        ))
+    }
+
+    fn proc_repair(&self, id: &str) -> Result<(), anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        let step_id: i64 = tx.query_row(
+            "SELECT current_step_id FROM procs WHERE id = ?",
+            [id],
+            |row| row.get(0),
+        )?;
+        match step_engine_status(&tx, id, step_id) {
+            Ok((_, None)) => {}
+            Ok((_, Some(problem))) => {
+                proc_flag(
+                    &tx,
+                    id,
+                    &format!(
+                        "snapshot of step {} {:#}, resumed from its frames",
+                        step_id, problem
+                    ),
+                )?;
+            }
+            Err(e) => match previous_good_step(&tx, id, step_id)? {
+                Some((good_step_id, _)) => {
+                    proc_flag(
+                        &tx,
+                        id,
+                        &format!(
+                            "step {} is unreadable ({:#}), resumed from step {}",
+                            step_id, e, good_step_id
+                        ),
+                    )?;
+                    // the proc continues from the good step, as if rewound
+                    // to it, rather than from the one it can't read
+                    tx.execute(
+                        "UPDATE procs SET current_step_id = ? WHERE id = ?",
+                        params![good_step_id, id],
+                    )?;
+                }
+                None => {
+                    let reason = format!(
+                        "step {} is unreadable ({:#}), and so are all before it",
+                        step_id, e
+                    );
+                    proc_flag(&tx, id, &reason)?;
+                    tx.commit()?;
+                    return Err(anyhow!(reason));
+                }
+            },
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn proc_history(&self, id: &str) -> Result<Vec<StepSummary>, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM steps JOIN procs ON (procs.id = steps.proc_id) WHERE steps.proc_id = ? ORDER BY steps.step_id",
+                step_summary_columns()
+            ))
+            .context("proc_history query failed")?;
+
+        let result = stmt
+            .query_map([id], step_summary)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(result)
+    }
+
+    fn proc_step_get(&self, id: &str, step_id: u64) -> Result<StepDetails, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {}, steps.frames, steps.funcs, steps.journal, (steps.snapshot IS NOT NULL OR steps.snapshot_checksum IS NOT NULL) FROM steps JOIN procs ON (procs.id = steps.proc_id) WHERE steps.proc_id = ? AND steps.step_id = ?",
+                step_summary_columns()
+            ))
+            .context("proc_step_get query failed")?;
+
+        let result = stmt
+            .query_row(params![id, step_id as i64], |row| {
+                Ok(StepDetails {
+                    summary: step_summary(row)?,
+                    frames: json_column(row, 8)?,
+                    funcs: json_column(row, 9)?,
+                    journal: json_column(row, 10)?,
+                    has_snapshot: row.get(11)?,
+                })
             })
-        })?;
+            .optional()?
+            .ok_or(anyhow!("proc {} has no step {}", id, step_id))?;
 
         Ok(result)
     }
 
+    fn proc_step_replay(&self, id: &str, step_id: u64) -> Result<StepReplay, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {}, steps.msg, steps.clock, steps.seed, module_versions.compiled_src, modules.max_heap_mb, modules.max_step_ms, procs.resume_mode, steps.journal FROM steps JOIN procs ON (procs.id = steps.proc_id) JOIN modules ON (modules.id = procs.module_id) JOIN module_versions ON (module_versions.module_id = procs.module_id AND module_versions.version = COALESCE(steps.module_version, procs.module_version)) WHERE steps.proc_id = ? AND steps.step_id = ?",
+                PARENT_STEP_ID
+            ))
+            .context("proc_step_replay query failed")?;
+
+        let (parent_step_id, mut replay) = stmt
+            .query_row(params![id, step_id as i64], |row| {
+                let replay = StepReplay {
+                    compiled_src: row.get(4)?,
+                    resumed_from: EngineStatus::default(),
+                    limits: ModuleLimits {
+                        max_heap_mb: row.get(5)?,
+                        max_step_ms: row.get(6)?,
+                    },
+                    resume_mode: row
+                        .get::<_, Option<String>>(7)?
+                        .and_then(|mode| mode.parse().ok()),
+                    msg: json_column(row, 1)?,
+                    clock: row.get(2)?,
+                    seed: row.get(3)?,
+                    journal: json_column(row, 8)?,
+                };
+                Ok((row.get::<_, Option<i64>>(0)?, replay))
+            })
+            .optional()?
+            .ok_or(anyhow!("proc {} has no step {}", id, step_id))?;
+
+        if let Some(parent_step_id) = parent_step_id {
+            // a snapshot that can't be read is left out, the step is then
+            // replayed from the frames it continued from
+            (replay.resumed_from, _) = step_engine_status(&conn, &id.to_string(), parent_step_id)?;
+        }
+
+        Ok(replay)
+    }
+
+    fn proc_rewind(&self, id: &str, step_id: u64) -> Result<(), anyhow::Error> {
+        let conn = self.pool.get()?;
+        let count = conn.execute(
+            // and back onto the module version the step ran
+            "UPDATE procs SET current_step_id = ?2, module_version = COALESCE((SELECT module_version FROM steps WHERE proc_id = ?1 AND step_id = ?2), module_version) WHERE id = ?1 AND EXISTS (SELECT 1 FROM steps WHERE proc_id = ?1 AND step_id = ?2)",
+            params![id, step_id as i64],
+        )?;
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(anyhow!("proc {} has no step {}", id, step_id))
+        }
+    }
+
+    fn proc_fork(
+        &self,
+        id: &str,
+        step_id: Option<u64>,
+        name: &str,
+    ) -> Result<String, anyhow::Error> {
+        let fork_id = nanoid!();
+
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        let step_id = match step_id {
+            Some(step_id) => step_id as i64,
+            None => tx.query_row(
+                "SELECT current_step_id FROM procs WHERE id = ?",
+                [id],
+                |row| row.get(0),
+            )?,
+        };
+
+        tx.execute(
+            "INSERT INTO procs (id, name, module_id, current_step_id, resume_mode, module_version) SELECT ?1, ?2, module_id, 1, resume_mode, COALESCE((SELECT module_version FROM steps WHERE proc_id = ?3 AND step_id = ?4), module_version) FROM procs WHERE id = ?3",
+            params![fork_id, name, id, step_id],
+        )?;
+
+        // the fork's first step is the copied one, sharing its snapshot pages
+        let count = tx.execute(
+            "INSERT INTO steps (proc_id, step_id, msg, status, val, suspension, frames, funcs, snapshot, snapshot_size, snapshot_format, snapshot_checksum, v8_version, clock, seed, journal, module_version) SELECT ?, 1, msg, status, val, suspension, frames, funcs, snapshot, snapshot_size, snapshot_format, snapshot_checksum, v8_version, clock, seed, journal, module_version FROM steps WHERE proc_id = ? AND step_id = ?",
+            params![fork_id, id, step_id],
+        )?;
+        if count != 1 {
+            return Err(anyhow!("proc {} has no step {}", id, step_id));
+        }
+        tx.execute(
+            "INSERT INTO step_snapshot_pages (proc_id, step_id, idx, hash) SELECT ?, 1, idx, hash FROM step_snapshot_pages WHERE proc_id = ? AND step_id = ?",
+            params![fork_id, id, step_id],
+        )?;
+
+        tx.commit()?;
+
+        Ok(fork_id)
+    }
+
+    fn proc_migrate(
+        &self,
+        id: &str,
+        step_id: u64,
+        module_version: u32,
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.pool.get()?;
+        let count = conn.execute(
+            "UPDATE procs SET module_version = ?2 WHERE id = ?1 AND current_step_id = ?3 AND EXISTS (SELECT 1 FROM module_versions WHERE module_id = procs.module_id AND version = ?2)",
+            params![id, module_version, step_id],
+        )?;
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "proc {} has moved on from step {}, or has no module version {}",
+                id,
+                step_id,
+                module_version
+            ))
+        }
+    }
+
+    fn proc_unflag(&self, id: &str) -> Result<(), anyhow::Error> {
+        let conn = self.pool.get()?;
+        let count = conn.execute("UPDATE procs SET flagged = NULL WHERE id = ?", [id])?;
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(anyhow!("no proc {}", id))
+        }
+    }
+
     fn proc_get(&self, proc_id_or_name: &String) -> Result<ProcGetResponse, anyhow::Error> {
         let conn = self.pool.get()?;
 
@@ -288,6 +871,18 @@ impl ApeiroPersistence for Db {
 
         let count = stmt.execute(params![id])?;
 
+        conn.execute("DELETE FROM mbox WHERE proc_id = ?", params![id])?;
+        conn.execute("DELETE FROM timers WHERE proc_id = ?", params![id])?;
+        conn.execute(
+            "DELETE FROM proc_subscriptions WHERE proc_id = ?",
+            params![id],
+        )?;
+        conn.execute(
+            "DELETE FROM step_snapshot_pages WHERE proc_id = ?",
+            params![id],
+        )?;
+        delete_unused_snapshot_pages(&conn)?;
+
         if count == 1 {
             Ok(())
         } else {
@@ -295,54 +890,36 @@ impl ApeiroPersistence for Db {
         }
     }
 
-    fn proc_inspect(&self, id: &String) -> Result<ProcStatusDebug, anyhow::Error> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn
-            .prepare("SELECT steps.frames, steps.funcs FROM procs JOIN steps ON (procs.id = steps.proc_id AND procs.current_step_id = steps.step_id) WHERE procs.id = ?")
-            .context("proc_inspect query failed")?;
+    fn mbox_push(
+        &self,
+        proc_id: &String,
+        msg: &serde_json::Value,
+    ) -> Result<String, anyhow::Error> {
+        let id = nanoid!();
 
-        let result = stmt.query_row(&[id], |row| {
-            let frames: String = row.get(0)?;
-            let frames = serde_json::from_str(&frames.as_str()).unwrap();
-            let funcs: String = row.get(1)?;
-            let funcs = serde_json::from_str(&funcs.as_str()).unwrap();
+        let conn = self.pool.get()?;
 
-            Ok(ProcStatusDebug { funcs, frames })
-        })?;
+        conn.execute(
+            "INSERT INTO mbox (id, proc_id, msg, read) VALUES (?, ?, ?, ?)",
+            params![&id, proc_id, serde_json::to_string(msg)?, false],
+        )?;
 
-        Ok(result)
+        Ok(id)
     }
 
-    fn proc_list(&self) -> Result<Vec<ProcSummary>, anyhow::Error> {
+    fn mbox_list(&self, proc_id: &String) -> Result<Vec<MboxMessage>, anyhow::Error> {
         let conn = self.pool.get()?;
-        let mut stmt = conn.prepare(
-            "SELECT procs.id, steps.status, steps.suspension, procs.name, length(steps.snapshot), length(steps.funcs) + length(frames) FROM procs JOIN steps ON (procs.id = steps.proc_id AND procs.current_step_id = steps.step_id)",
-        ).context("proc_list query failed")?;
+        let mut stmt = conn
+            .prepare("SELECT id, msg FROM mbox WHERE proc_id = ? AND read = 0 ORDER BY rowid")
+            .context("mbox_list query failed")?;
 
         let result = stmt
-            .query_map((), |row| {
-                let id: String = row.get(0).unwrap();
-                let status: String = row.get(1).unwrap_or("\"CRASHED\"".to_string());
-                let status: StepResultStatus =
-                    serde_json::from_str(&status).expect("invalid status");
-                let suspension: Result<String, _> = row.get(2);
-                let suspension = if let Ok(suspension) = suspension {
-                    serde_json::from_str(&suspension).unwrap_or(None)
-                } else {
-                    None
-                };
-                let name: Option<String> = row.get(3).unwrap_or(None);
-                let snapshot_size: u32 = row.get(4).unwrap_or(0);
-                let snapshot_v2_size: u32 = row.get(5).unwrap_or(0);
+            .query_map(params![proc_id], |row| {
+                let id: String = row.get(0)?;
+                let msg: String = row.get(1)?;
+                let msg = serde_json::from_str(msg.as_str()).unwrap();
 
-                Ok(ProcSummary {
-                    id,
-                    name,
-                    status,
-                    suspension,
-                    snapshot_size,
-                    snapshot_v2_size,
-                })
+                Ok(MboxMessage { id, msg })
             })?
             .map(Result::unwrap)
             .collect();
@@ -350,26 +927,206 @@ impl ApeiroPersistence for Db {
         Ok(result)
     }
 
-    fn module_new(
-        &self,
-        name: &String,
+    fn mbox_purge(&self, proc_id: &String) -> Result<usize, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let count = conn.execute(
+            "DELETE FROM mbox WHERE proc_id = ? AND read = 0",
+            params![proc_id],
+        )?;
+
+        Ok(count)
+    }
+
+    fn timers_due(&self, now: u64) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT proc_id, id FROM timers WHERE deadline <= ?1 AND (fired = 0 OR claimed_until <= ?1) ORDER BY deadline",
+            )
+            .context("timers_due query failed")?;
+
+        let result = stmt
+            .query_map(params![now], |row| {
+                let proc_id: String = row.get(0)?;
+                let id: String = row.get(1)?;
+
+                Ok((proc_id, id))
+            })?
+            .map(Result::unwrap)
+            .collect();
+
+        Ok(result)
+    }
+
+    fn timer_claim(&self, id: &str, now: u64, until: u64) -> Result<bool, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let count = conn.execute(
+            "UPDATE timers SET fired = 1, claimed_until = ?3 WHERE id = ?1 AND deadline <= ?2 AND (fired = 0 OR claimed_until <= ?2)",
+            params![id, now, until],
+        )?;
+
+        Ok(count == 1)
+    }
+
+    fn timers_rearm(&self) -> Result<(), anyhow::Error> {
+        let conn = self.pool.get()?;
+        conn.execute("UPDATE timers SET fired = 0 WHERE fired = 1", ())?;
+
+        Ok(())
+    }
+
+    fn outbox_push(&self, post: &OutboundPost, not_before: u64) -> Result<(), anyhow::Error> {
+        let conn = self.pool.get()?;
+        outbox_insert(&conn, post, not_before)
+    }
+
+    fn outbox_due(&self, now: u64) -> Result<Vec<OutboundPost>, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, proc_id, url, headers, body, attempts, max_attempts, backoff_ms FROM outbox WHERE next_attempt_at <= ? ORDER BY next_attempt_at",
+            )
+            .context("outbox_due query failed")?;
+
+        let result = stmt
+            .query_map(params![now], |row| {
+                let headers: String = row.get(3)?;
+                let body: String = row.get(4)?;
+                Ok(OutboundPost {
+                    id: row.get(0)?,
+                    proc_id: row.get(1)?,
+                    url: row.get(2)?,
+                    headers: serde_json::from_str(&headers).unwrap_or_default(),
+                    body: serde_json::from_str(&body).unwrap_or_default(),
+                    attempts: row.get(5)?,
+                    max_attempts: row.get(6)?,
+                    backoff_ms: row.get(7)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(result)
+    }
+
+    fn outbox_claim(&self, id: &str, now: u64, until: u64) -> Result<bool, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let count = conn.execute(
+            "UPDATE outbox SET next_attempt_at = ? WHERE id = ? AND next_attempt_at <= ?",
+            params![until, id, now],
+        )?;
+
+        Ok(count == 1)
+    }
+
+    fn outbox_retry(
+        &self,
+        id: &str,
+        attempts: u32,
+        next_attempt_at: u64,
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE outbox SET attempts = ?, next_attempt_at = ? WHERE id = ?",
+            params![attempts, next_attempt_at, id],
+        )?;
+
+        Ok(())
+    }
+
+    fn outbox_delete(&self, id: &str) -> Result<(), anyhow::Error> {
+        let conn = self.pool.get()?;
+        conn.execute("DELETE FROM outbox WHERE id = ?", params![id])?;
+
+        Ok(())
+    }
+
+    fn proc_inspect(&self, id: &String) -> Result<ProcStatusDebug, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn
+            .prepare("SELECT steps.frames, steps.funcs FROM procs JOIN steps ON (procs.id = steps.proc_id AND procs.current_step_id = steps.step_id) WHERE procs.id = ?")
+            .context("proc_inspect query failed")?;
+
+        let result = stmt.query_row(&[id], |row| {
+            let frames: String = row.get(0)?;
+            let frames = serde_json::from_str(&frames.as_str()).unwrap();
+            let funcs: String = row.get(1)?;
+            let funcs = serde_json::from_str(&funcs.as_str()).unwrap();
+
+            Ok(ProcStatusDebug {
+                funcs,
+                frames,
+                rejected_msgs: vec![],
+            })
+        })?;
+
+        Ok(result)
+    }
+
+    fn proc_list(&self) -> Result<Vec<ProcSummary>, anyhow::Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT procs.id, steps.status, steps.suspension, procs.name, COALESCE(steps.snapshot_size, length(steps.snapshot)), length(steps.funcs) + length(frames), procs.flagged, procs.module_version FROM procs JOIN steps ON (procs.id = steps.proc_id AND procs.current_step_id = steps.step_id)",
+        ).context("proc_list query failed")?;
+
+        let result = stmt
+            .query_map((), |row| {
+                let id: String = row.get(0).unwrap();
+                let status: String = row.get(1).unwrap_or("\"CRASHED\"".to_string());
+                let status: StepResultStatus =
+                    serde_json::from_str(&status).expect("invalid status");
+                let suspension: Result<String, _> = row.get(2);
+                let suspension = if let Ok(suspension) = suspension {
+                    serde_json::from_str(&suspension).unwrap_or(None)
+                } else {
+                    None
+                };
+                let name: Option<String> = row.get(3).unwrap_or(None);
+                let snapshot_size: u32 = row.get(4).unwrap_or(0);
+                let snapshot_v2_size: u32 = row.get(5).unwrap_or(0);
+                let flagged: Option<String> = row.get(6).unwrap_or(None);
+                let module_version: u32 = row.get(7).unwrap_or(0);
+
+                Ok(ProcSummary {
+                    id,
+                    name,
+                    status,
+                    suspension,
+                    snapshot_size,
+                    snapshot_v2_size,
+                    module_version,
+                    flagged,
+                })
+            })?
+            .map(Result::unwrap)
+            .collect();
+
+        Ok(result)
+    }
+
+    fn module_new(
+        &self,
+        name: &String,
         src: &String,
         compiled_src: &CompilationResult,
         singleton: Option<u32>,
+        limits: &ModuleLimits,
+        on_error: ErrorPolicy,
+        retry: &RetryPolicy,
     ) -> Result<String, anyhow::Error> {
         let id = nanoid!();
 
-        let conn = self.pool.get()?;
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
         use sha256::digest;
         let hash_sha256 = digest(src.clone());
         let source_map = serde_json::to_string(&compiled_src.source_map)?;
         let pc_to_map = serde_json::to_string(&compiled_src.program_counter_mapping)?;
 
-        conn.execute(
+        tx.execute(
             r#"INSERT INTO modules
-            (id, name, src, compiled_src, source_map, pc_to_map, hash_sha256, singleton_version)
+            (id, name, src, compiled_src, source_map, pc_to_map, hash_sha256, singleton_version, max_heap_mb, max_step_ms, on_error, retry)
             VALUES
-            (?, ?, ?, ?, ?, ?, ?, ?)"#,
+            (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
             params![
                 &id,
                 name,
@@ -378,7 +1135,11 @@ impl ApeiroPersistence for Db {
                 &source_map,
                 &pc_to_map,
                 &hash_sha256,
-                &singleton
+                &singleton,
+                limits.max_heap_mb,
+                limits.max_step_ms,
+                on_error.as_str(),
+                serde_json::to_string(retry)?,
             ],
         )
         .map_err(|e| match e {
@@ -392,6 +1153,21 @@ impl ApeiroPersistence for Db {
             _ => anyhow!("module_new failed: {}", e),
         })?;
 
+        tx.execute(
+            "INSERT INTO module_versions (module_id, version, src, compiled_src, source_map, pc_to_map, pc_map_format, hash_sha256) VALUES (?, 1, ?, ?, ?, ?, ?, ?)",
+            params![
+                &id,
+                src,
+                compiled_src.compiled_src,
+                &source_map,
+                &pc_to_map,
+                PC_MAP_FORMAT,
+                &hash_sha256,
+            ],
+        )?;
+
+        tx.commit()?;
+
         Ok(id)
     }
 
@@ -412,7 +1188,7 @@ impl ApeiroPersistence for Db {
     fn module_list(&self) -> Result<Vec<ModuleSummary>, anyhow::Error> {
         let conn = self.pool.get()?;
         let mut stmt =
-            conn.prepare("SELECT id, src, compiled_src, name, singleton_version FROM modules")?;
+            conn.prepare("SELECT id, src, compiled_src, name, singleton_version, max_heap_mb, max_step_ms, (SELECT MAX(version) FROM module_versions WHERE module_id = modules.id), on_error, retry FROM modules")?;
 
         let result = stmt
             .query_map((), |row| {
@@ -427,8 +1203,15 @@ impl ApeiroPersistence for Db {
                     src,
                     compiled_src,
                     name,
+                    version: row.get::<_, Option<u32>>(7)?.unwrap_or(1),
                     procs: vec![],
                     singleton,
+                    limits: ModuleLimits {
+                        max_heap_mb: row.get(5)?,
+                        max_step_ms: row.get(6)?,
+                    },
+                    on_error: error_policy(row.get(8)?),
+                    retry: retry_policy(row.get(9)?),
                 })
             })?
             .map(Result::unwrap)
@@ -441,49 +1224,95 @@ impl ApeiroPersistence for Db {
         &self,
         module_id: &String,
         new_src: &String,
-        compiled_src: &String,
-    ) -> Result<(), anyhow::Error> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare("UPDATE modules SET src = ?, compiled_src = ? WHERE id = ?")?;
+        compiled_src: &CompilationResult,
+    ) -> Result<u32, anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let hash_sha256 = sha256::digest(new_src.clone());
+        let source_map = serde_json::to_string(&compiled_src.source_map)?;
+        let pc_to_map = serde_json::to_string(&compiled_src.program_counter_mapping)?;
 
-        stmt.execute(params![new_src, compiled_src, module_id])?;
+        let version: u32 = tx.query_row(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM module_versions WHERE module_id = ?",
+            [module_id],
+            |row| row.get(0),
+        )?;
 
-        Ok(())
+        tx.execute(
+            "INSERT INTO module_versions (module_id, version, src, compiled_src, source_map, pc_to_map, pc_map_format, hash_sha256) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                module_id,
+                version,
+                new_src,
+                compiled_src.compiled_src,
+                &source_map,
+                &pc_to_map,
+                PC_MAP_FORMAT,
+                &hash_sha256,
+            ],
+        )?;
+
+        let count = tx.execute(
+            "UPDATE modules SET src = ?, compiled_src = ?, source_map = ?, pc_to_map = ?, hash_sha256 = ? WHERE id = ?",
+            params![
+                new_src,
+                compiled_src.compiled_src,
+                &source_map,
+                &pc_to_map,
+                &hash_sha256,
+                module_id
+            ],
+        )?;
+        if count != 1 {
+            return Err(anyhow!("no module {}", module_id));
+        }
+
+        tx.commit()?;
+
+        Ok(version)
     }
 
     fn module_get(&self, module_id: &String) -> Result<ModuleSummary, anyhow::Error> {
         let conn = self.pool.get()?;
-        let mut stmt = conn.prepare(
-            "SELECT id, src, compiled_src, name, singleton_version FROM modules WHERE id = ?",
+        let version: Option<u32> = conn.query_row(
+            "SELECT MAX(version) FROM module_versions WHERE module_id = ?",
+            [module_id],
+            |row| row.get(0),
         )?;
 
-        let (id, src, compiled_src, name, singleton) = stmt.query_row(&[module_id], |row| {
-            let id: String = row.get(0)?;
-            let src: String = row.get(1)?;
-            let compiled_src: String = row.get(2)?;
-            let name: String = row.get(3)?;
-            let singleton: Option<u32> = row.get(4)?;
+        let version = version.ok_or(anyhow!("no module {}", module_id))?;
+        module_summary(&conn, module_id, version)
+    }
 
-            Ok((id, src, compiled_src, name, singleton))
-        })?;
+    fn module_version_get(
+        &self,
+        module_id: &str,
+        version: u32,
+    ) -> Result<ModuleSummary, anyhow::Error> {
+        let conn = self.pool.get()?;
+        module_summary(&conn, module_id, version)
+    }
 
-        let mut stmt = conn.prepare("SELECT id FROM procs WHERE module_id = ?")?;
-        let procs = stmt.query_map(&[module_id], |row| row.get(0))?;
-        let mut proc_vec = Vec::new();
-        for proc in procs {
-            proc_vec.push(proc?);
-        }
+    fn module_version_pc_map(
+        &self,
+        module_id: &str,
+        version: u32,
+    ) -> Result<(Vec<ProgramCounterToSourceLocation>, u32), anyhow::Error> {
+        let conn = self.pool.get()?;
+        let (pc_to_map, format): (Option<String>, Option<u32>) = conn
+            .query_row(
+                "SELECT pc_to_map, pc_map_format FROM module_versions WHERE module_id = ? AND version = ?",
+                params![module_id, version],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .ok_or(anyhow!("module {} has no version {}", module_id, version))?;
 
-        let result = ModuleSummary {
-            id,
-            src,
-            compiled_src,
-            name,
-            singleton,
-            procs: proc_vec,
+        let pc_to_map = match pc_to_map {
+            Some(pc_to_map) => serde_json::from_str(&pc_to_map)?,
+            None => vec![],
         };
-
-        Ok(result)
+        Ok((pc_to_map, format.unwrap_or(1)))
     }
 
     fn proc_rename_if_exists(
@@ -517,26 +1346,1002 @@ impl ApeiroPersistence for Db {
         Ok(result)
     }
 
-    fn proc_subscription_new(
+    fn proc_subscription_set(
         &self,
         proc_id: &String,
-        subscription: &serde_json::Value,
-    ) -> Result<String, anyhow::Error> {
-        let id = nanoid!();
+        subscription: Option<&serde_json::Value>,
+    ) -> Result<(), anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
 
-        let conn = self.pool.get()?;
+        tx.execute(
+            "DELETE FROM proc_subscriptions WHERE proc_id = ?",
+            params![proc_id],
+        )?;
+        if let Some(subscription) = subscription {
+            tx.execute(
+                "INSERT INTO proc_subscriptions (id, proc_id, subscription) VALUES (?, ?, ?)",
+                params![nanoid!(), proc_id, serde_json::to_string(subscription)?],
+            )?;
+        }
 
-        let subscription = serde_json::to_string(subscription)?;
+        tx.commit()?;
 
-        conn.execute(
-            "INSERT INTO proc_subscriptions (id, proc_id, subscription) VALUES (?, ?, ?)",
-            params![&id, proc_id, subscription],
+        Ok(())
+    }
+}
+
+/// The module as of `version`, with every proc of the module.
+fn module_summary(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    module_id: &str,
+    version: u32,
+) -> Result<ModuleSummary, anyhow::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT modules.id, module_versions.src, module_versions.compiled_src, modules.name, modules.singleton_version, modules.max_heap_mb, modules.max_step_ms, modules.on_error, modules.retry FROM modules JOIN module_versions ON (module_versions.module_id = modules.id) WHERE modules.id = ? AND module_versions.version = ?",
+    )?;
+
+    let (id, src, compiled_src, name, singleton, limits, on_error, retry) = stmt
+        .query_row(params![module_id, version], |row| {
+            let id: String = row.get(0)?;
+            let src: String = row.get(1)?;
+            let compiled_src: String = row.get(2)?;
+            let name: String = row.get(3)?;
+            let singleton: Option<u32> = row.get(4)?;
+            let limits = ModuleLimits {
+                max_heap_mb: row.get(5)?,
+                max_step_ms: row.get(6)?,
+            };
+
+            Ok((
+                id,
+                src,
+                compiled_src,
+                name,
+                singleton,
+                limits,
+                error_policy(row.get(7)?),
+                retry_policy(row.get(8)?),
+            ))
+        })
+        .optional()?
+        .ok_or(anyhow!("module {} has no version {}", module_id, version))?;
+
+    let mut stmt = conn.prepare("SELECT id FROM procs WHERE module_id = ?")?;
+    let procs = stmt.query_map([module_id], |row| row.get(0))?;
+    let mut proc_vec = Vec::new();
+    for proc in procs {
+        proc_vec.push(proc?);
+    }
+
+    Ok(ModuleSummary {
+        id,
+        src,
+        compiled_src,
+        name,
+        version,
+        singleton,
+        procs: proc_vec,
+        limits,
+        on_error,
+        retry,
+    })
+}
+
+/// What a proc resumes from after `step_id`. Fails if the step's frames or
+/// funcs can't be read; a snapshot that can't be is left out and returned as
+/// the problem with it, the step can still be resumed from its frames.
+fn step_engine_status(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    proc_id: &str,
+    step_id: i64,
+) -> Result<(EngineStatus, Option<anyhow::Error>), anyhow::Error> {
+    let mut stmt = conn.prepare("SELECT frames, funcs, v8_version, clock, seed, journal, snapshot_format, snapshot_size, snapshot_checksum FROM steps WHERE proc_id = ? AND step_id = ?")?;
+    let (frames, funcs, mut engine_status, format, size, checksum) =
+        stmt.query_row(params![proc_id, step_id], |row| {
+            let engine_status = EngineStatus {
+                v8_version: row.get(2)?,
+                clock: row.get(3)?,
+                seed: row.get(4)?,
+                journal: row
+                    .get::<_, Option<String>>(5)?
+                    .and_then(|journal| serde_json::from_str(&journal).ok()),
+                ..Default::default()
+            };
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                engine_status,
+                row.get::<_, Option<u32>>(6)?,
+                row.get::<_, Option<usize>>(7)?,
+                row.get::<_, Option<String>>(8)?,
+            ))
+        })?;
+    engine_status.frames = serde_json::from_str(&frames).context("corrupt frames")?;
+    engine_status.funcs = serde_json::from_str(&funcs).context("corrupt funcs")?;
+
+    // a snapshot stored whole in `steps.snapshot`, from before snapshots had
+    // checksums, can't be checked and is left out: the step is resumed from
+    // its frames, like one whose snapshot was made by another V8
+    let mut problem = None;
+    engine_status.snapshot = match checksum {
+        None => None,
+        Some(checksum) => {
+            let snapshot = snapshot_get(conn, proc_id, step_id).and_then(|snapshot| {
+                let snapshot = snapshot.ok_or(anyhow!("is missing its pages"))?;
+                snapshot_store::verify(&snapshot, format, size, &checksum)?;
+                Ok(snapshot)
+            });
+            match snapshot {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    problem = Some(e);
+                    None
+                }
+            }
+        }
+    };
+
+    Ok((engine_status, problem))
+}
+
+/// The latest step leading up to `step_id` that the proc can be resumed
+/// from.
+fn previous_good_step(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    proc_id: &str,
+    step_id: i64,
+) -> Result<Option<(i64, EngineStatus)>, anyhow::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM steps WHERE proc_id = ? AND step_id = ?",
+        PARENT_STEP_ID
+    ))?;
+    let mut step_id = step_id;
+    while let Some(parent_step_id) = stmt
+        .query_row(params![proc_id, step_id], |row| {
+            row.get::<_, Option<i64>>(0)
+        })
+        .optional()?
+        .flatten()
+    {
+        if let Ok((engine_status, _)) = step_engine_status(conn, proc_id, parent_step_id) {
+            return Ok(Some((parent_step_id, engine_status)));
+        }
+        step_id = parent_step_id;
+    }
+    Ok(None)
+}
+
+/// Steps stored before they recorded their parent continued from the one
+/// before them.
+const PARENT_STEP_ID: &str = "COALESCE(steps.parent_step_id, NULLIF(steps.step_id - 1, 0))";
+
+/// What `step_summary` reads, selected from `steps` joined with `procs`.
+fn step_summary_columns() -> String {
+    format!(
+        "steps.step_id, {}, steps.msg, steps.status, steps.val, steps.suspension, steps.clock, steps.step_id = procs.current_step_id",
+        PARENT_STEP_ID
+    )
+}
+
+/// A JSON column, `None` if it's null or not valid JSON.
+fn json_column(
+    row: &r2d2_sqlite::rusqlite::Row,
+    idx: usize,
+) -> Result<Option<serde_json::Value>, r2d2_sqlite::rusqlite::Error> {
+    Ok(row
+        .get::<_, Option<String>>(idx)?
+        .and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+fn step_summary(
+    row: &r2d2_sqlite::rusqlite::Row,
+) -> Result<StepSummary, r2d2_sqlite::rusqlite::Error> {
+    let status: String = row.get(3)?;
+    Ok(StepSummary {
+        step_id: row.get(0)?,
+        parent_step_id: row.get(1)?,
+        msg: json_column(row, 2)?,
+        step_result: StepResult {
+            status: serde_json::from_str(&status).unwrap_or(StepResultStatus::CRASHED),
+            val: json_column(row, 4)?,
+            suspension: json_column(row, 5)?,
+        },
+        clock: row.get(6)?,
+        current: row.get(7)?,
+    })
+}
+
+/// Marks a proc as needing an operator's attention, shown by `proc_list`.
+fn proc_flag(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    proc_id: &str,
+    reason: &str,
+) -> Result<(), anyhow::Error> {
+    conn.execute(
+        "UPDATE procs SET flagged = ? WHERE id = ?",
+        params![reason, proc_id],
+    )?;
+    Ok(())
+}
+
+/// Stores a failed step of the proc, continuing from its current one, with
+/// the frames it failed from: those of the step it continued. Returns its
+/// step id.
+fn failed_step_insert(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    proc_id: &str,
+    msg: Option<&serde_json::Value>,
+    state: &StepResult,
+    clock: u64,
+    retry_of: Option<u64>,
+    effects: &StepEffects,
+) -> Result<u64, anyhow::Error> {
+    let (step_id, parent_step_id) = conn
+        .prepare("SELECT (SELECT COALESCE(MAX(step_id), 0) + 1 FROM steps WHERE proc_id = procs.id), NULLIF(current_step_id, 0) FROM procs WHERE id = ?")?
+        .query_row([proc_id], |row| {
+            Ok((row.get::<_, u64>(0)?, row.get::<_, Option<i64>>(1)?))
+        })?;
+
+    conn.execute(
+        "INSERT INTO steps (proc_id, step_id, parent_step_id, msg, status, val, frames, funcs, clock, retry_of, module_version) SELECT ?1, ?2, ?3, ?4, ?5, ?6, COALESCE(parent.frames, 'null'), COALESCE(parent.funcs, 'null'), ?7, ?8, (SELECT module_version FROM procs WHERE id = ?1) FROM (SELECT 1) LEFT JOIN steps AS parent ON (parent.proc_id = ?1 AND parent.step_id = ?3)",
+        params![
+            proc_id,
+            step_id,
+            parent_step_id,
+            msg.map(|msg| msg.to_string()),
+            serde_json::to_string(&state.status)?,
+            state.val.as_ref().map(|v| v.to_string()),
+            clock,
+            retry_of,
+        ],
+    )?;
+    // left in the mailbox, the message would fail the next step too
+    mbox_mark_read(conn, effects.mbox_read)?;
+    timer_delete(conn, effects.timers_fired)?;
+
+    Ok(step_id)
+}
+
+fn mbox_mark_read(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    ids: &[String],
+) -> Result<(), anyhow::Error> {
+    let mut stmt = conn.prepare("UPDATE mbox SET read = 1 WHERE id = ?")?;
+    for id in ids {
+        stmt.execute(params![id])?;
+    }
+    Ok(())
+}
+
+fn timer_delete(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    ids: &[String],
+) -> Result<(), anyhow::Error> {
+    let mut stmt = conn.prepare("DELETE FROM timers WHERE id = ?")?;
+    for id in ids {
+        stmt.execute(params![id])?;
+    }
+    Ok(())
+}
+
+fn outbox_insert(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    post: &OutboundPost,
+    not_before: u64,
+) -> Result<(), anyhow::Error> {
+    conn.execute(
+            "INSERT OR IGNORE INTO outbox (id, proc_id, url, headers, body, attempts, max_attempts, backoff_ms, next_attempt_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                post.id,
+                post.proc_id,
+                post.url,
+                serde_json::to_string(&post.headers)?,
+                serde_json::to_string(&post.body)?,
+                post.attempts,
+                post.max_attempts,
+                post.backoff_ms,
+                not_before,
+            ],
         )?;
 
-        Ok(id)
+    Ok(())
+}
+
+/// Reassembles the snapshot of a step from its pages, `None` if it has none.
+fn snapshot_get(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    proc_id: &str,
+    step_id: i64,
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT snapshot_pages.data FROM step_snapshot_pages JOIN snapshot_pages ON (snapshot_pages.hash = step_snapshot_pages.hash) WHERE step_snapshot_pages.proc_id = ? AND step_snapshot_pages.step_id = ? ORDER BY step_snapshot_pages.idx",
+    )?;
+    let mut rows = stmt.query(params![proc_id, step_id])?;
+
+    let mut snapshot: Option<Vec<u8>> = None;
+    while let Some(row) = rows.next()? {
+        let page = snapshot_store::decompress(&row.get::<_, Vec<u8>>(0)?)
+            .context("corrupt snapshot page")?;
+        snapshot.get_or_insert_with(Vec::new).extend(page);
     }
+    Ok(snapshot)
+}
+
+/// Pages no step links to anymore, after steps were pruned or deleted.
+fn delete_unused_snapshot_pages(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+) -> Result<(), anyhow::Error> {
+    conn.execute(
+        "DELETE FROM snapshot_pages WHERE hash NOT IN (SELECT hash FROM step_snapshot_pages)",
+        (),
+    )?;
+    Ok(())
 }
 
 fn is_proc_id(s: &String) -> bool {
     s.len() == 21
 }
+
+/// The policy stored for a module, modules from before policies `Suspend`.
+fn error_policy(on_error: Option<String>) -> ErrorPolicy {
+    on_error
+        .and_then(|policy| policy.parse().ok())
+        .unwrap_or_default()
+}
+
+/// The retry policy stored for a module, the defaults for modules from
+/// before retry policies.
+fn retry_policy(retry: Option<String>) -> RetryPolicy {
+    retry
+        .and_then(|retry| serde_json::from_str(&retry).ok())
+        .unwrap_or_default()
+}
+
+/// `CREATE TABLE IF NOT EXISTS` leaves tables of older databases as they
+/// were, columns added since have to be added separately.
+fn add_column_if_missing(
+    conn: &r2d2_sqlite::rusqlite::Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<(), anyhow::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map((), |row| row.get::<_, String>(1))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fetch::{FetchOptions, FetchRequest},
+        journal::{FetchOutcome, Journal},
+    };
+    use serde_json::json;
+
+    fn memory_db() -> Db {
+        // every connection to `:memory:` is a database of its own, those to
+        // a named one share it
+        let name = format!("file:{}?mode=memory&cache=shared", nanoid!());
+        let db = Db {
+            pool: Pool::builder()
+                .max_size(4)
+                .build(SqliteConnectionManager::file(name))
+                .unwrap(),
+            retention: Default::default(),
+        };
+        db.init().unwrap();
+        db
+    }
+
+    /// A proc of a module of its own.
+    fn new_proc(db: &Db) -> String {
+        let name = nanoid!();
+        let src = format!("export default function main() {{}} // {}", name);
+        let compiled = CompilationResult {
+            compiled_src: src.clone(),
+            source_map: None,
+            program_counter_mapping: vec![],
+        };
+        let module_id = db
+            .module_new(
+                &name,
+                &src,
+                &compiled,
+                None,
+                &ModuleLimits::default(),
+                ErrorPolicy::default(),
+                &RetryPolicy::default(),
+            )
+            .unwrap();
+        db.proc_new(&module_id, &None, None, 1).unwrap()
+    }
+
+    fn suspended() -> StepResult {
+        StepResult {
+            status: StepResultStatus::SUSPEND,
+            val: None,
+            suspension: Some(json!({ "$recv": {} })),
+        }
+    }
+
+    #[test]
+    pub fn test_step_replay() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let first = EngineStatus {
+            frames: Some(json!({ "fnhash": "main", "pc": 1 })),
+            funcs: Some(json!({})),
+            ..Default::default()
+        };
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &first,
+            &StepEffects::default(),
+        )
+        .unwrap();
+
+        let request = FetchRequest {
+            url: "https://example.com/".to_string(),
+            options: FetchOptions::default(),
+        };
+        let mut journal = Journal::default();
+        let outcome = journal.fetch(request.clone(), |_| {
+            FetchOutcome::Error("connection refused".to_string())
+        });
+        let second = EngineStatus {
+            frames: Some(json!({ "fnhash": "main", "pc": 2 })),
+            funcs: Some(json!({})),
+            clock: Some(1_700_000_000_000),
+            seed: Some(42),
+            journal: Some(serde_json::to_value(journal.entries()).unwrap()),
+            ..Default::default()
+        };
+        db.proc_update(
+            &proc_id,
+            Some(&json!({ "n": 1 })),
+            &suspended(),
+            &second,
+            &StepEffects::default(),
+        )
+        .unwrap();
+
+        let replay = db.proc_step_replay(&proc_id, 2).unwrap();
+        assert_eq!(replay.msg, Some(json!({ "n": 1 })));
+        assert_eq!(replay.clock, second.clock);
+        assert_eq!(replay.seed, second.seed);
+        assert_eq!(replay.resumed_from.frames, first.frames);
+
+        // the replayed step is handed the outcome rather than requesting again
+        let entries = serde_json::from_value(replay.journal.unwrap()).unwrap();
+        let mut journal = Journal::replaying(entries);
+        let replayed = journal.fetch(request, |request| {
+            panic!("{} was requested again", request.url)
+        });
+        assert_eq!(replayed, outcome);
+
+        let replay = db.proc_step_replay(&proc_id, 1).unwrap();
+        assert_eq!(replay.resumed_from.frames, None);
+        assert!(db.proc_step_replay(&proc_id, 3).is_err());
+    }
+
+    #[test]
+    pub fn test_snapshot_pages_shared() {
+        let db = memory_db();
+        let snapshot: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let engine_status = EngineStatus {
+            frames: Some(json!({})),
+            funcs: Some(json!({})),
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        };
+        let procs = [new_proc(&db), new_proc(&db)];
+        for proc_id in &procs {
+            db.proc_update(
+                proc_id,
+                None,
+                &suspended(),
+                &engine_status,
+                &StepEffects::default(),
+            )
+            .unwrap();
+        }
+
+        let conn = db.pool.get().unwrap();
+        let stored: usize = conn
+            .query_row("SELECT COUNT(*) FROM snapshot_pages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, snapshot_store::pages(&snapshot).len());
+        for proc_id in &procs {
+            let (engine_status, problem) = step_engine_status(&conn, proc_id, 1).unwrap();
+            assert!(problem.is_none());
+            assert_eq!(engine_status.snapshot, Some(snapshot.clone()));
+        }
+    }
+
+    #[test]
+    pub fn test_unreadable_step_falls_back() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let frames = |pc| EngineStatus {
+            frames: Some(json!({ "fnhash": "main", "pc": pc })),
+            funcs: Some(json!({})),
+            ..Default::default()
+        };
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &frames(1),
+            &StepEffects::default(),
+        )
+        .unwrap();
+        db.proc_update(
+            &proc_id,
+            Some(&json!(2)),
+            &suspended(),
+            &frames(2),
+            &StepEffects::default(),
+        )
+        .unwrap();
+
+        let conn = db.pool.get().unwrap();
+        conn.execute("UPDATE steps SET frames = '{' WHERE step_id = 2", [])
+            .unwrap();
+        let flagged = || {
+            db.proc_list()
+                .unwrap()
+                .into_iter()
+                .find(|proc| proc.id == proc_id)
+                .unwrap()
+                .flagged
+        };
+
+        // reading it leaves it as it is
+        assert!(db.proc_get_details(&proc_id).is_err());
+        assert_eq!(flagged(), None);
+
+        db.proc_repair(&proc_id).unwrap();
+        let details = db.proc_get_details(&proc_id).unwrap();
+        assert_eq!(details.engine_status.frames, frames(1).frames);
+        assert!(flagged().unwrap().contains("resumed from step 1"));
+
+        // rewound to the step it was resumed from, the next step follows it
+        db.proc_update(
+            &proc_id,
+            Some(&json!(3)),
+            &suspended(),
+            &frames(3),
+            &StepEffects::default(),
+        )
+        .unwrap();
+        let step = db.proc_step_get(&proc_id, 3).unwrap();
+        assert_eq!(step.summary.parent_step_id, Some(1));
+
+        db.proc_unflag(&proc_id).unwrap();
+        assert_eq!(flagged(), None);
+    }
+
+    #[test]
+    pub fn test_legacy_snapshot_left_out() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let engine_status = EngineStatus {
+            frames: Some(json!({})),
+            funcs: Some(json!({})),
+            ..Default::default()
+        };
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &engine_status,
+            &StepEffects::default(),
+        )
+        .unwrap();
+
+        let conn = db.pool.get().unwrap();
+        conn.execute("UPDATE steps SET snapshot = x'00ff'", [])
+            .unwrap();
+        let details = db.proc_get_details(&proc_id).unwrap();
+        assert_eq!(details.engine_status.snapshot, None);
+        assert_eq!(details.engine_status.frames, Some(json!({})));
+    }
+
+    #[test]
+    pub fn test_pc_map_format() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let conn = db.pool.get().unwrap();
+        let module_id: String = conn
+            .query_row(
+                "SELECT module_id FROM procs WHERE id = ?",
+                [&proc_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let (_, format) = db.module_version_pc_map(&module_id, 1).unwrap();
+        assert_eq!(format, PC_MAP_FORMAT);
+
+        conn.execute("UPDATE module_versions SET pc_map_format = NULL", [])
+            .unwrap();
+        let (_, format) = db.module_version_pc_map(&module_id, 1).unwrap();
+        assert_eq!(format, 1);
+    }
+
+    #[test]
+    pub fn test_migrate_from_checked_step() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &EngineStatus::default(),
+            &StepEffects::default(),
+        )
+        .unwrap();
+        let details = db.proc_get_details(&proc_id).unwrap();
+        let src = details.compiled_src.clone();
+        let compiled = CompilationResult {
+            compiled_src: src.clone(),
+            source_map: None,
+            program_counter_mapping: vec![],
+        };
+        let version = db.module_edit(&details.module_id, &src, &compiled).unwrap();
+
+        // the proc stepped after its frames were checked
+        db.proc_update(
+            &proc_id,
+            Some(&json!(1)),
+            &suspended(),
+            &EngineStatus::default(),
+            &StepEffects::default(),
+        )
+        .unwrap();
+        assert!(db.proc_migrate(&proc_id, details.step_id, version).is_err());
+        assert_eq!(db.proc_get_details(&proc_id).unwrap().module_version, 1);
+
+        let details = db.proc_get_details(&proc_id).unwrap();
+        db.proc_migrate(&proc_id, details.step_id, version).unwrap();
+        assert_eq!(
+            db.proc_frames(&proc_id).unwrap(),
+            (StepResultStatus::SUSPEND, None, version)
+        );
+    }
+
+    #[test]
+    pub fn test_failed_step_keeps_frames() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let failed = StepResult {
+            status: StepResultStatus::ERROR,
+            val: Some(json!("boom")),
+            suspension: None,
+        };
+        let step_id = db
+            .proc_step_failed(&proc_id, None, &failed, 0, None, &StepEffects::default())
+            .unwrap();
+        assert_eq!(
+            db.proc_step_get(&proc_id, step_id).unwrap().frames,
+            Some(json!(null))
+        );
+
+        let engine_status = EngineStatus {
+            frames: Some(json!({ "fnhash": "main", "pc": 1 })),
+            funcs: Some(json!({ "main": 1 })),
+            ..Default::default()
+        };
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &engine_status,
+            &StepEffects::default(),
+        )
+        .unwrap();
+        let step_id = db
+            .proc_step_failed(
+                &proc_id,
+                Some(&json!(2)),
+                &failed,
+                0,
+                None,
+                &StepEffects::default(),
+            )
+            .unwrap();
+        let step = db.proc_step_get(&proc_id, step_id).unwrap();
+        assert_eq!(step.frames, engine_status.frames);
+        assert_eq!(step.funcs, engine_status.funcs);
+    }
+
+    #[test]
+    pub fn test_failed_proc_keeps_frames() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let engine_status = EngineStatus {
+            frames: Some(json!({ "fnhash": "main", "pc": 1 })),
+            funcs: Some(json!({ "main": 1 })),
+            ..Default::default()
+        };
+        let timers = [("timer".to_string(), 1_000)];
+        let effects = StepEffects {
+            timers_started: &timers,
+            ..Default::default()
+        };
+        db.proc_update(&proc_id, None, &suspended(), &engine_status, &effects)
+            .unwrap();
+        let failed = StepResult {
+            status: StepResultStatus::ERROR,
+            val: Some(json!("boom")),
+            suspension: None,
+        };
+
+        // out of attempts, the proc ends at the failed step
+        let step_id = db
+            .proc_fail(
+                &proc_id,
+                Some(&json!(2)),
+                &failed,
+                0,
+                None,
+                &StepEffects::default(),
+            )
+            .unwrap();
+        let step = db.proc_step_get(&proc_id, step_id).unwrap();
+        assert_eq!(step.frames, engine_status.frames);
+        assert_eq!(step.funcs, engine_status.funcs);
+        assert!(step.summary.current);
+        assert_eq!(
+            db.proc_get(&proc_id).unwrap().step_result.status,
+            StepResultStatus::ERROR
+        );
+        assert!(db.timers_due(1_000).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_failed_steps_by_retry() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &EngineStatus::default(),
+            &StepEffects::default(),
+        )
+        .unwrap();
+        let failed = StepResult {
+            status: StepResultStatus::ERROR,
+            val: Some(json!("boom")),
+            suspension: None,
+        };
+        let fail = |msg: &serde_json::Value, retry_of| {
+            db.proc_step_failed(
+                &proc_id,
+                Some(msg),
+                &failed,
+                0,
+                retry_of,
+                &StepEffects::default(),
+            )
+            .unwrap()
+        };
+        // the same message sent twice fails as two attempts of its own
+        let first = fail(&json!(1), None);
+        let second = fail(&json!(2), None);
+        fail(&json!(1), Some(first));
+        let again = fail(&json!(1), None);
+
+        let attempts = |retry_of| db.proc_failed_steps(&proc_id, retry_of).unwrap().len();
+        assert_eq!(attempts(None), 4);
+        assert_eq!(attempts(Some(first)), 2);
+        assert_eq!(attempts(Some(second)), 1);
+        assert_eq!(attempts(Some(again)), 1);
+
+        // a step that succeeds starts the count over
+        db.proc_update(
+            &proc_id,
+            Some(&json!(2)),
+            &suspended(),
+            &EngineStatus::default(),
+            &StepEffects::default(),
+        )
+        .unwrap();
+        assert_eq!(attempts(Some(first)), 0);
+    }
+
+    #[test]
+    pub fn test_mbox() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let other_proc_id = new_proc(&db);
+        let ids: Vec<String> = (0..4)
+            .map(|n| db.mbox_push(&proc_id, &json!({ "n": n })).unwrap())
+            .collect();
+        db.mbox_push(&other_proc_id, &json!({ "n": 0 })).unwrap();
+
+        let pending = |proc_id| -> Vec<serde_json::Value> {
+            db.mbox_list(proc_id)
+                .unwrap()
+                .into_iter()
+                .map(|msg| msg.msg)
+                .collect()
+        };
+        assert_eq!(
+            pending(&proc_id),
+            vec![
+                json!({ "n": 0 }),
+                json!({ "n": 1 }),
+                json!({ "n": 2 }),
+                json!({ "n": 3 })
+            ]
+        );
+
+        // messages no step matched stay queued, in order, those a step read
+        // are dropped with it
+        let effects = StepEffects {
+            mbox_read: &[ids[0].clone(), ids[2].clone()],
+            ..Default::default()
+        };
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &EngineStatus::default(),
+            &effects,
+        )
+        .unwrap();
+        assert_eq!(
+            pending(&proc_id),
+            vec![json!({ "n": 1 }), json!({ "n": 3 })]
+        );
+
+        let count = |read: bool| -> usize {
+            let conn = db.pool.get().unwrap();
+            conn.query_row(
+                "SELECT COUNT(*) FROM mbox WHERE proc_id = ? AND read = ?",
+                params![proc_id, read],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(count(true), 0);
+        assert_eq!(count(false), 2);
+
+        assert_eq!(db.mbox_purge(&proc_id).unwrap(), 2);
+        assert_eq!(pending(&proc_id), Vec::<serde_json::Value>::new());
+        assert_eq!(pending(&other_proc_id), vec![json!({ "n": 0 })]);
+    }
+
+    #[test]
+    pub fn test_outbox_with_step() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let post = |id: &str| OutboundPost {
+            id: id.to_string(),
+            proc_id: proc_id.clone(),
+            url: "http://localhost/hook".to_string(),
+            headers: Default::default(),
+            body: json!({ "id": id }),
+            attempts: 0,
+            max_attempts: 5,
+            backoff_ms: 1_000,
+        };
+        let queued = || -> Vec<String> {
+            db.outbox_due(now_as_millis())
+                .unwrap()
+                .into_iter()
+                .map(|post| post.id)
+                .collect()
+        };
+
+        // the posts of a failed step are dropped with it
+        let failed = StepResult {
+            status: StepResultStatus::ERROR,
+            val: Some(json!({ "error": "boom" })),
+            suspension: None,
+        };
+        let posts = [post("a")];
+        let effects = StepEffects {
+            posts: &posts,
+            ..Default::default()
+        };
+        db.proc_step_failed(&proc_id, None, &failed, 0, None, &effects)
+            .unwrap();
+        assert!(queued().is_empty());
+
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &EngineStatus::default(),
+            &effects,
+        )
+        .unwrap();
+        assert_eq!(queued(), vec!["a".to_string()]);
+
+        // a replayed step queues the same keys again, which are dropped
+        let posts = [post("a"), post("b")];
+        let effects = StepEffects {
+            posts: &posts,
+            ..Default::default()
+        };
+        db.proc_update(
+            &proc_id,
+            None,
+            &suspended(),
+            &EngineStatus::default(),
+            &effects,
+        )
+        .unwrap();
+        assert_eq!(queued(), vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    pub fn test_timers() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let step = |effects: &StepEffects| {
+            db.proc_update(
+                &proc_id,
+                None,
+                &suspended(),
+                &EngineStatus::default(),
+                effects,
+            )
+            .unwrap()
+        };
+        let due = |now| db.timers_due(now).unwrap();
+        let timers_started = [("t1".to_string(), 100), ("t2".to_string(), 200)];
+        step(&StepEffects {
+            timers_started: &timers_started,
+            ..Default::default()
+        });
+        assert_eq!(due(150), vec![(proc_id.clone(), "t1".to_string())]);
+
+        // claimed by one tick, the next leaves it alone until the claim runs out
+        assert!(!db.timer_claim("t2", 150, 1_150).unwrap());
+        assert!(db.timer_claim("t1", 150, 1_150).unwrap());
+        assert!(!db.timer_claim("t1", 150, 1_150).unwrap());
+        assert_eq!(due(1_000), vec![(proc_id.clone(), "t2".to_string())]);
+        assert_eq!(due(1_150).len(), 2);
+
+        // spent by the step it reached
+        step(&StepEffects {
+            timers_fired: &["t1".to_string()],
+            timers_cancelled: &["t2".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(due(10_000), vec![]);
+    }
+
+    #[test]
+    pub fn test_subscriptions() {
+        let db = memory_db();
+        let proc_id = new_proc(&db);
+        let subscriptions = || db.proc_subscriptions_get_all().unwrap();
+        let subscription = json!({ "type": "tick" });
+
+        db.proc_subscription_set(&proc_id, Some(&subscription))
+            .unwrap();
+        db.proc_subscription_set(&proc_id, Some(&subscription))
+            .unwrap();
+        assert_eq!(subscriptions(), vec![(proc_id.clone(), subscription)]);
+
+        db.proc_subscription_set(&proc_id, None).unwrap();
+        assert_eq!(subscriptions(), vec![]);
+    }
+}
@@ -1,13 +1,19 @@
 use std::{collections::HashMap, string::String, sync::Arc};
 
-use anyhow::{anyhow, Ok, Result};
-use apeiro_compiler::{apeiro_compile, extract_export_name, CompilationResult};
+use anyhow::{anyhow, Context, Ok, Result};
+use apeiro_compiler::{
+    apeiro_compile, extract_export_name, CompilationResult, FnLayouts, LiveFrame, PC_MAP_FORMAT,
+};
 use apeiro_internal_api::{
-    ModuleNewRequest, ModuleSummary, ProcListOutput, ProcNewOutput, ProcNewRequest,
-    ProcSendRequest, ProcStatus, ProcStatusDebug, StepResult, StepResultStatus,
+    ErrorPolicy, MboxMessage, MboxRejection, ModuleCheckOutput, ModuleMigrateOutput,
+    ModuleNewRequest, ModuleSummary, ProcCompatibility, ProcExplainOutput, ProcHistoryOutput,
+    ProcListOutput, ProcMboxOutput, ProcMboxPurgeOutput, ProcMigrateRejection, ProcNewOutput,
+    ProcNewRequest, ProcSendRequest, ProcStatus, ProcStatusDebug, ResumeMode, RetryPolicy,
+    StepDetails, StepResult, StepResultStatus,
 };
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
+use serde_json_matcher::MatcherIndex;
 use tokio::sync::{mpsc, RwLock};
 use tracing::trace;
 
@@ -53,21 +59,31 @@ pub struct RemoteDEngineCmd {
 struct SharedDEngine {
     p2p_channel: RwLock<Option<tokio::sync::mpsc::Sender<RemoteDEngineCmd>>>,
     runtime_js_src: Option<fn() -> String>,
+    /// see `Engine::base_snapshot`
+    base_snapshot: Option<Arc<[u8]>>,
+    /// for procs that don't pick their own
+    resume_mode: ResumeMode,
     db: Box<dyn ApeiroPersistence>,
     locks: Arc<RwLock<HashMap<String, Arc<RwLock<()>>>>>,
     tx: mpsc::Sender<DEngineCmd>,
     watchers: Arc<RwLock<HashMap<String, tokio::sync::watch::Sender<ProcEvent>>>>,
     watchers_exec: Arc<RwLock<HashMap<(String, String), tokio::sync::watch::Sender<ProcEvent>>>>,
-    proc_subscriptions: Arc<RwLock<HashMap<String, Vec<serde_json::Value>>>>,
+    proc_subscriptions: Arc<RwLock<MatcherIndex<String>>>,
 }
 
 use tracing::{event, instrument, Level};
 
 use crate::{
-    db::ApeiroPersistence,
+    db::{ApeiroPersistence, StepEffects},
     eventloop::{now_as_millis, EventLoop},
+    journal::Journal,
+    matcher::{recv_matcher_of, MsgMatcher},
+    outbox::{Delivery, ATTEMPT_LEASE_MS},
 };
 
+/// How long a fired timer may take to reach its proc before it is due again.
+const FIRE_LEASE_MS: u64 = 60 * 1_000;
+
 pub trait PluginStorage {
     fn get(&self) -> Result<serde_json::Value, anyhow::Error>;
     fn set(&self, val: serde_json::Value) -> Result<(), anyhow::Error>;
@@ -103,7 +119,46 @@ impl DEngine {
         runtime_js_src: Option<fn() -> String>,
         db: Box<dyn ApeiroPersistence>,
     ) -> Result<(DEngine, EventLoop)> {
-        let (shared_dengine, rx, tx) = SharedDEngine::new_inner(runtime_js_src, db)?;
+        DEngine::new_with_resume_mode(runtime_js_src, ResumeMode::default(), db)
+    }
+
+    /// Like `new`, with `resume_mode` for procs that don't pick their own.
+    /// Resuming from frames, procs start without the base snapshot.
+    pub fn new_with_resume_mode(
+        runtime_js_src: Option<fn() -> String>,
+        resume_mode: ResumeMode,
+        db: Box<dyn ApeiroPersistence>,
+    ) -> Result<(DEngine, EventLoop)> {
+        let base_snapshot = match resume_mode {
+            ResumeMode::Snapshot => runtime_js_src
+                .map(crate::Engine::base_snapshot)
+                .transpose()?,
+            ResumeMode::Frames => None,
+        };
+        DEngine::new_inner(runtime_js_src, base_snapshot, resume_mode, db)
+    }
+
+    /// Like `new`, but without a base snapshot: every proc loads the engine
+    /// runtime on its first step.
+    pub fn new_without_base_snapshot(
+        runtime_js_src: Option<fn() -> String>,
+        db: Box<dyn ApeiroPersistence>,
+    ) -> Result<(DEngine, EventLoop)> {
+        DEngine::new_inner(runtime_js_src, None, ResumeMode::default(), db)
+    }
+
+    fn new_inner(
+        runtime_js_src: Option<fn() -> String>,
+        base_snapshot: Option<Vec<u8>>,
+        resume_mode: ResumeMode,
+        db: Box<dyn ApeiroPersistence>,
+    ) -> Result<(DEngine, EventLoop)> {
+        let (shared_dengine, rx, tx) = SharedDEngine::new_inner(
+            runtime_js_src,
+            base_snapshot.map(Arc::from),
+            resume_mode,
+            db,
+        )?;
         let instance = Arc::new(shared_dengine);
         let event_loop = EventLoop {
             dengine: DEngine(instance.clone()),
@@ -113,6 +168,10 @@ impl DEngine {
         Ok((DEngine(instance), event_loop))
     }
 
+    pub(crate) fn base_snapshot(&self) -> Option<Arc<[u8]>> {
+        self.0.base_snapshot.clone()
+    }
+
     #[instrument(skip(self))]
     pub async fn get_proc_lock(&self, proc_id: &String) -> Result<Arc<RwLock<()>>, anyhow::Error> {
         let proc_lock = {
@@ -129,15 +188,21 @@ impl DEngine {
         Ok(proc_lock)
     }
 
+    /// Subscribes the proc to the events its `suspension` waits for, if it
+    /// does, and to nothing else: a proc that moved on from a `$subscribe`
+    /// no longer receives the events it subscribed to.
     pub async fn process_post_step_suspension(
         &self,
         proc_id: &String,
-        suspension: &serde_json::Value,
-    ) {
-        if let Some(subscription) = suspension.get("$subscribe") {
-            trace!("subscription detected");
-            self.subscribe_proc_to_events(proc_id.clone(), subscription.clone())
-                .await;
+        suspension: Option<&serde_json::Value>,
+    ) -> Result<(), anyhow::Error> {
+        match suspension.and_then(|suspension| suspension.get("$subscribe")) {
+            Some(subscription) => {
+                trace!("subscription detected");
+                self.subscribe_proc_to_events(proc_id.clone(), subscription.clone())
+                    .await
+            }
+            None => self.unsubscribe_proc_from_events(proc_id).await,
         }
     }
 
@@ -145,6 +210,7 @@ impl DEngine {
         &self,
         module: ModuleSummary,
         name: Option<String>,
+        resume_mode: Option<ResumeMode>,
     ) -> Result<ProcNewOutput, anyhow::Error> {
         let name = if module.singleton.is_some() {
             self.0.db.proc_rename_if_exists(
@@ -157,7 +223,10 @@ impl DEngine {
             name.unwrap_or_else(|| format!("{}_{}", module.name, now_as_millis()))
         };
 
-        let proc_id = self.0.db.proc_new(&module.id, &Some(name))?;
+        let proc_id = self
+            .0
+            .db
+            .proc_new(&module.id, &Some(name), resume_mode, module.version)?;
         let step_id = nanoid!();
 
         let mut engine = crate::Engine::new(
@@ -166,17 +235,19 @@ impl DEngine {
             step_id,
             self.clone(),
         );
+        engine.limits = module.limits;
+        engine.resume_mode = resume_mode.unwrap_or(self.0.resume_mode);
 
         let (res, engine_status) = engine
             .step_process(module.compiled_src, None, None, None)
             .await?;
 
-        self.0.db.proc_update(&proc_id, &res, &engine_status)?;
+        self.0
+            .db
+            .proc_update(&proc_id, None, &res, &engine_status, &step_effects(&engine))?;
 
-        if let Some(suspension) = &res.suspension {
-            self.process_post_step_suspension(&proc_id, suspension)
-                .await;
-        };
+        self.process_post_step_suspension(&proc_id, res.suspension.as_ref())
+            .await?;
 
         Ok(ProcNewOutput {
             id: proc_id,
@@ -186,8 +257,12 @@ impl DEngine {
 
     #[instrument(skip(self))]
     pub async fn proc_new(&self, req: ProcNewRequest) -> Result<ProcNewOutput, anyhow::Error> {
-        let module = self.module_get(req.module_id.clone()).await?;
-        self.proc_new_compiled(module, req.name).await
+        let module = match req.version {
+            Some(version) => self.0.db.module_version_get(&req.module_id, version)?,
+            None => self.module_get(req.module_id.clone()).await?,
+        };
+        self.proc_new_compiled(module, req.name, req.resume_mode)
+            .await
     }
 
     #[instrument(skip(self))]
@@ -197,6 +272,8 @@ impl DEngine {
         Ok(ProcListOutput { procs })
     }
 
+    /// Adds a new version of the module. Procs already running keep their
+    /// version until they are migrated, new ones start on this one.
     pub async fn module_edit(
         &self,
         module_id: String,
@@ -204,51 +281,149 @@ impl DEngine {
     ) -> Result<Option<ProcNewOutput>, anyhow::Error> {
         let module_summary = self.0.db.module_get(&module_id)?;
 
-        // let mut procs_not_done = 0;
-        // let mut max_version = 0;
-
-        // for proc in module_summary.procs {
-        //     let proc = self.0.db.proc_get(&proc).unwrap();
-        //     if !(proc.step_result.status == StepResultStatus::DONE || proc.step_result.status == StepResultStatus::CRASHED) {
-        //     } else {
-        //         procs_not_done += 1;
-        //     }
-        // }
-        let procs_not_done = module_summary
-            .procs
-            .iter()
-            .filter(|proc_id| {
-                let proc = self.0.db.proc_get(proc_id).unwrap();
-                !(proc.step_result.status == StepResultStatus::DONE
-                    || proc.step_result.status == StepResultStatus::CRASHED)
-            })
-            .count();
-
-        if procs_not_done > 0 {
-            Err(anyhow!(
-                "can't edit module while there are procs still running"
-            ))
+        let src = new_src.clone();
+        let compiled_src = tokio::task::spawn_blocking(move || apeiro_compile(src)).await??;
+
+        self.0.db.module_edit(&module_id, &new_src, &compiled_src)?;
+
+        if module_summary.singleton.is_some() {
+            let new_proc = self
+                .proc_new(ProcNewRequest {
+                    module_id: module_id.clone(),
+                    name: None,
+                    version: None,
+                    resume_mode: None,
+                })
+                .await?;
+            Ok(Some(new_proc))
         } else {
-            let src = new_src.clone();
-            let compiled_src = tokio::task::spawn_blocking(move || apeiro_compile(src)).await??;
+            Ok(None)
+        }
+    }
+
+    /// The fn layouts of a stored version. Versions stored in an older
+    /// `PC_MAP_FORMAT` don't list every pc, so only what they do list is used.
+    fn version_layouts(&self, module_id: &str, version: u32) -> Result<FnLayouts> {
+        let (mapping, format) = self.0.db.module_version_pc_map(module_id, version)?;
+        if format == PC_MAP_FORMAT {
+            Ok(FnLayouts::new(&mapping))
+        } else {
+            Ok(FnLayouts::partial(&mapping))
+        }
+    }
+
+    /// Which of the module's suspended procs could resume under `src`, were
+    /// it the module's next version, and how its fns differ from the latest.
+    #[instrument(skip(self, src))]
+    pub async fn module_check(
+        &self,
+        module_id: String,
+        src: String,
+    ) -> Result<ModuleCheckOutput, anyhow::Error> {
+        let module = self.0.db.module_get(&module_id)?;
+        let compiled_src = tokio::task::spawn_blocking(move || apeiro_compile(src)).await??;
+
+        let latest = self.version_layouts(&module_id, module.version)?;
+        let proposed = FnLayouts::new(&compiled_src.program_counter_mapping);
+        let diff = latest.diff(&proposed);
+
+        let mut procs = vec![];
+        for proc_id in &module.procs {
+            let (module_version, problems) = match self.0.db.proc_frames(proc_id) {
+                Result::Ok((StepResultStatus::SUSPEND, frames, module_version)) => {
+                    (module_version, frame_problems(&proposed, &frames))
+                }
+                Result::Ok(_) => continue,
+                Err(e) => (0, vec![format!("{:#}", e)]),
+            };
+            procs.push(ProcCompatibility {
+                proc_id: proc_id.clone(),
+                module_version,
+                compatible: problems.is_empty(),
+                problems,
+            });
+        }
 
-            self.0
+        let fnhashes = |fnhashes: Vec<u64>| fnhashes.iter().map(u64::to_string).collect();
+        Ok(ModuleCheckOutput {
+            version: module.version,
+            added_fns: fnhashes(diff.added),
+            removed_fns: fnhashes(diff.removed),
+            changed_fns: fnhashes(diff.changed),
+            procs,
+        })
+    }
+
+    /// Moves the module's suspended procs onto `version`, its latest if
+    /// unset. Procs whose frames can't be resumed under `version` are left
+    /// where they are.
+    #[instrument(skip(self))]
+    pub async fn module_migrate(
+        &self,
+        module_id: String,
+        version: Option<u32>,
+    ) -> Result<ModuleMigrateOutput, anyhow::Error> {
+        let module = match version {
+            Some(version) => self.0.db.module_version_get(&module_id, version)?,
+            None => self.0.db.module_get(&module_id)?,
+        };
+        let layouts = self.version_layouts(&module_id, module.version)?;
+
+        let mut migrated = vec![];
+        let mut incompatible = vec![];
+        for proc_id in &module.procs {
+            let proc_lock = self.get_proc_lock(proc_id).await?;
+            let _proc_lock_guard = proc_lock.write().await;
+
+            let proc = match self.0.db.proc_get_details(proc_id) {
+                Result::Ok(proc) => proc,
+                Err(e) => {
+                    incompatible.push(ProcMigrateRejection {
+                        proc_id: proc_id.clone(),
+                        reason: format!("{:#}", e),
+                    });
+                    continue;
+                }
+            };
+            if proc.state.status != StepResultStatus::SUSPEND
+                || proc.module_version == module.version
+            {
+                continue;
+            }
+            if proc.module_version > module.version {
+                incompatible.push(ProcMigrateRejection {
+                    proc_id: proc.pid,
+                    reason: format!("already on version {}", proc.module_version),
+                });
+                continue;
+            }
+
+            let problems = frame_problems(&layouts, &proc.engine_status.frames);
+            if !problems.is_empty() {
+                incompatible.push(ProcMigrateRejection {
+                    proc_id: proc.pid,
+                    reason: problems.join(", "),
+                });
+                continue;
+            }
+            match self
+                .0
                 .db
-                .module_edit(&module_id, &new_src, &compiled_src.compiled_src)?;
-
-            if module_summary.singleton.is_some() {
-                let new_proc = self
-                    .proc_new(ProcNewRequest {
-                        module_id: module_id.clone(),
-                        name: None,
-                        version: None,
-                    })
-                    .await?;
-                Ok(Some(new_proc))
-            } else {
-                Ok(None)
+                .proc_migrate(&proc.pid, proc.step_id, module.version)
+            {
+                Result::Ok(()) => migrated.push(proc.pid),
+                Err(e) => incompatible.push(ProcMigrateRejection {
+                    proc_id: proc.pid,
+                    reason: format!("{:#}", e),
+                }),
             }
         }
+
+        Ok(ModuleMigrateOutput {
+            version: module.version,
+            migrated,
+            incompatible,
+        })
     }
 
     #[instrument(skip(self))]
@@ -292,6 +467,13 @@ impl DEngine {
                 } else {
                     None
                 },
+                &req.limits.unwrap_or_default(),
+                req.on_error.unwrap_or(if req.retry.is_some() {
+                    ErrorPolicy::Retry
+                } else {
+                    ErrorPolicy::Suspend
+                }),
+                &req.retry.unwrap_or_default(),
             )?;
 
             Ok(module)
@@ -323,30 +505,257 @@ impl DEngine {
             .map_err(|_e| anyhow!("db problem"))?;
 
         let executing = self.proc_is_executing(&proc.proc_id).await?;
-
-        Ok(ProcStatus::new(
-            proc.proc_id,
-            proc.module_id,
-            proc.name,
-            proc.step_result,
-            executing,
-        ))
+        let failed_attempts = self.0.db.proc_failed_steps(&proc.proc_id, None)?;
+
+        Ok(ProcStatus {
+            failed_attempts,
+            ..ProcStatus::new(
+                proc.proc_id,
+                proc.module_id,
+                proc.name,
+                proc.step_result,
+                executing,
+            )
+        })
     }
 
     #[instrument]
     pub async fn proc_delete(&self, proc_id: String) -> Result<(), anyhow::Error> {
         self.0.db.proc_delete(&proc_id)?;
+        self.0.proc_subscriptions.write().await.remove(&proc_id);
 
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    pub async fn proc_history(&self, proc_id: String) -> Result<ProcHistoryOutput, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        let steps = self.0.db.proc_history(&proc.proc_id)?;
+
+        Ok(ProcHistoryOutput { steps })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn proc_step(
+        &self,
+        proc_id: String,
+        step_id: u64,
+    ) -> Result<StepDetails, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        self.0.db.proc_step_get(&proc.proc_id, step_id)
+    }
+
+    /// Runs a step of the proc again from the step it continued from, with
+    /// the message, clock and seed it ran with the first time. Requests it
+    /// makes as it did then get their recorded outcome rather than being
+    /// made again. Nothing the step does is kept, the proc is left as it is.
+    #[instrument(skip(self))]
+    pub async fn proc_step_replay(
+        &self,
+        proc_id: String,
+        step_id: u64,
+    ) -> Result<StepResult, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        let replay = self.0.db.proc_step_replay(&proc.proc_id, step_id)?;
+
+        let mut engine = crate::Engine::replaying(
+            self.0.runtime_js_src,
+            proc.proc_id.clone(),
+            step_id.to_string(),
+            self,
+        );
+        engine.limits = replay.limits;
+        engine.resume_mode = replay.resume_mode.unwrap_or(self.0.resume_mode);
+        if let Some(clock) = replay.clock {
+            engine.clock = clock;
+        }
+        if let Some(seed) = replay.seed {
+            engine.seed = seed;
+        }
+        if let Some(journal) = replay.journal {
+            let entries = serde_json::from_value(journal).context("corrupt journal")?;
+            engine.journal = Journal::replaying(entries);
+        }
+
+        // the step is handed its message alone, as it was when it ran
+        if let Some(msg) = replay.msg {
+            if let Some(timer_id) = msg.get("$timer").and_then(serde_json::Value::as_str) {
+                engine.timers_fired.push(timer_id.to_string());
+            } else {
+                engine.mbox.push(MboxMessage { id: nanoid!(), msg });
+            }
+        }
+
+        let (res, _) = engine
+            .step_process(
+                replay.compiled_src,
+                replay.resumed_from.funcs,
+                replay.resumed_from.frames,
+                loadable_snapshot(
+                    &proc.proc_id,
+                    replay.resumed_from.snapshot,
+                    replay.resumed_from.v8_version.as_deref(),
+                ),
+            )
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Resets the proc to an earlier step, the next message it receives
+    /// continues from there. Its mailbox and timers are left as they are.
+    #[instrument(skip(self))]
+    pub async fn proc_rewind(
+        &self,
+        proc_id: String,
+        step_id: u64,
+    ) -> Result<ProcStatus, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        {
+            let proc_lock = self.get_proc_lock(&proc.proc_id).await?;
+            let _proc_lock_guard = proc_lock.write().await;
+            self.0.db.proc_rewind(&proc.proc_id, step_id)?;
+        }
+
+        let rewound = self.0.db.proc_get(&proc.proc_id)?;
+        self.process_post_step_suspension(&proc.proc_id, rewound.step_result.suspension.as_ref())
+            .await?;
+        self.send_to_watchers(&proc.proc_id, &ProcEvent::StepResult(rewound.step_result))
+            .await?;
+
+        self.proc_get(proc.proc_id).await
+    }
+
+    /// Clears the proc's flag, see `ProcSummary::flagged`. It stays on the
+    /// step it was resumed from.
+    #[instrument(skip(self))]
+    pub async fn proc_unflag(&self, proc_id: String) -> Result<ProcStatus, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        self.0.db.proc_unflag(&proc.proc_id)?;
+
+        self.proc_get(proc.proc_id).await
+    }
+
+    /// Starts a new proc from one of `proc_id`'s steps, its current one if
+    /// `at_step` is unset. From then on the two procs evolve independently;
+    /// the fork starts with an empty mailbox and no timers.
+    #[instrument(skip(self))]
+    pub async fn proc_fork(
+        &self,
+        proc_id: String,
+        at_step: Option<u64>,
+    ) -> Result<ProcNewOutput, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        let name = format!(
+            "{}_fork_{}",
+            proc.name.as_ref().unwrap_or(&proc.proc_id),
+            now_as_millis()
+        );
+
+        let fork_id = {
+            let proc_lock = self.get_proc_lock(&proc.proc_id).await?;
+            let _proc_lock_guard = proc_lock.read().await;
+            self.0.db.proc_fork(&proc.proc_id, at_step, &name)?
+        };
+
+        let fork = self.0.db.proc_get(&fork_id)?;
+        self.process_post_step_suspension(&fork_id, fork.step_result.suspension.as_ref())
+            .await?;
+
+        Ok(ProcNewOutput {
+            id: fork_id,
+            state: fork.step_result,
+        })
+    }
+
     #[instrument(skip(self))]
     pub async fn proc_get_debug(&self, proc_id: String) -> Result<ProcStatusDebug, anyhow::Error> {
-        let proc_status_debug = self.0.db.proc_inspect(&proc_id)?;
+        let mut proc_status_debug = self.0.db.proc_inspect(&proc_id)?;
+
+        let proc = self.0.db.proc_get(&proc_id)?;
+        let matcher = proc
+            .step_result
+            .suspension
+            .as_ref()
+            .and_then(recv_matcher_of)
+            .and_then(|matcher| MsgMatcher::new(matcher).ok());
+        if let Some(matcher) = matcher {
+            for MboxMessage { id, msg } in self.0.db.mbox_list(&proc.proc_id)? {
+                let errors = matcher.validation_errors(&msg);
+                if !errors.is_empty() {
+                    proc_status_debug
+                        .rejected_msgs
+                        .push(MboxRejection { id, msg, errors });
+                }
+            }
+        }
 
         Ok(proc_status_debug)
     }
 
+    /// Reports whether `msg` would be taken by the `$recv` (or `$subscribe`)
+    /// the proc is currently suspended on, without delivering it.
+    #[instrument(skip(self))]
+    pub async fn proc_explain(
+        &self,
+        proc_id: String,
+        msg: serde_json::Value,
+    ) -> Result<ProcExplainOutput, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        let suspension = proc.step_result.suspension;
+
+        let matcher_def = match &suspension {
+            Some(suspension) if proc.step_result.status == StepResultStatus::SUSPEND => suspension
+                .get("$subscribe")
+                .cloned()
+                .or_else(|| recv_matcher_of(suspension)),
+            _ => None,
+        };
+        let matcher_def = match matcher_def {
+            Some(matcher_def) => matcher_def,
+            None => {
+                return Ok(ProcExplainOutput {
+                    accepted: false,
+                    suspension,
+                    explanation: None,
+                    errors: vec!["proc is not waiting for a message".to_string()],
+                })
+            }
+        };
+
+        let matcher = MsgMatcher::new(matcher_def)?;
+        let explanation = matcher
+            .explain(&msg)
+            .map(serde_json::to_value)
+            .transpose()?;
+
+        Ok(ProcExplainOutput {
+            accepted: matcher.matches(&msg),
+            suspension,
+            explanation,
+            errors: matcher.validation_errors(&msg),
+        })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn proc_mbox(&self, proc_id: String) -> Result<ProcMboxOutput, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        let msgs = self.0.db.mbox_list(&proc.proc_id)?;
+
+        Ok(ProcMboxOutput { msgs })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn proc_mbox_purge(
+        &self,
+        proc_id: String,
+    ) -> Result<ProcMboxPurgeOutput, anyhow::Error> {
+        let proc = self.0.db.proc_get(&proc_id)?;
+        let purged = self.0.db.mbox_purge(&proc.proc_id)?;
+
+        Ok(ProcMboxPurgeOutput { purged })
+    }
+
     #[instrument(skip(self))]
     pub async fn proc_send_and_watch(
         &self,
@@ -414,12 +823,11 @@ impl DEngine {
     }
 
     pub async fn load_proc_subscriptions(&self) -> Result<(), anyhow::Error> {
+        // also drops those of procs that moved on before they were dropped
         let procs = self.0.db.proc_list()?;
         for proc in procs {
-            if let Some(suspension) = proc.suspension {
-                self.process_post_step_suspension(&proc.id, &suspension)
-                    .await;
-            }
+            self.process_post_step_suspension(&proc.id, proc.suspension.as_ref())
+                .await?;
         }
 
         Ok(())
@@ -429,6 +837,100 @@ impl DEngine {
         self.send(DEngineCmd::Tick).await
     }
 
+    /// Wakes up procs whose `$sleep`/`$recv` timers have expired. A timer
+    /// that can't be fired is left for the next tick, the others still go.
+    #[instrument(skip(self))]
+    pub async fn fire_due_timers(&self) -> Result<(), anyhow::Error> {
+        for (proc_id, timer_id) in self.0.db.timers_due(now_as_millis())? {
+            if let Err(err) = self.fire_timer(&proc_id, &timer_id).await {
+                event!(
+                    Level::ERROR,
+                    "failed to fire timer {} of {}: {:?}",
+                    timer_id,
+                    proc_id,
+                    err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn fire_timer(&self, proc_id: &String, timer_id: &String) -> Result<(), anyhow::Error> {
+        if self.proc_is_executing(proc_id).await? {
+            // the running step may still cancel it, retry on next tick
+            return Ok(());
+        }
+
+        // ticks overlap, only the one that claims the timer sends it. Until
+        // the step it reaches spends it, it is due again once the claim runs
+        // out, so a send or step that fails doesn't lose it.
+        let now = now_as_millis();
+        if !self.0.db.timer_claim(timer_id, now, now + FIRE_LEASE_MS)? {
+            return Ok(());
+        }
+
+        trace!("firing timer {} of {}", timer_id, proc_id);
+        self.proc_send(
+            proc_id.clone(),
+            None,
+            ProcSendRequest {
+                msg: serde_json::json!({ "$timer": timer_id }),
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Delivers `$http_post`s whose next attempt is due, and sends the outcome
+    /// of each one that is settled to its proc.
+    #[instrument(skip(self))]
+    pub async fn deliver_due_posts(&self) -> Result<(), anyhow::Error> {
+        let now = now_as_millis();
+        for post in self.0.db.outbox_due(now)? {
+            if !self
+                .0
+                .db
+                .outbox_claim(&post.id, now, now + ATTEMPT_LEASE_MS)?
+            {
+                continue;
+            }
+
+            let dengine = self.clone();
+            tokio::task::spawn(async move {
+                let res = match post.attempt(now).await {
+                    Delivery::Retry(next_attempt_at) => {
+                        trace!("retrying post {} at {}", post.id, next_attempt_at);
+                        dengine
+                            .0
+                            .db
+                            .outbox_retry(&post.id, post.attempts + 1, next_attempt_at)
+                    }
+                    // the post stays queued until its outcome is handed to the
+                    // proc, once its lease runs out it is attempted again
+                    Delivery::Done(msg) => match dengine
+                        .proc_send(post.proc_id.clone(), None, ProcSendRequest { msg })
+                        .await
+                    {
+                        Result::Ok(_) => dengine.0.db.outbox_delete(&post.id),
+                        Err(e) => Err(e),
+                    },
+                };
+                if let Err(err) = res {
+                    event!(
+                        Level::ERROR,
+                        "failed to deliver post {}: {:?}",
+                        post.id,
+                        err
+                    );
+                }
+            });
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn proc_send(
         &self,
@@ -519,36 +1021,82 @@ impl DEngine {
         step_id: &String,
         body: &ProcSendRequest,
     ) -> Result<StepResult, anyhow::Error> {
-        let proc = self.0.db.proc_get_details(&proc_id_or_name)?;
+        let pid = self.0.db.proc_get(proc_id_or_name)?.proc_id;
+        // the proc is read under the lock, so that it can't step or be
+        // migrated in the meantime
+        let proc_lock = self.get_proc_lock(&pid).await?;
+        let _proc_lock_guard = proc_lock.write().await;
+
+        self.0.db.proc_repair(&pid)?;
+        let proc = self.0.db.proc_get_details(&pid)?;
 
         let res = if proc.state.status != StepResultStatus::SUSPEND {
             Err(anyhow!("can only send to suspended procs"))
         } else {
-            let proc_lock = self.get_proc_lock(&proc.pid).await.expect("cant lock");
-            let _proc_lock_guard = proc_lock.write().await;
-
-            event!(Level::INFO, "after proc lock guard");
+            // control messages only drive the proc forward, they are never
+            // received by user code so there is no point in keeping them around
+            let fired_timer = body
+                .msg
+                .get("$timer")
+                .and_then(serde_json::Value::as_str)
+                .map(String::from);
+            let (transient_msg, pushed_msg) = if fired_timer.is_some() {
+                (None, None)
+            } else if is_control_msg(&body.msg) {
+                let transient_msg = MboxMessage {
+                    id: nanoid!(),
+                    msg: body.msg.clone(),
+                };
+                (Some(transient_msg), None)
+            } else {
+                (None, Some(self.0.db.mbox_push(&proc.pid, &body.msg)?))
+            };
 
             let mut engine = crate::Engine::new(
-                Some(crate::get_engine_runtime),
+                self.0.runtime_js_src,
                 proc.pid.clone(),
                 step_id.clone(),
                 self.clone(),
             );
 
-            engine.mbox.push(body.msg.clone());
+            engine.limits = proc.limits;
+            engine.resume_mode = proc.resume_mode.unwrap_or(self.0.resume_mode);
+            *engine.mbox = self.0.db.mbox_list(&proc.pid)?;
+            engine.mbox.extend(transient_msg);
+            engine.timers_fired.extend(fired_timer);
 
-            let (res, engine_status) = engine
+            let stepped = engine
                 .step_process(
                     proc.compiled_src,
                     proc.engine_status.funcs,
                     proc.engine_status.frames,
-                    proc.engine_status.snapshot,
+                    loadable_snapshot(
+                        &proc.pid,
+                        proc.engine_status.snapshot,
+                        proc.engine_status.v8_version.as_deref(),
+                    ),
                 )
-                .await?;
+                .await;
+            let (res, engine_status) = match stepped {
+                Result::Ok(stepped) => stepped,
+                Err(e) => {
+                    let policy = (proc.on_error, &proc.retry);
+                    self.step_failed(&proc.pid, step_id, policy, &body.msg, pushed_msg, &e)
+                        .await?;
+                    return Err(e);
+                }
+            };
 
-            self.0.db.proc_update(&proc.pid, &res, &engine_status)?;
+            self.0.db.proc_update(
+                &proc.pid,
+                Some(&body.msg),
+                &res,
+                &engine_status,
+                &step_effects(&engine),
+            )?;
 
+            self.process_post_step_suspension(&proc.pid, res.suspension.as_ref())
+                .await?;
             if let Some(suspension) = &res.suspension {
                 if let Some(generator_tag) = suspension.get("$generator") {
                     if generator_tag.as_bool().unwrap_or(false) {
@@ -568,10 +1116,6 @@ impl DEngine {
                         }))
                         .await?;
                     }
-                } else if let Some(subscription) = suspension.get("$subscribe") {
-                    trace!("subscription detected");
-                    self.subscribe_proc_to_events(proc.pid.clone(), subscription.clone())
-                        .await;
                 }
             };
 
@@ -583,30 +1127,188 @@ impl DEngine {
         res
     }
 
+    /// Keeps a step of `proc_id` that failed with `err` in its history and
+    /// applies its module's error policy. `pushed_msg` is the mailbox entry
+    /// of the message that triggered the step, if it was queued there.
+    async fn step_failed(
+        &self,
+        proc_id: &String,
+        step_id: &str,
+        (on_error, retry): (ErrorPolicy, &RetryPolicy),
+        msg: &serde_json::Value,
+        pushed_msg: Option<String>,
+        err: &anyhow::Error,
+    ) -> Result<(), anyhow::Error> {
+        let now = now_as_millis();
+        let mut error = step_error(err);
+
+        // the message is kept with the failed step, and a delivered timer is
+        // spent whether or not the step succeeded: retries redeliver both
+        let fired_timer: Vec<String> = msg
+            .get("$timer")
+            .and_then(serde_json::Value::as_str)
+            .map(String::from)
+            .into_iter()
+            .collect();
+        let effects = StepEffects {
+            mbox_read: pushed_msg.as_slice(),
+            timers_fired: &fired_timer,
+            ..Default::default()
+        };
+
+        // a retry counts the attempts before it from the step it first
+        // retried on, a different message failing in between doesn't use up
+        // its retries
+        let retry_of = retried_step(step_id);
+        let mut backoff = None;
+        if on_error == ErrorPolicy::Retry {
+            let earlier = match retry_of {
+                Some(retry_of) => self.0.db.proc_failed_steps(proc_id, Some(retry_of))?.len(),
+                None => 0,
+            };
+            let attempt = earlier as u32 + 1;
+            error["attempt"] = attempt.into();
+            let retryable = error["error"]
+                .as_str()
+                .is_some_and(|error| retry.is_retryable(error));
+            if attempt < retry.max_attempts && retryable {
+                let wait = retry.backoff(attempt);
+                error["retry_at"] = (now + wait).into();
+                backoff = Some(wait);
+            }
+        }
+
+        let state = StepResult {
+            status: StepResultStatus::ERROR,
+            val: Some(error),
+            suspension: None,
+        };
+        match (on_error, backoff) {
+            (ErrorPolicy::Suspend, _) => {
+                self.0
+                    .db
+                    .proc_step_failed(proc_id, Some(msg), &state, now, retry_of, &effects)?;
+            }
+            (ErrorPolicy::Retry, Some(wait)) => {
+                let failed_step_id = self.0.db.proc_step_failed(
+                    proc_id,
+                    Some(msg),
+                    &state,
+                    now,
+                    retry_of,
+                    &effects,
+                )?;
+                trace!(
+                    "retrying step {} of {} in {} ms",
+                    failed_step_id,
+                    proc_id,
+                    wait
+                );
+                // the clock delivers the message again once `wait` is over,
+                // as a step that tells which attempts came before it
+                let retry_step_id = format!(
+                    "{}{}_{}",
+                    RETRY_STEP_PREFIX,
+                    retry_of.unwrap_or(failed_step_id),
+                    failed_step_id
+                );
+                self.send(DEngineCmd::Send(DEngineCmdSend {
+                    proc_id: "clock".to_string(),
+                    step_id: retry_step_id.clone(),
+                    req: ProcSendRequest {
+                        msg: serde_json::json!({
+                            "sender": proc_id,
+                            "wait": wait,
+                            "msg": msg,
+                            "step_id": retry_step_id,
+                        }),
+                    },
+                }))
+                .await?;
+            }
+            // out of attempts, or an error not worth retrying
+            (ErrorPolicy::Error, _) | (ErrorPolicy::Retry, None) => {
+                self.0
+                    .db
+                    .proc_fail(proc_id, Some(msg), &state, now, retry_of, &effects)?;
+                self.unsubscribe_proc_from_events(proc_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn get_all_subscriptions(&self) -> Vec<(String, serde_json::Value)> {
-        let mut result = vec![];
         let proc_subscriptions_locked = self.0.proc_subscriptions.read().await;
-        for (proc_id, proc_subscriptions) in proc_subscriptions_locked.iter() {
-            for subscription in proc_subscriptions {
-                result.push((proc_id.clone(), subscription.clone()));
+        proc_subscriptions_locked
+            .iter()
+            .map(|(proc_id, subscription)| (proc_id.clone(), subscription.clone()))
+            .collect()
+    }
+
+    /// Procs with a `$subscribe` matcher accepting `event`.
+    pub async fn subscribed_procs(&self, event: &serde_json::Value) -> Vec<String> {
+        let proc_subscriptions_locked = self.0.proc_subscriptions.read().await;
+        proc_subscriptions_locked
+            .matching(event)
+            .into_iter()
+            .collect()
+    }
+
+    /// Sends `event` to every proc subscribed to it, returning the pids of
+    /// those it was sent to.
+    #[instrument(skip(self))]
+    pub async fn publish_event(
+        &self,
+        event: serde_json::Value,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        // one proc the event can't be sent to doesn't keep it from the others
+        let mut sent = vec![];
+        for proc_id in self.subscribed_procs(&event).await {
+            let req = ProcSendRequest { msg: event.clone() };
+            match self.proc_send(proc_id.clone(), None, req).await {
+                Result::Ok(_) => sent.push(proc_id),
+                Err(err) => {
+                    event!(
+                        Level::ERROR,
+                        "failed to send event to {}: {:?}",
+                        proc_id,
+                        err
+                    );
+                }
             }
         }
-        result
+
+        Ok(sent)
     }
 
     #[instrument(skip(self))]
-    pub async fn subscribe_proc_to_events(&self, proc_id: String, subscription: serde_json::Value) {
-        let _subscription_id = self
-            .0
+    pub async fn subscribe_proc_to_events(
+        &self,
+        proc_id: String,
+        subscription: serde_json::Value,
+    ) -> Result<(), anyhow::Error> {
+        self.0
             .db
-            .proc_subscription_new(&proc_id, &subscription)
-            .unwrap();
+            .proc_subscription_set(&proc_id, Some(&subscription))?;
         let mut proc_subscriptions_locked = self.0.proc_subscriptions.write().await;
-        if let Some(subscriptions) = proc_subscriptions_locked.get_mut(&proc_id) {
-            subscriptions.push(subscription);
-        } else {
-            proc_subscriptions_locked.insert(proc_id, vec![subscription]);
+        proc_subscriptions_locked.remove(&proc_id);
+        if let Err(err) = proc_subscriptions_locked.insert(proc_id, subscription) {
+            event!(Level::ERROR, "invalid subscription: {:?}", err);
         }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn unsubscribe_proc_from_events(
+        &self,
+        proc_id: &String,
+    ) -> Result<(), anyhow::Error> {
+        self.0.db.proc_subscription_set(proc_id, None)?;
+        self.0.proc_subscriptions.write().await.remove(proc_id);
+
+        Ok(())
     }
 
     pub async fn watch_stats(&self) -> Vec<(String, usize)> {
@@ -692,6 +1394,133 @@ impl DEngine {
     }
 }
 
+/// A snapshot only loads into the V8 build that made it, anything else
+/// aborts the daemon. Procs whose snapshot can't be loaded are rehydrated
+/// from their frames and funcs instead, and snapshotted again by this build.
+/// So are those whose snapshot was stored before its V8 build was, there is
+/// no telling which one made it.
+fn loadable_snapshot(
+    proc_id: &str,
+    snapshot: Option<Vec<u8>>,
+    v8_version: Option<&str>,
+) -> Option<Vec<u8>> {
+    match (snapshot, v8_version) {
+        (Some(snapshot), Some(v8_version)) if v8_version == v8::V8::get_version() => Some(snapshot),
+        (Some(_), Some(v8_version)) => {
+            event!(
+                Level::WARN,
+                "rehydrating proc {} from frames, its snapshot was made by V8 {} rather than {}",
+                proc_id,
+                v8_version,
+                v8::V8::get_version()
+            );
+            None
+        }
+        (Some(_), None) => {
+            event!(
+                Level::WARN,
+                "rehydrating proc {} from frames, its snapshot was made by an unknown V8",
+                proc_id
+            );
+            None
+        }
+        (None, _) => None,
+    }
+}
+
+/// The frames of a suspended proc, outermost first.
+fn live_frames(frames: &Option<serde_json::Value>) -> Result<Vec<LiveFrame>, anyhow::Error> {
+    fn collect(
+        value: &serde_json::Value,
+        frames: &mut Vec<LiveFrame>,
+    ) -> Result<(), anyhow::Error> {
+        match value {
+            serde_json::Value::Object(obj) => match obj.get("fnhash") {
+                Some(fnhash) => {
+                    let fnhash = fnhash
+                        .as_str()
+                        .and_then(|fnhash| fnhash.parse().ok())
+                        .ok_or(anyhow!("frame with an invalid fnhash {}", fnhash))?;
+                    let pc = obj
+                        .get("$pc")
+                        .and_then(serde_json::Value::as_i64)
+                        .ok_or(anyhow!("frame of fn {} without a $pc", fnhash))?;
+                    frames.push(LiveFrame {
+                        fnhash,
+                        pc: pc as i32,
+                    });
+                }
+                None => {
+                    for value in obj.values() {
+                        collect(value, frames)?;
+                    }
+                }
+            },
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    collect(value, frames)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    let mut live = vec![];
+    if let Some(frames) = frames {
+        collect(frames, &mut live)?;
+    }
+    Ok(live)
+}
+
+/// Why a proc suspended in `frames` can't be resumed into `layouts`.
+fn frame_problems(layouts: &FnLayouts, frames: &Option<serde_json::Value>) -> Vec<String> {
+    match live_frames(frames) {
+        Result::Ok(frames) => layouts
+            .check_frames(&frames)
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        Err(e) => vec![format!("{:#}", e)],
+    }
+}
+
+/// How the step ids of retries start, followed by the failed step they first
+/// retried and the one they retry, see `DEngine::step_failed`.
+const RETRY_STEP_PREFIX: &str = "retry_";
+
+/// The failed step a retry's `step_id` first retried, `None` if it isn't a
+/// retry.
+fn retried_step(step_id: &str) -> Option<u64> {
+    let (retry_of, _) = step_id.strip_prefix(RETRY_STEP_PREFIX)?.split_once('_')?;
+    retry_of.parse().ok()
+}
+
+/// What the engine's step did besides moving its proc on.
+fn step_effects(engine: &crate::Engine) -> StepEffects<'_> {
+    StepEffects {
+        mbox_read: &engine.mbox_consumed,
+        timers_started: &engine.timers_started,
+        timers_cancelled: &engine.timers_cancelled,
+        timers_fired: &engine.timers_fired,
+        posts: &engine.posts,
+    }
+}
+
+/// What a failed step's `val` holds: the error, and where in the module's
+/// source it was thrown if it was.
+fn step_error(err: &anyhow::Error) -> serde_json::Value {
+    match err.downcast_ref::<crate::PristineRunError>() {
+        Some(e) => serde_json::json!({ "error": e.msg, "frames": e.frames }),
+        None => serde_json::json!({ "error": format!("{:#}", err) }),
+    }
+}
+
+/// Messages used by the engine itself to advance a proc (e.g. generators).
+fn is_control_msg(msg: &serde_json::Value) -> bool {
+    msg.get("$generator").is_some() || msg.get("$timer").is_some()
+}
+
 impl SharedDEngine {
     pub async fn set_p2p_channel(&self, channel: tokio::sync::mpsc::Sender<RemoteDEngineCmd>) {
         let mut p2p_channel = self.p2p_channel.write().await;
@@ -700,6 +1529,8 @@ impl SharedDEngine {
 
     fn new_inner(
         runtime_js_src: Option<fn() -> String>,
+        base_snapshot: Option<Arc<[u8]>>,
+        resume_mode: ResumeMode,
         db: Box<dyn ApeiroPersistence>,
     ) -> Result<(
         SharedDEngine,
@@ -711,12 +1542,14 @@ impl SharedDEngine {
         let instance = SharedDEngine {
             p2p_channel: RwLock::new(None),
             runtime_js_src,
+            base_snapshot,
+            resume_mode,
             db,
             locks: Arc::new(RwLock::new(HashMap::new())),
             tx: tx.clone(),
             watchers: Arc::new(RwLock::new(HashMap::new())),
             watchers_exec: Arc::new(RwLock::new(HashMap::new())),
-            proc_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            proc_subscriptions: Arc::new(RwLock::new(MatcherIndex::new())),
         };
 
         instance.init_db()?;
@@ -726,6 +1559,7 @@ impl SharedDEngine {
 
     #[instrument(skip(self))]
     fn init_db(&self) -> Result<(), anyhow::Error> {
-        self.db.init()
+        self.db.init()?;
+        self.db.timers_rearm()
     }
 }
@@ -1,27 +1,64 @@
-use std::{cell::RefCell, string::String, thread};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    string::String,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Ok, Result};
 use apeiro_internal_api::{
-    EngineStatus, ModuleNewRequest, ProcSendRequest, StackTraceFrame, StepResult,
+    EngineStatus, MboxMessage, ModuleLimits, ModuleNewRequest, ProcSendRequest, ResumeMode,
+    StackTraceFrame, StepResult, StepResultStatus,
 };
-use serde_json::Value;
+use serde_json::{json, Value};
 use tracing::{event, instrument, trace, Level};
 use v8::{ContextScope, CreateParams, HandleScope, Isolate, PromiseState};
 
 use crate::{
     dengine::DEngineCmd,
     eventloop::now_as_millis,
+    fetch::{FetchOptions, FetchRequest},
+    journal::{FetchOutcome, Journal},
+    matcher::MsgMatcher,
+    outbox::{self, OutboundPost, PostOptions},
     struct_method_to_v8, throw_exception,
     v8_helpers::{stack_trace_to_frames, v8_println, v8_struct_key},
-    v8_init, v8_str, DEngine,
+    v8_init, v8_str,
+    watchdog::{max_heap, Watchdog, DEFAULT_MAX_STEP_MS},
+    DEngine,
 };
 
 pub struct Engine {
     runtime_js_src: Option<fn() -> String>,
-    pub mbox: Box<Vec<serde_json::Value>>,
+    pub mbox: Box<Vec<MboxMessage>>,
+    /// ids of mailbox messages consumed by `$recv` during the current step
+    pub mbox_consumed: Vec<String>,
+    /// timers armed during the current step, as `(timer_id, deadline)`
+    pub timers_started: Vec<(String, u64)>,
+    pub timers_cancelled: Vec<String>,
+    /// timers whose deadline passed and that were delivered to this step
+    pub timers_fired: Vec<String>,
+    /// posts queued by `$http_post` during the current step
+    pub posts: Vec<OutboundPost>,
     proc_id: String,
-    _step_id: String,
+    step_id: String,
     pub dengine: Option<DEngine>,
+    /// what `Date.now()` returns for the whole step; stored with the step so
+    /// that it can be replayed with the same value
+    pub clock: u64,
+    /// seeds the step's `Math.random()`, see `clock`
+    pub seed: u32,
+    /// requests made by the step, replayed from an earlier run if any
+    pub journal: Journal,
+    /// the module's limits, enforced by a `Watchdog` for the whole step
+    pub limits: ModuleLimits,
+    /// when the running step's `Watchdog` terminates it
+    step_deadline: Option<Instant>,
+    /// what a proc's first step starts from, see `Engine::base_snapshot`
+    base_snapshot: Option<Arc<[u8]>>,
+    /// whether the step takes a snapshot for the next one to resume from
+    pub resume_mode: ResumeMode,
 }
 
 impl std::fmt::Debug for DEngine {
@@ -32,7 +69,8 @@ impl std::fmt::Debug for DEngine {
 
 #[derive(Default, Debug)]
 pub struct EngineInstance<'a> {
-    pub enginecode: Option<v8::Local<'a, v8::Module>>,
+    /// namespace of the engine runtime module
+    pub enginecode: Option<v8::Local<'a, v8::Object>>,
     pub usercode: Option<v8::Local<'a, v8::Module>>,
     pub frames: Option<v8::Local<'a, v8::Value>>,
     pub funcs: Option<v8::Local<'a, v8::Object>>,
@@ -44,22 +82,252 @@ impl Engine {
         proc_id: String,
         step_id: String,
         dengine: DEngine,
+    ) -> Engine {
+        Engine {
+            base_snapshot: dengine.base_snapshot(),
+            dengine: Some(dengine),
+            ..Engine::detached(engine_runtime, proc_id, step_id)
+        }
+    }
+
+    /// An engine for running a step of a proc again, see
+    /// `DEngine::proc_step_replay`. Like a detached one, nothing the step does
+    /// reaches outside of it.
+    pub fn replaying(
+        engine_runtime: Option<fn() -> String>,
+        proc_id: String,
+        step_id: String,
+        dengine: &DEngine,
+    ) -> Engine {
+        Engine {
+            base_snapshot: dengine.base_snapshot(),
+            ..Engine::detached(engine_runtime, proc_id, step_id)
+        }
+    }
+
+    /// An engine that isn't part of a `DEngine`, so its procs can't reach
+    /// anything outside of the step.
+    fn detached(
+        engine_runtime: Option<fn() -> String>,
+        proc_id: String,
+        step_id: String,
     ) -> Engine {
         v8_init();
+        let seed = step_seed(&proc_id, &step_id);
         Engine {
             runtime_js_src: engine_runtime,
             mbox: Box::new(vec![]),
+            mbox_consumed: vec![],
+            timers_started: vec![],
+            timers_cancelled: vec![],
+            timers_fired: vec![],
+            posts: vec![],
             proc_id,
-            _step_id: step_id,
-            dengine: Some(dengine),
+            step_id,
+            dengine: None,
+            clock: now_as_millis(),
+            seed,
+            journal: Journal::default(),
+            limits: ModuleLimits::default(),
+            step_deadline: None,
+            base_snapshot: None,
+            resume_mode: ResumeMode::default(),
         }
     }
 
+    /// Builds the snapshot procs start from: the global object and the
+    /// engine runtime, loaded once rather than on every proc's first step.
+    pub fn base_snapshot(engine_runtime: fn() -> String) -> Result<Vec<u8>> {
+        let mut engine = Engine::detached(Some(engine_runtime), String::new(), String::new());
+        let mut engine_instance = EngineInstance::default();
+
+        let engine_external_ref = (&engine as *const _) as *mut std::ffi::c_void;
+        let engine_instance_external_ref = (&engine_instance as *const _) as *mut std::ffi::c_void;
+        let refs = external_references(engine_external_ref, engine_instance_external_ref);
+        let mut isolate = engine.setup_isolate(Isolate::snapshot_creator(Some(refs)));
+
+        let loaded = {
+            let handle_scope = &mut HandleScope::new(&mut isolate);
+            let global = engine.global_template(
+                handle_scope,
+                engine_external_ref,
+                engine_instance_external_ref,
+            );
+            let context = v8::Context::new_from_template(handle_scope, global);
+            handle_scope.set_default_context(context);
+            let context_scope = &mut ContextScope::new(handle_scope, context);
+            let context_scope = &mut v8::TryCatch::new(context_scope);
+
+            engine.load_engine_runtime_ts(context_scope, &mut engine_instance, context);
+            if context_scope.has_caught() {
+                Err(anyhow!("failed to load the engine runtime"))
+            } else {
+                Ok(())
+            }
+        };
+
+        let snapshot = isolate
+            .create_blob(v8::FunctionCodeHandling::Keep)
+            .ok_or(anyhow!("failed to create the base snapshot"))?;
+        loaded?;
+        Ok(snapshot.to_vec())
+    }
+
     fn setup_isolate(&self, mut isolate: v8::OwnedIsolate) -> v8::OwnedIsolate {
         isolate.set_capture_stack_trace_for_uncaught_exceptions(true, 100);
         isolate
     }
 
+    /// The global object of every step: the engine's callbacks and what the
+    /// step is pinned to.
+    fn global_template<'s>(
+        &self,
+        handle_scope: &mut HandleScope<'s, ()>,
+        engine_external_ref: *mut std::ffi::c_void,
+        engine_instance_external_ref: *mut std::ffi::c_void,
+    ) -> v8::Local<'s, v8::ObjectTemplate> {
+        let engine_ref = v8::External::new(handle_scope, engine_external_ref);
+        let engine_instance_ref = v8::External::new(handle_scope, engine_instance_external_ref);
+
+        let log_callback_fn_builder = v8::FunctionTemplate::builder(log_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let mbox_fn_builder = v8::FunctionTemplate::builder(mbox_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let send_fn_builder = v8::FunctionTemplate::builder(send_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let usercode_fn_builder = v8::FunctionTemplate::builder(usercode_callback)
+            .data(engine_instance_ref.into())
+            .build(handle_scope);
+
+        let get_frames_fn_builder = v8::FunctionTemplate::builder(frames_callback)
+            .data(engine_instance_ref.into())
+            .build(handle_scope);
+
+        let get_funcs_fn_builder = v8::FunctionTemplate::builder(funcs_callback)
+            .data(engine_instance_ref.into())
+            .build(handle_scope);
+
+        let get_fn_builder = v8::FunctionTemplate::builder(get_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let pid_fn_builder = v8::FunctionTemplate::builder(pid_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let spawn_fn_builder = v8::FunctionTemplate::builder(spawn_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let http_post_fn_builder = v8::FunctionTemplate::builder(http_post_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let fetch_fn_builder = v8::FunctionTemplate::builder(fetch_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let timer_start_fn_builder = v8::FunctionTemplate::builder(timer_start_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let timer_cancel_fn_builder = v8::FunctionTemplate::builder(timer_cancel_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let timer_fired_fn_builder = v8::FunctionTemplate::builder(timer_fired_callback)
+            .data(engine_ref.into())
+            .build(handle_scope);
+
+        let global = v8::ObjectTemplate::new(handle_scope);
+        global.set_internal_field_count(1);
+        global.set(
+            v8::String::new(handle_scope, "log").unwrap().into(),
+            log_callback_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$mbox_take").unwrap().into(),
+            mbox_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$send").unwrap().into(),
+            send_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$usercode").unwrap().into(),
+            usercode_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$get_frames").unwrap().into(),
+            get_frames_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$get_funcs").unwrap().into(),
+            get_funcs_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$get").unwrap().into(),
+            get_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$pid").unwrap().into(),
+            pid_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$spawn").unwrap().into(),
+            spawn_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$fetch").unwrap().into(),
+            fetch_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$http_post").unwrap().into(),
+            http_post_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$timer_start")
+                .unwrap()
+                .into(),
+            timer_start_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$timer_cancel")
+                .unwrap()
+                .into(),
+            timer_cancel_fn_builder.into(),
+        );
+        global.set(
+            v8::String::new(handle_scope, "$timer_fired")
+                .unwrap()
+                .into(),
+            timer_fired_fn_builder.into(),
+        );
+
+        let clock = v8::Number::new(handle_scope, self.clock as f64);
+        global.set(
+            v8::String::new(handle_scope, "$step_clock").unwrap().into(),
+            clock.into(),
+        );
+        let seed = v8::Integer::new_from_unsigned(handle_scope, self.seed);
+        global.set(
+            v8::String::new(handle_scope, "$step_seed").unwrap().into(),
+            seed.into(),
+        );
+
+        let state_obj = v8::ObjectTemplate::new(handle_scope);
+        let state_obj_key = v8::String::new(handle_scope, "$state").unwrap().into();
+        global.set(state_obj_key, state_obj.into());
+
+        global
+    }
+
     pub async fn step_process(
         &mut self,
         src: String,
@@ -79,168 +347,47 @@ impl Engine {
         let engine_external_ref = (self as *const _) as *mut std::ffi::c_void;
         let engine_instance_external_ref = (&engine_instance as *const _) as *mut std::ffi::c_void;
 
-        let use_v8_snapshot = true;
-        let (mut isolate, _snapshot_existed) = if use_v8_snapshot {
-            use v8::MapFnTo;
-
-            let refs = v8::ExternalReferences::new(&[
-                v8::ExternalReference {
-                    function: log_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: mbox_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: usercode_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: send_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: funcs_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: frames_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: get_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: pid_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: spawn_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: http_post_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    function: fetch_callback.map_fn_to(),
-                },
-                v8::ExternalReference {
-                    pointer: engine_instance_external_ref,
-                },
-                v8::ExternalReference {
-                    pointer: engine_external_ref,
-                },
-            ]);
-            let refs: &'static v8::ExternalReferences = Box::leak(Box::new(refs));
+        // without a snapshot the proc is resumed from `frames` and `funcs`
+        let use_v8_snapshot = self.resume_mode == ResumeMode::Snapshot;
+        let (mut isolate, from_base_snapshot) = if use_v8_snapshot {
+            let refs = external_references(engine_external_ref, engine_instance_external_ref);
 
-            let (snapshot_creator, snapshot_existed) = match snapshot {
-                Some(snapshot) => (
+            let (snapshot_creator, from_base_snapshot) = match (snapshot, &self.base_snapshot) {
+                (Some(snapshot), _) => (
                     Isolate::snapshot_creator_from_existing_snapshot(snapshot, Some(refs)),
+                    false,
+                ),
+                (None, Some(base_snapshot)) => (
+                    Isolate::snapshot_creator_from_existing_snapshot(
+                        base_snapshot.clone(),
+                        Some(refs),
+                    ),
                     true,
                 ),
-                None => (Isolate::snapshot_creator(Some(refs)), false),
+                (None, None) => (Isolate::snapshot_creator(Some(refs)), false),
             };
-            (self.setup_isolate(snapshot_creator), snapshot_existed)
+            (self.setup_isolate(snapshot_creator), from_base_snapshot)
         } else {
-            (
-                self.setup_isolate(Isolate::new(CreateParams::default())),
-                false,
-            )
+            // the isolate's own heap limit is the module's, so that v8
+            // collects garbage harder as the step nears it. The v8 crate's
+            // snapshot creators take no `CreateParams`, there the `Watchdog`
+            // alone keeps the step within it
+            let params = CreateParams::default().heap_limits(0, max_heap(&self.limits));
+            (self.setup_isolate(Isolate::new(params)), false)
         };
 
+        let watchdog = Watchdog::arm(&mut isolate, &self.limits);
+        self.step_deadline = Some(watchdog.deadline());
+
         let new_state: Result<(StepResult, EngineStatus)> = {
             let handle_scope = &mut HandleScope::new(&mut isolate);
 
-            let engine_ref = v8::External::new(handle_scope, engine_external_ref);
-            let engine_instance_ref = v8::External::new(handle_scope, engine_instance_external_ref);
-
-            let log_callback_fn_builder = v8::FunctionTemplate::builder(log_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let mbox_fn_builder = v8::FunctionTemplate::builder(mbox_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let send_fn_builder = v8::FunctionTemplate::builder(send_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let usercode_fn_builder = v8::FunctionTemplate::builder(usercode_callback)
-                .data(engine_instance_ref.into())
-                .build(handle_scope);
-
-            let get_frames_fn_builder = v8::FunctionTemplate::builder(frames_callback)
-                .data(engine_instance_ref.into())
-                .build(handle_scope);
-
-            let get_funcs_fn_builder = v8::FunctionTemplate::builder(funcs_callback)
-                .data(engine_instance_ref.into())
-                .build(handle_scope);
-
-            let get_fn_builder = v8::FunctionTemplate::builder(get_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let pid_fn_builder = v8::FunctionTemplate::builder(pid_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let spawn_fn_builder = v8::FunctionTemplate::builder(spawn_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let http_post_fn_builder = v8::FunctionTemplate::builder(http_post_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let fetch_fn_builder = v8::FunctionTemplate::builder(fetch_callback)
-                .data(engine_ref.into())
-                .build(handle_scope);
-
-            let global = v8::ObjectTemplate::new(handle_scope);
-            global.set_internal_field_count(1);
-            global.set(
-                v8::String::new(handle_scope, "log").unwrap().into(),
-                log_callback_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$recv").unwrap().into(),
-                mbox_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$send").unwrap().into(),
-                send_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$usercode").unwrap().into(),
-                usercode_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$get_frames").unwrap().into(),
-                get_frames_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$get_funcs").unwrap().into(),
-                get_funcs_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$get").unwrap().into(),
-                get_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$pid").unwrap().into(),
-                pid_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$spawn").unwrap().into(),
-                spawn_fn_builder.into(),
+            let global = self.global_template(
+                handle_scope,
+                engine_external_ref,
+                engine_instance_external_ref,
             );
-            global.set(
-                v8::String::new(handle_scope, "fetch").unwrap().into(),
-                fetch_fn_builder.into(),
-            );
-            global.set(
-                v8::String::new(handle_scope, "$http_post").unwrap().into(),
-                http_post_fn_builder.into(),
-            );
-
-            let state_obj = v8::ObjectTemplate::new(handle_scope);
-            let state_obj_key = v8::String::new(handle_scope, "$state").unwrap().into();
-            global.set(state_obj_key, state_obj.into());
+            let state_obj_key = v8::String::new(handle_scope, "$state").unwrap();
 
             let context = v8::Context::new_from_template(handle_scope, global);
             if use_v8_snapshot {
@@ -266,7 +413,11 @@ impl Engine {
 
             let context_scope = &mut v8::TryCatch::new(context_scope);
             let new_state = (|| {
-                self.load_engine_runtime_ts(context_scope, &mut engine_instance, context);
+                if from_base_snapshot {
+                    attach_engine_runtime(context_scope, &mut engine_instance, context)?;
+                } else {
+                    self.load_engine_runtime_ts(context_scope, &mut engine_instance, context);
+                }
 
                 let usercode_module =
                     instantiate_module(context_scope, "usercode".into(), src.clone())?;
@@ -274,8 +425,11 @@ impl Engine {
 
                 event!(Level::INFO, "before kickoff");
 
-                let engine_entrypoint =
-                    get_module_default(context_scope, engine_instance.enginecode.unwrap())?;
+                let engine_entrypoint = get_namespace_fn(
+                    context_scope,
+                    engine_instance.enginecode.unwrap(),
+                    "default",
+                )?;
                 let undefined = v8::undefined(context_scope).into();
                 let js_stmt_result = engine_entrypoint
                     .call(context_scope, undefined, &[])
@@ -283,6 +437,11 @@ impl Engine {
 
                 context_scope.perform_microtask_checkpoint();
 
+                // user code is done running, what's left is winding up the step
+                if watchdog.stand_down() {
+                    return Err(anyhow!("step was terminated"));
+                }
+
                 let js_stmt_result = if js_stmt_result.is_promise() {
                     let returned_promise: v8::Local<v8::Promise> =
                         unsafe { v8::Local::cast(js_stmt_result) };
@@ -336,7 +495,7 @@ impl Engine {
                 trace!("\n\n\n\n\n{:?}\n\n\n\n\n", updated_state);
 
                 // fetch engine_status
-                let get_engine_status = get_module_fn(
+                let get_engine_status = get_namespace_fn(
                     context_scope,
                     engine_instance.enginecode.unwrap(),
                     "$get_engine_status",
@@ -413,7 +572,7 @@ impl Engine {
                     let engine_status = EngineStatus {
                         funcs: Some(new_fns),
                         frames: Some(new_frames),
-                        snapshot: None,
+                        ..Default::default()
                     };
 
                     (res_json, engine_status)
@@ -433,6 +592,9 @@ impl Engine {
             }
         };
 
+        let terminated = watchdog.disarm(&mut isolate);
+        self.step_deadline = None;
+
         let snapshot_slice = if use_v8_snapshot {
             let snapshot = isolate
                 .create_blob(v8::FunctionCodeHandling::Keep)
@@ -442,13 +604,41 @@ impl Engine {
             None
         };
 
+        if let Some(reason) = terminated {
+            event!(Level::WARN, "proc {} terminated: {}", self.proc_id, reason);
+            // nothing the step did is kept, the proc is done for
+            self.mbox_consumed.clear();
+            self.timers_started.clear();
+            self.timers_cancelled.clear();
+            self.posts.clear();
+            return Ok((
+                StepResult {
+                    status: StepResultStatus::CRASHED,
+                    val: Some(json!({ "error": reason })),
+                    suspension: None,
+                },
+                EngineStatus {
+                    clock: Some(self.clock),
+                    seed: Some(self.seed),
+                    journal: Some(serde_json::to_value(self.journal.entries())?),
+                    ..Default::default()
+                },
+            ));
+        }
+
         match new_state {
             Result::Ok((res_json, engine_status)) => Ok((
                 res_json,
                 EngineStatus {
                     frames: engine_status.frames,
                     funcs: engine_status.funcs,
+                    v8_version: snapshot_slice
+                        .as_ref()
+                        .map(|_| v8::V8::get_version().to_string()),
                     snapshot: snapshot_slice,
+                    clock: Some(self.clock),
+                    seed: Some(self.seed),
+                    journal: Some(serde_json::to_value(self.journal.entries())?),
                 },
             )),
             Err(e) => {
@@ -463,24 +653,22 @@ impl Engine {
             let engine_runtime = engine_runtime_fn();
             let enginecode_module =
                 instantiate_module(context_scope, "engine".into(), engine_runtime).unwrap();
+            let enginecode_obj = enginecode_module
+                .get_module_namespace()
+                .to_object(context_scope)
+                .unwrap();
 
-            if let Some(v8_funcs) = engine_instance.funcs {
-                let enginecode_obj = enginecode_module
-                    .get_module_namespace()
-                    .to_object(context_scope)
-                    .unwrap();
-                let fns_key = v8::String::new(context_scope, "$fns").unwrap();
-                assert!(enginecode_obj
-                    .set(context_scope, fns_key.into(), v8_funcs.into())
-                    .unwrap());
-            }
-
-            engine_instance.enginecode = Some(enginecode_module);
+            set_engine_funcs(context_scope, engine_instance, enginecode_obj);
+            engine_instance.enginecode = Some(enginecode_obj);
 
             let global = context.global(context_scope);
+            // for steps starting from a snapshot this is taken, see
+            // `attach_engine_runtime`
+            let enginecode_key = v8_str!(context_scope, "$engine");
+            global.set(context_scope, enginecode_key, enginecode_obj.into());
             export_symbols_to_global(
                 context_scope,
-                enginecode_module,
+                enginecode_obj,
                 global,
                 vec![
                     "$fn",
@@ -489,6 +677,12 @@ impl Engine {
                     "$frame_end",
                     "$isSuspendSignal",
                     "$dyn_import",
+                    "$recv",
+                    "$sleep",
+                    "$isSuspending",
+                    "$frame_unwind",
+                    "either",
+                    "fetch",
                 ],
             );
         }
@@ -540,11 +734,17 @@ impl Engine {
         let filter_def: serde_json::Value = apeiro_serde::OBJ_COUNT_DE.set(&counter, || {
             apeiro_serde::from_v8(scope, args.get(0)).unwrap()
         });
-        let is_json_schema = filter_def.as_object().unwrap().contains_key("$schema");
-        let filter = serde_json_matcher::from_json(filter_def).unwrap();
+        let filter = match MsgMatcher::new(filter_def) {
+            Result::Ok(filter) => filter,
+            Err(e) => {
+                throw_exception!(scope, e.to_string().as_str());
+                return;
+            }
+        };
         for (index, msg) in self.mbox.iter().enumerate() {
-            if is_json_schema || filter.matches(msg) {
-                let msg = self.mbox.remove(index);
+            if filter.matches(&msg.msg) {
+                let MboxMessage { id, msg } = self.mbox.remove(index);
+                self.mbox_consumed.push(id);
                 event!(
                     Level::INFO,
                     "mbox: {}: found match at index {}: {:?}",
@@ -559,20 +759,48 @@ impl Engine {
         }
 
         event!(Level::INFO, "no recv match found");
-        // no matching value found
-        let exception_obj = v8::Object::new(scope);
-        let key_str = v8_str!(scope / "apeiro_suspend");
-        let r#true = v8::Boolean::new(scope, true);
-        exception_obj.set(scope, key_str.into(), r#true.into());
-        let key_str = v8_str!(scope / "until");
-        let until_val = if args.get(0).is_null_or_undefined() {
-            r#true.into()
-        } else {
-            args.get(0)
-        };
-        exception_obj.set(scope, key_str.into(), until_val);
-        scope.throw_exception(exception_obj.into());
-        return;
+        retval.set_undefined();
+    }
+
+    #[inline]
+    #[instrument(skip(self))]
+    fn timer_start_callback(
+        &mut self,
+        scope: &mut v8::HandleScope,
+        args: v8::FunctionCallbackArguments,
+        mut retval: v8::ReturnValue,
+    ) {
+        let timeout = args.get(0).number_value(scope).unwrap_or(0.0).max(0.0) as u64;
+        let timer_id = nanoid::nanoid!();
+        self.timers_started
+            .push((timer_id.clone(), now_as_millis() + timeout));
+
+        let timer_id = v8::String::new(scope, timer_id.as_str()).unwrap();
+        retval.set(timer_id.into());
+    }
+
+    #[inline]
+    #[instrument(skip(self))]
+    fn timer_cancel_callback(
+        &mut self,
+        scope: &mut v8::HandleScope,
+        args: v8::FunctionCallbackArguments,
+        _retval: v8::ReturnValue,
+    ) {
+        let timer_id = args.get(0).to_rust_string_lossy(scope);
+        self.timers_cancelled.push(timer_id);
+    }
+
+    #[inline]
+    #[instrument(skip(self))]
+    fn timer_fired_callback(
+        &mut self,
+        scope: &mut v8::HandleScope,
+        args: v8::FunctionCallbackArguments,
+        mut retval: v8::ReturnValue,
+    ) {
+        let timer_id = args.get(0).to_rust_string_lossy(scope);
+        retval.set_bool(self.timers_fired.contains(&timer_id));
     }
 
     #[inline]
@@ -613,7 +841,9 @@ impl Engine {
             } else if proc_id.contains("$subscribe") {
                 let dengine = self.dengine.clone().unwrap();
                 tokio::task::spawn(async move {
-                    dengine.subscribe_proc_to_events(proc_id.clone(), msg).await;
+                    if let Err(err) = dengine.subscribe_proc_to_events(proc_id.clone(), msg).await {
+                        event!(Level::ERROR, "failed to subscribe {}: {:?}", proc_id, err);
+                    }
                 });
             } else {
                 tokio::task::spawn(async move {
@@ -626,7 +856,12 @@ impl Engine {
                 });
             }
         } else {
-            panic!();
+            event!(
+                Level::INFO,
+                "{} is detached, not sending to {}",
+                self.proc_id,
+                args.get(0).to_rust_string_lossy(scope)
+            );
         }
     }
 
@@ -658,7 +893,13 @@ impl Engine {
             let fn_src = function_serialized.get("src").unwrap().as_str().unwrap();
             let synthetic_src = format!("let main = {}; export default main;", fn_src);
 
-            let dengine = self.dengine.clone().unwrap();
+            let dengine = match self.dengine.clone() {
+                Some(dengine) => dengine,
+                None => {
+                    throw_exception!(scope, "can't spawn procs from a detached engine");
+                    return;
+                }
+            };
 
             let handle = tokio::runtime::Handle::current();
             let _guard = handle.enter();
@@ -667,13 +908,20 @@ impl Engine {
                 name: Some(format!("synthetic_{}", now_as_millis())),
                 singleton: None,
                 src_is_compiled: Some(true),
+                limits: Some(self.limits.clone()),
+                on_error: None,
+                retry: None,
             }))
             .unwrap();
 
             let new_module = futures::executor::block_on(dengine.module_get(new_module)).unwrap();
 
-            let res =
-                futures::executor::block_on(dengine.proc_new_compiled(new_module, None)).unwrap();
+            let res = futures::executor::block_on(dengine.proc_new_compiled(
+                new_module,
+                None,
+                Some(self.resume_mode),
+            ))
+            .unwrap();
 
             let new_function_pid = v8::String::new(scope, res.id.as_str()).unwrap();
             retval.set(new_function_pid.into());
@@ -682,6 +930,9 @@ impl Engine {
         }
     }
 
+    /// `$http_post(url, body, headers?, { idempotencyKey?, retries?, backoff? })`
+    /// queues a post to be delivered once the step is stored, and returns its
+    /// idempotency key. The outcome arrives later as a message, see `outbox`.
     #[inline]
     fn http_post_callback(
         &mut self,
@@ -689,86 +940,113 @@ impl Engine {
         args: v8::FunctionCallbackArguments,
         mut retval: v8::ReturnValue,
     ) {
-        let url = args.get(0);
-        let msg = args.get(1);
-        let headers = args.get(2);
-        if let Result::Ok(url) = v8::Local::<v8::String>::try_from(url) {
-            let msg: serde_json::Value = apeiro_serde::from_v8(scope, msg).unwrap();
-            let headers: Option<serde_json::Value> = apeiro_serde::from_v8(scope, headers).unwrap();
-
-            let mut headers = if let Some(headers) = headers {
-                let headers = headers.as_object().unwrap().clone();
-                let mut result = reqwest::header::HeaderMap::new();
-                for (k, v) in headers {
-                    let k = string_to_static_str(k);
-                    let v = v.as_str().unwrap();
-                    let v = reqwest::header::HeaderValue::from_str(v).unwrap();
-                    result.insert(k, v);
+        let url = match v8::Local::<v8::String>::try_from(args.get(0)) {
+            Result::Ok(url) => url.to_rust_string_lossy(scope),
+            Err(_) => {
+                throw_exception!(scope, "1st arg to $http_post wasn't a string");
+                return;
+            }
+        };
+        let body: serde_json::Value = match apeiro_serde::from_v8(scope, args.get(1)) {
+            Result::Ok(body) => body,
+            Err(e) => {
+                throw_exception!(scope, &format!("invalid $http_post body: {}", e));
+                return;
+            }
+        };
+        let headers: Option<BTreeMap<String, String>> =
+            match apeiro_serde::from_v8(scope, args.get(2)) {
+                Result::Ok(headers) => headers,
+                Err(e) => {
+                    throw_exception!(scope, &format!("invalid $http_post headers: {}", e));
+                    return;
                 }
-                result
-            } else {
-                reqwest::header::HeaderMap::new()
             };
-            headers.insert(
-                reqwest::header::CONTENT_TYPE,
-                "application/json".parse().unwrap(),
-            );
-
-            let url = url.to_rust_string_lossy(scope);
+        let options = args.get(3);
+        let options: PostOptions = if options.is_null_or_undefined() {
+            PostOptions::default()
+        } else {
+            match apeiro_serde::from_v8(scope, options) {
+                Result::Ok(options) => options,
+                Err(e) => {
+                    throw_exception!(scope, &format!("invalid $http_post options: {}", e));
+                    return;
+                }
+            }
+        };
 
-            tokio::task::spawn(async move {
-                let res = reqwest::Client::new()
-                    .post(url)
-                    .headers(headers)
-                    .json(&msg)
-                    .send()
-                    .await;
-
-                let _resp: serde_json::Value = res.unwrap().json().await.unwrap();
-            });
+        let id = options
+            .idempotency_key
+            .unwrap_or_else(|| format!("{}-{}-{}", self.proc_id, self.step_id, self.posts.len()));
+        let mut headers = headers.unwrap_or_default();
+        headers
+            .entry("content-type".to_string())
+            .or_insert_with(|| "application/json".to_string());
+        self.posts.push(OutboundPost {
+            id: id.clone(),
+            proc_id: self.proc_id.clone(),
+            url,
+            headers,
+            body,
+            attempts: 0,
+            max_attempts: options
+                .retries
+                .map_or(outbox::DEFAULT_MAX_ATTEMPTS, |retries| retries + 1),
+            backoff_ms: options.backoff.unwrap_or(outbox::DEFAULT_BACKOFF_MS),
+        });
 
-            let v8_true = v8::Boolean::new(scope, true);
-            retval.set(v8_true.into());
-        } else {
-            throw_exception!(scope, "invalid invocation");
-        }
+        let id = v8::String::new(scope, &id).unwrap();
+        retval.set(id.into());
     }
 
-    #[inline]
     fn fetch_callback<'a, 's>(
         &mut self,
         scope: &mut v8::HandleScope<'a>,
         args: v8::FunctionCallbackArguments<'a>,
         mut retval: v8::ReturnValue<'s>,
     ) {
-        let url = args.get(0);
+        let url = match v8::Local::<v8::String>::try_from(args.get(0)) {
+            Result::Ok(url) => url.to_rust_string_lossy(scope),
+            Err(_) => {
+                throw_exception!(scope, "1st arg to fetch wasn't a string");
+                return;
+            }
+        };
         let options = args.get(1);
-        if let Result::Ok(url) = v8::Local::<v8::String>::try_from(url) {
-            let runtime = tokio::runtime::Runtime::new().unwrap();
-            let _guard = runtime.enter();
-
-            let options: FetchConfiguration =
-                apeiro_serde::from_v8(scope, options).unwrap_or(FetchConfiguration::default());
-
-            let url = url.to_rust_string_lossy(scope);
-
-            let res = _fetch(url, options).unwrap();
-            let handle =
-                thread::spawn(move || res.blocking_recv().expect("failed to receive from fetch"));
-
-            let val_received = handle.join().expect("thread panicked");
-            trace!("val received from fetch channel: {}", val_received);
-
-            let v8_resp = apeiro_serde::to_v8(scope, &val_received).unwrap();
-            // if respond with promise:
-            // let promise_factory = v8::PromiseResolver::new(scope).unwrap();
-            // promise_factory.resolve(scope, v8_resp);
-            // retval.set(promise_factory.get_promise(scope).into());
-            // if respond without promise:
-            retval.set(v8_resp.into());
-            std::mem::forget(runtime);
+        let options: FetchOptions = if options.is_null_or_undefined() {
+            FetchOptions::default()
         } else {
-            throw_exception!(scope, "1st arg to fetch wasn't a string");
+            match apeiro_serde::from_v8(scope, options) {
+                Result::Ok(options) => options,
+                Err(e) => {
+                    throw_exception!(scope, &format!("invalid fetch options: {}", e));
+                    return;
+                }
+            }
+        };
+
+        let request = FetchRequest { url, options };
+        // a request can't outlast the step that made it
+        let timeout = match self.step_deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => Duration::from_millis(self.limits.max_step_ms.unwrap_or(DEFAULT_MAX_STEP_MS)),
+        };
+        let outcome = self.journal.fetch(request, |request| {
+            match crate::fetch::fetch_blocking(request, timeout) {
+                Result::Ok(response) => FetchOutcome::Response(response),
+                Err(e) => FetchOutcome::Error(format!("fetch to {} failed: {}", request.url, e)),
+            }
+        });
+
+        match outcome {
+            FetchOutcome::Response(response) => {
+                trace!("fetch responded with {}", response.status);
+                let v8_resp = apeiro_serde::to_v8(scope, &response).unwrap();
+                retval.set(v8_resp);
+            }
+            FetchOutcome::Error(e) => {
+                throw_exception!(scope, &e);
+            }
         }
     }
 
@@ -861,14 +1139,46 @@ fn map_exception_to_original_code(
     }
 }
 
+/// Takes the engine runtime a step's snapshot was made with, rather than
+/// loading it again.
+fn attach_engine_runtime<'s>(
+    scope: &mut v8::TryCatch<'_, HandleScope<'s>>,
+    engine_instance: &mut EngineInstance<'s>,
+    context: v8::Local<'_, v8::Context>,
+) -> Result<()> {
+    let global = context.global(scope);
+    let enginecode_key = v8_str!(scope, "$engine");
+    let enginecode_obj = global
+        .get(scope, enginecode_key)
+        .and_then(|enginecode| enginecode.to_object(scope))
+        .ok_or(anyhow!("snapshot has no engine runtime"))?;
+
+    set_engine_funcs(scope, engine_instance, enginecode_obj);
+    engine_instance.enginecode = Some(enginecode_obj);
+    Ok(())
+}
+
+fn set_engine_funcs<'s>(
+    scope: &mut v8::TryCatch<'_, HandleScope<'s>>,
+    engine_instance: &EngineInstance<'s>,
+    enginecode_obj: v8::Local<'s, v8::Object>,
+) {
+    if let Some(v8_funcs) = engine_instance.funcs {
+        let fns_key = v8::String::new(scope, "$fns").unwrap();
+        assert!(enginecode_obj
+            .set(scope, fns_key.into(), v8_funcs.into())
+            .unwrap());
+    }
+}
+
 fn export_symbols_to_global<'a>(
     scope: &mut v8::TryCatch<'a, v8::HandleScope>,
-    enginecode_module: v8::Local<v8::Module>,
+    enginecode_obj: v8::Local<v8::Object>,
     global: v8::Local<v8::Object>,
     vec: Vec<&str>,
 ) {
     for fn_name in vec {
-        let fn_func = get_module_fn(scope, enginecode_module, fn_name).unwrap();
+        let fn_func = get_namespace_fn(scope, enginecode_obj, fn_name).unwrap();
         let fn_key = v8_str!(scope, fn_name);
         global.set(scope, fn_key, fn_func.into());
     }
@@ -882,6 +1192,9 @@ struct_method_to_v8!(pid_callback -> Engine::pid_callback);
 struct_method_to_v8!(spawn_callback -> Engine::spawn_callback);
 struct_method_to_v8!(http_post_callback -> Engine::http_post_callback);
 struct_method_to_v8!(fetch_callback -> Engine::fetch_callback);
+struct_method_to_v8!(timer_start_callback -> Engine::timer_start_callback);
+struct_method_to_v8!(timer_cancel_callback -> Engine::timer_cancel_callback);
+struct_method_to_v8!(timer_fired_callback -> Engine::timer_fired_callback);
 
 fn frames_callback(
     scope: &mut v8::HandleScope,
@@ -934,12 +1247,11 @@ fn unexpected_module_resolve_callback<'a>(
     unreachable!("unexpected_module_resolve_callback")
 }
 
-pub fn get_module_fn<'a>(
+pub fn get_namespace_fn<'a>(
     scope: &mut v8::TryCatch<'a, v8::HandleScope>,
-    module: v8::Local<v8::Module>,
+    namespace_obj: v8::Local<v8::Object>,
     name: &str,
 ) -> Result<v8::Local<'a, v8::Function>, anyhow::Error> {
-    let namespace_obj = module.get_module_namespace().to_object(scope).unwrap();
     let name_v8 = v8::String::new(scope, name).unwrap();
     let exported_value = namespace_obj.get(scope, name_v8.into()).unwrap();
     if exported_value.is_function() {
@@ -950,13 +1262,6 @@ pub fn get_module_fn<'a>(
     }
 }
 
-pub fn get_module_default<'a>(
-    scope: &mut v8::TryCatch<'a, v8::HandleScope>,
-    module: v8::Local<v8::Module>,
-) -> Result<v8::Local<'a, v8::Function>, anyhow::Error> {
-    get_module_fn(scope, module, "default")
-}
-
 pub fn instantiate_module<'a>(
     scope: &mut v8::HandleScope<'a>,
     name: String,
@@ -972,7 +1277,9 @@ pub fn instantiate_module<'a>(
     let module = v8::script_compiler::compile_module(scope, script).unwrap();
     let module_instantiation = module.instantiate_module(scope, unexpected_module_resolve_callback);
     if module_instantiation == Some(true) && module.get_status() == v8::ModuleStatus::Instantiated {
-        let _module_instance = module.evaluate(scope).unwrap();
+        module
+            .evaluate(scope)
+            .ok_or(anyhow!("module evaluation was terminated"))?;
         if module.get_status() == v8::ModuleStatus::Evaluated {
             Ok(module)
         } else {
@@ -986,65 +1293,79 @@ pub fn instantiate_module<'a>(
     }
 }
 
-fn string_to_static_str(s: String) -> &'static str {
-    Box::leak(s.into_boxed_str())
-}
-
-#[derive(serde::Serialize, serde::Deserialize, Default)]
-struct FetchConfiguration {
-    method: Option<String>,
-    headers: Option<serde_json::Value>,
-    body: Option<String>,
-}
-
-fn _fetch(
-    url: String,
-    options: FetchConfiguration,
-) -> Result<tokio::sync::oneshot::Receiver<serde_json::Value>> {
-    let (sender, receiver) = tokio::sync::oneshot::channel::<serde_json::Value>();
-
-    trace!("before spawn");
-    tokio::task::spawn(async move {
-        trace!("spawned start");
-        let _headers = if let Some(headers) = options.headers {
-            let headers = headers.as_object().unwrap();
-            let mut result = reqwest::header::HeaderMap::new();
-            for (k, v) in headers {
-                let k = string_to_static_str(k.clone());
-                let v = v.as_str().unwrap();
-                let v = reqwest::header::HeaderValue::from_str(v).unwrap();
-                result.insert(k, v);
-            }
-            result
-        } else {
-            reqwest::header::HeaderMap::new()
-        };
-
-        let client = reqwest::Client::new();
-
-        let mut req = if options.method == Some("POST".to_string()) {
-            client.post(url)
-        } else {
-            client.get(url)
-        };
-
-        req = req.headers(_headers);
-
-        if let Some(body) = options.body {
-            req = req.body(body);
-        };
-
-        trace!("request sending");
-        let res = req.send().await.unwrap();
-        trace!("request sent");
-
-        let resp: serde_json::Value = res.json().await.unwrap();
-        trace!("request completed {}", resp);
-
-        sender.send(resp).unwrap();
+/// Gives every step of every proc its own random stream, reproducible from
+/// the ids alone. FNV-1a rather than `DefaultHasher`, whose output may change
+/// between Rust releases.
+fn step_seed(proc_id: &str, step_id: &str) -> u32 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    // the separator keeps ("ab", "c") and ("a", "bc") apart
+    let bytes = proc_id.bytes().chain([0xff]).chain(step_id.bytes());
+    let hash = bytes.fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
     });
+    (hash ^ (hash >> 32)) as u32
+}
 
-    trace!("after spawn");
-
-    Ok(receiver)
+/// Everything v8 needs to be told about to serialize a snapshot and to
+/// deserialize one, in an order that must stay the same between the two: the
+/// engine's callbacks, and where its `Engine` and `EngineInstance` are.
+fn external_references(
+    engine_external_ref: *mut std::ffi::c_void,
+    engine_instance_external_ref: *mut std::ffi::c_void,
+) -> &'static v8::ExternalReferences {
+    use v8::MapFnTo;
+
+    let refs = v8::ExternalReferences::new(&[
+        v8::ExternalReference {
+            function: log_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: mbox_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: usercode_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: send_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: funcs_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: frames_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: get_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: pid_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: spawn_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: http_post_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: fetch_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: timer_start_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: timer_cancel_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: timer_fired_callback.map_fn_to(),
+        },
+        v8::ExternalReference {
+            pointer: engine_instance_external_ref,
+        },
+        v8::ExternalReference {
+            pointer: engine_external_ref,
+        },
+    ]);
+    Box::leak(Box::new(refs))
 }
@@ -81,6 +81,59 @@ function $reanimate_funcs(inp) {
 	}
 	return inp;
 }
+// ## Time & Randomness
+
+/**
+ * Small seedable PRNG (mulberry32), good enough for user code and cheap to
+ * restore since its whole state is the seed.
+ */
+function $seeded_random(seed: number): () => number {
+	let state = seed >>> 0;
+	return () => {
+		state = (state + 0x6d2b79f5) >>> 0;
+		let t = state;
+		t = Math.imul(t ^ (t >>> 15), t | 1);
+		t ^= t + Math.imul(t ^ (t >>> 7), t | 61);
+		return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+	};
+}
+
+/** What `Date.now()` returns, see `$pin_clock_and_random`. */
+let $pinned_clock = 0;
+
+/**
+ * `Date.now()` and `new Date()` read `$pinned_clock` rather than the system
+ * clock. The runtime is evaluated again on top of a proc's own snapshot,
+ * where `Date` is the proxy of an earlier evaluation, reading that one's
+ * `$pinned_clock`: the real `Date` is kept aside and proxied afresh.
+ */
+(function $install_pinned_date() {
+	const realDateKey = Symbol.for('apeiro.RealDate');
+	if (!(realDateKey in globalThis)) {
+		(globalThis as any)[realDateKey] = Date;
+	}
+	const RealDate: DateConstructor = (globalThis as any)[realDateKey];
+	RealDate.now = () => $pinned_clock;
+	globalThis.Date = new Proxy(RealDate, {
+		construct(target, args, newTarget) {
+			return Reflect.construct(target, args.length > 0 ? args : [$pinned_clock], newTarget);
+		},
+		apply(target) {
+			return new target($pinned_clock).toString();
+		},
+	});
+})();
+
+/**
+ * Pins `Date.now()`, `new Date()` and `Math.random()` to the clock and seed
+ * the engine recorded for this step, so that replaying the step sees the
+ * very same values. Called at the start of every step.
+ */
+function $pin_clock_and_random(clock: number, seed: number) {
+	$pinned_clock = clock;
+	Math.random = $seeded_random(seed);
+}
+
 // ## Frames
 
 type FnDeclId = string;
@@ -89,6 +142,8 @@ interface Frame {
 	$pc: number;
 	scope?: Scope;
 	fnhash: FnDeclId;
+	$timer?: Timer;
+	$either?: EitherState;
 }
 
 class ApeiroEngineError extends Error {
@@ -114,6 +169,17 @@ export function $frame_end(dead_child: Frame) {
 	$frames.pop();
 }
 
+/**
+ * Drops the frames left above `live` by blocks that were exited by an
+ * exception rather than by running to their end.
+ */
+export function $frame_unwind(live: Frame) {
+	while ($frames.length > 0 && $frames[$frames.length - 1] !== live) {
+		const dead = $frames.pop()!;
+		dead.scope = undefined;
+	}
+}
+
 var current_frame = 0;
 
 export function $new_frame(fnhash, last_fn_hash) {
@@ -170,10 +236,285 @@ export function $isSuspendSignal(e: any): e is ApeiroSignal {
 	return e instanceof ApeiroSignal || e.apeiro_suspend === true;
 }
 
-function $suspend(until: Record<string, any>) {
+// set once a suspension signal starts unwinding the stack, so that `finally`
+// blocks (see `$isSuspending`) don't mistake it for the proc finishing
+let $suspending = false;
+
+function $suspend(until: Record<string, any>): never {
+	$suspending = true;
 	throw new ApeiroSignal(until);
 }
 
+/**
+ * Whether the current step is being unwound by a suspension. The compiler
+ * guards `finally` bodies with it, so they only run once the proc really
+ * leaves the `try`, be it by completing or by a genuine error.
+ */
+export function $isSuspending(): boolean {
+	return $suspending;
+}
+
+// ## Receive & Timers
+
+interface Timer {
+	id: string;
+	$pc: number;
+}
+
+/**
+ * Returns the timer armed by the innermost frame for the statement it is
+ * currently executing, arming a new one if `timeout` is given. Timers live on
+ * the frame so that the same wait is resumed (not restarted) on later steps.
+ */
+function $frame_timer(frame: Frame | undefined, timeout?: number): Timer | undefined {
+	if (!frame) {
+		if (timeout !== undefined) {
+			throw new ApeiroEngineError("timers can only be used from within a proc");
+		}
+		return undefined;
+	}
+	let timer: Timer | undefined = frame.$timer;
+	if (timer && timer.$pc !== frame.$pc) {
+		// left over from an earlier wait of this frame
+		$timer_cancel(timer.id);
+		delete frame.$timer;
+		timer = undefined;
+	}
+	if (!timer && timeout !== undefined) {
+		timer = { id: $timer_start(timeout), $pc: frame.$pc };
+		frame.$timer = timer;
+	}
+	return timer;
+}
+
+function $frame_timer_clear(frame: Frame | undefined) {
+	if (frame?.$timer) {
+		$timer_cancel(frame.$timer.id);
+		delete frame.$timer;
+	}
+}
+
+export function $recv(matcher?: Record<string, any>, options?: { timeout?: number }) {
+	if ($describing_alternative) {
+		return $describe_alternative(options?.timeout !== undefined
+			? { $recv: matcher ?? true, $timeout: options.timeout }
+			: { $recv: matcher ?? true });
+	}
+	const frame = $frames[$frames.length - 1];
+	const timer = $frame_timer(frame, options?.timeout);
+
+	const msg = $mbox_take(matcher ?? {});
+	if (msg !== undefined) {
+		$frame_timer_clear(frame);
+		return msg;
+	}
+
+	if (timer) {
+		if ($timer_fired(timer.id)) {
+			$frame_timer_clear(frame);
+			return { $timeout: true };
+		}
+		$suspend({ $recv: matcher ?? true, $timeout: options!.timeout });
+	}
+	$suspend(matcher ?? true);
+}
+
+export function $sleep(ms: number) {
+	if ($describing_alternative) {
+		return $describe_alternative({ $sleep: ms });
+	}
+	const frame = $frames[$frames.length - 1];
+	const timer = $frame_timer(frame, ms)!;
+
+	if ($timer_fired(timer.id)) {
+		$frame_timer_clear(frame);
+		return;
+	}
+	$suspend({ $sleep: ms });
+}
+
+// ## Either
+
+/**
+ * One of the waits an `either` is suspended on. `$recv` alternatives may also
+ * carry a `$timeout`, in which case the branch gets `{ $timeout: true }`
+ * once it expires, as with a plain `$recv`.
+ */
+interface Alternative {
+	$recv?: Record<string, any> | true;
+	$timeout?: number;
+	$sleep?: number;
+}
+
+/**
+ * The state of an `either` awaited by a frame. Once a branch is picked its
+ * handler may suspend in turn, so the choice is kept on the frame for the
+ * handler to be resumed rather than the alternatives to be raced again.
+ */
+interface EitherState {
+	$pc: number;
+	timers: (string | null)[];
+	chosen?: { idx: number; val: any };
+}
+
+const SYMBOL_ALTERNATIVE = "apeiro:either:alternative";
+
+// while set, `$recv` and `$sleep` describe the wait instead of performing it
+let $describing_alternative = false;
+
+function $describe_alternative(alternative: Alternative) {
+	return { [SYMBOL_ALTERNATIVE]: alternative };
+}
+
+/**
+ * Runs the spec closure of an `either` branch (the compiler wraps specs into
+ * closures, see `either_param_to_closure`) to find out what it waits on. A
+ * plain object is taken as a `$recv` matcher.
+ */
+function $alternative_of(spec: () => any): Alternative {
+	$describing_alternative = true;
+	let described;
+	try {
+		described = spec();
+	} finally {
+		$describing_alternative = false;
+	}
+	if (described?.[SYMBOL_ALTERNATIVE]) {
+		return described[SYMBOL_ALTERNATIVE];
+	}
+	if (described !== null && typeof described === "object") {
+		return { $recv: described };
+	}
+	throw new ApeiroEngineError("either alternatives must be a $recv, a $sleep or a matcher");
+}
+
+function $either_state_clear(frame: Frame) {
+	for (const id of frame.$either?.timers ?? []) {
+		if (id !== null) {
+			$timer_cancel(id);
+		}
+	}
+	delete frame.$either;
+}
+
+/**
+ * Waits on whichever of several `$recv`s and `$sleep`s comes first and
+ * returns what the handler of its branch returns:
+ *
+ *     either([
+ *         [$recv({ type: "paid" }), (msg) => msg],
+ *         [$sleep(4 * 60 * 60 * 1000), () => null],
+ *     ]);
+ *
+ * Messages take precedence over expired timers, and earlier branches over
+ * later ones. While waiting, the proc's suspension lists every alternative
+ * under `$either`.
+ */
+export function either(branches: [() => any, (val?: any) => any][]) {
+	// while resuming a handler its frames are already restored above the
+	// caller's, which is the one last replayed
+	const frame = $frames[Math.min(current_frame, $frames.length) - 1];
+	if (!frame) {
+		throw new ApeiroEngineError("either can only be used from within a proc");
+	}
+	if (frame.$either && frame.$either.$pc !== frame.$pc) {
+		// left over from an earlier either of this frame
+		$either_state_clear(frame);
+	}
+
+	let state = frame.$either;
+	if (!state?.chosen) {
+		const alternatives = branches.map(([spec]) => $alternative_of(spec));
+		if (!state) {
+			state = {
+				$pc: frame.$pc,
+				timers: alternatives.map((alternative) => {
+					const timeout = alternative.$sleep ?? alternative.$timeout;
+					return timeout !== undefined ? $timer_start(timeout) : null;
+				}),
+			};
+			frame.$either = state;
+		}
+
+		let chosen: { idx: number; val: any } | undefined;
+		for (let idx = 0; idx < alternatives.length && !chosen; idx++) {
+			const matcher = alternatives[idx].$recv;
+			if (matcher !== undefined) {
+				const msg = $mbox_take(matcher === true ? {} : matcher);
+				if (msg !== undefined) {
+					chosen = { idx, val: msg };
+				}
+			}
+		}
+		for (let idx = 0; idx < alternatives.length && !chosen; idx++) {
+			const timer = state.timers[idx];
+			if (timer !== null && $timer_fired(timer)) {
+				chosen = {
+					idx,
+					val: alternatives[idx].$sleep !== undefined ? undefined : { $timeout: true },
+				};
+			}
+		}
+
+		if (!chosen) {
+			$suspend({ $either: alternatives });
+		}
+		$either_state_clear(frame);
+		state = { $pc: frame.$pc, timers: [], chosen };
+		frame.$either = state;
+	}
+
+	const { idx, val } = state.chosen!;
+	const result = branches[idx][1](val);
+	delete frame.$either;
+	return result;
+}
+
+// ## Fetch
+
+interface FetchResponse {
+	url: string;
+	status: number;
+	statusText: string;
+	headers: Record<string, string>;
+	body: string;
+}
+
+/**
+ * `fetch` as user code knows it, on top of the engine's `$fetch`. The request
+ * completes before `fetch` returns, so neither the response nor its body
+ * need to be awaited (though they can be). Network errors are thrown.
+ */
+export function fetch(url: string, options?: { method?: string; headers?: Record<string, string>; body?: string }) {
+	const res: FetchResponse = $fetch(url, options);
+	const headers: Record<string, string> = {};
+	for (const name of Object.keys(res.headers)) {
+		headers[name.toLowerCase()] = res.headers[name];
+	}
+	return {
+		url: res.url,
+		status: res.status,
+		statusText: res.statusText,
+		ok: res.status >= 200 && res.status < 300,
+		headers: {
+			get(name: string) {
+				return this.raw[name.toLowerCase()] ?? null;
+			},
+			has(name: string) {
+				return name.toLowerCase() in this.raw;
+			},
+			raw: headers,
+		},
+		body: res.body,
+		text() {
+			return this.body;
+		},
+		json() {
+			return JSON.parse(this.body);
+		},
+	};
+}
+
 // ## Engine Entrypoint
 
 interface SuspendStepResult {
@@ -225,8 +566,10 @@ export function $get_engine_status(): {
 }
 
 export default async function $step(): Promise<StepResult> {
+	$pin_clock_and_random(globalThis.$step_clock, globalThis.$step_seed);
 	let fn = $usercode().default;
 	current_frame = 0;
+	$suspending = false;
 	if (globalThis.$frames_snapshot_store === undefined) {
 		$frames = $get_frames();
 		$fns = $reanimate_funcs($get_funcs());
@@ -291,7 +634,7 @@ export default async function $step(): Promise<StepResult> {
 export function $dyn_import(spec) {
 	if (spec === "apeiro://$") {
 		const input = (spec) => {
-			throw new ApeiroSignal({
+			$suspend({
 				until_input: spec,
 			});
 		};
@@ -60,19 +60,21 @@ impl PluginInstance for ClockProcessor {
                         now
                     );
 
+                    // a tick unless the subscription asked for something
+                    // else, e.g. a failed step's message to retry
+                    let msg = obj_subscription
+                        .get("msg")
+                        .cloned()
+                        .unwrap_or_else(|| serde_json::json!({ "type": "$tick", "tick": now }));
+                    // a retry is sent as the step it asked for
+                    let step_id = obj_subscription
+                        .get("step_id")
+                        .and_then(serde_json::Value::as_str)
+                        .map(String::from);
                     let dengine = dengine.clone();
                     tokio::task::spawn(async move {
                         dengine
-                            .proc_send(
-                                target_proc_id,
-                                None,
-                                ProcSendRequest {
-                                    msg: serde_json::json!({
-                                        "type": "$tick",
-                                        "tick": now,
-                                    }),
-                                },
-                            )
+                            .proc_send(target_proc_id, step_id, ProcSendRequest { msg })
                             .await
                             .unwrap();
                     });
@@ -102,10 +104,17 @@ impl PluginInstance for ClockProcessor {
             serde_json::to_value::<Vec<serde_json::Value>>(vec![]).unwrap();
         let mut state = storage.get().unwrap_or(default_val);
         let state = state.as_array_mut().unwrap();
-        state.push(serde_json::json!({
+        let mut subscription = serde_json::json!({
             "target_pid": target_proc_id,
             "time": now_as_millis() + wait_time,
-        }));
+        });
+        if let Some(msg) = msg_val.get("msg") {
+            subscription["msg"] = msg.clone();
+        }
+        if let Some(step_id) = msg_val.get("step_id") {
+            subscription["step_id"] = step_id.clone();
+        }
+        state.push(subscription);
         storage.set(serde_json::to_value(state).unwrap()).unwrap();
 
         Ok(())
@@ -138,27 +147,17 @@ impl EventLoop {
                         };
                         clock_plugin.tick(dengine, Box::new(storage)).unwrap();
                     }
-                    let dengine = self.dengine.clone();
-                    let subscriptions = dengine.get_all_subscriptions().await;
-                    for (proc_id, subscription) in subscriptions {
-                        if let Some(subscription) = subscription.as_object() {
-                            if let Some(serde_json::Value::Number(time)) = subscription.get("$time")
-                            {
-                                let time = time.as_u64().unwrap();
-                                match std::time::SystemTime::now()
-                                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                                {
-                                    Result::Ok(n) => {
-                                        if n.as_millis() >= time.into() {
-                                            trace!("triggering {} because {}", proc_id, time);
-                                        }
-                                    }
-                                    Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-                                }
+                    {
+                        let dengine = self.dengine.clone();
+                        tokio::task::spawn(async move {
+                            if let Err(err) = dengine.fire_due_timers().await {
+                                event!(Level::ERROR, "failed to fire timers: {:?}", err);
                             }
-                        }
+                            if let Err(err) = dengine.deliver_due_posts().await {
+                                event!(Level::ERROR, "failed to deliver posts: {:?}", err);
+                            }
+                        });
                     }
-                    // TODO
                 }
                 DEngineCmd::Broadcast(proc_id, exec_id, msg) => {
                     let dengine = self.dengine.clone();
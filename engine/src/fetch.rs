@@ -0,0 +1,116 @@
+use std::{collections::BTreeMap, sync::OnceLock, time::Duration};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::RuntimeFlavor;
+
+/// A request as made by `fetch(url, options)`, minus the url.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FetchOptions {
+    pub method: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchRequest {
+    pub url: String,
+    #[serde(flatten)]
+    pub options: FetchOptions,
+}
+
+/// Everything user code can read off a response. The body is kept as text,
+/// which is what both `text()` and `json()` are built on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchResponse {
+    pub url: String,
+    pub status: u16,
+    #[serde(rename = "statusText")]
+    pub status_text: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+/// Requests are made from v8 callbacks, which can't await, on a runtime of
+/// their own so that blocking on them doesn't stall the one driving procs.
+fn http_runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("apeiro-fetch")
+            .enable_all()
+            .build()
+            .expect("failed to start the fetch runtime")
+    })
+}
+
+/// Performs `request`, blocking until the whole body has been received, for
+/// at most `timeout`. Only failing to get a response is an error, a non-2xx
+/// status isn't.
+///
+/// The step waits on its v8 thread, there is nothing else it could run
+/// meanwhile. On a multi-threaded runtime the thread's other tasks are
+/// handed off while it waits, and `timeout` keeps a request that never
+/// completes from holding the thread past the step's own limit.
+pub fn fetch_blocking(request: &FetchRequest, timeout: Duration) -> Result<FetchResponse> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let request = request.clone();
+    http_runtime().spawn(async move {
+        let response = tokio::time::timeout(timeout, fetch(request))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out after {}ms", timeout.as_millis())));
+        let _ = sender.send(response);
+    });
+
+    let wait = || {
+        receiver
+            .recv()
+            .map_err(|_| anyhow!("fetch was interrupted"))?
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(wait)
+        }
+        _ => wait(),
+    }
+}
+
+async fn fetch(request: FetchRequest) -> Result<FetchResponse> {
+    let method = request.options.method.as_deref().unwrap_or("GET");
+    let method = reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+        .map_err(|_| anyhow!("invalid method {}", method))?;
+
+    let mut req = reqwest::Client::new().request(method, &request.url);
+    for (name, value) in request.options.headers.iter().flatten() {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    if let Some(body) = request.options.body {
+        req = req.body(body);
+    }
+
+    let res = req.send().await?;
+    let url = res.url().to_string();
+    let status = res.status();
+    // repeated headers are combined, as `Headers.get` does
+    let mut headers: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in res.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        headers
+            .entry(name.to_string())
+            .and_modify(|combined| {
+                combined.push_str(", ");
+                combined.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+    let body = res.text().await?;
+
+    Ok(FetchResponse {
+        url,
+        status: status.as_u16(),
+        status_text: status.canonical_reason().unwrap_or("").to_string(),
+        headers,
+        body,
+    })
+}
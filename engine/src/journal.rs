@@ -0,0 +1,145 @@
+//! What a step did to the outside world, in order. The journal is stored with
+//! the step, so that running the step again can be handed what happened the
+//! first time instead of repeating it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::fetch::{FetchRequest, FetchResponse};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Fetch {
+        request: FetchRequest,
+        #[serde(flatten)]
+        outcome: FetchOutcome,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchOutcome {
+    Response(FetchResponse),
+    Error(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Journal {
+    recorded: Vec<JournalEntry>,
+    /// entries of an earlier run of the same step, consumed in order
+    replayed: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// A journal for running a step again, given what the step's earlier run
+    /// recorded.
+    pub fn replaying(entries: Vec<JournalEntry>) -> Journal {
+        Journal {
+            recorded: vec![],
+            replayed: entries,
+        }
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.recorded
+    }
+
+    /// Returns the recorded outcome if the earlier run made the same request
+    /// at this point, otherwise performs it. Once the step diverges from the
+    /// earlier run, everything after is performed for real.
+    pub fn fetch(
+        &mut self,
+        request: FetchRequest,
+        perform: impl FnOnce(&FetchRequest) -> FetchOutcome,
+    ) -> FetchOutcome {
+        let replayed = match self.replayed.get(self.recorded.len()) {
+            Some(JournalEntry::Fetch {
+                request: recorded,
+                outcome,
+            }) if *recorded == request => Some(outcome.clone()),
+            _ => None,
+        };
+        let outcome = match replayed {
+            Some(outcome) => outcome,
+            None => {
+                self.replayed.clear();
+                perform(&request)
+            }
+        };
+
+        self.recorded.push(JournalEntry::Fetch {
+            request,
+            outcome: outcome.clone(),
+        });
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::FetchOptions;
+
+    fn request(url: &str) -> FetchRequest {
+        FetchRequest {
+            url: url.to_string(),
+            options: FetchOptions::default(),
+        }
+    }
+
+    fn response(body: &str) -> FetchOutcome {
+        FetchOutcome::Response(FetchResponse {
+            url: "https://example.com/".to_string(),
+            status: 200,
+            status_text: "OK".to_string(),
+            headers: Default::default(),
+            body: body.to_string(),
+        })
+    }
+
+    #[test]
+    pub fn test_replay_makes_no_request() {
+        let mut first = Journal::default();
+        let a = first.fetch(request("https://example.com/a"), |_| response("a"));
+        let b = first.fetch(request("https://example.com/b"), |_| {
+            FetchOutcome::Error("unreachable".to_string())
+        });
+
+        // as stored with the step
+        let stored = serde_json::to_value(first.entries()).unwrap();
+        let mut replay = Journal::replaying(serde_json::from_value(stored).unwrap());
+        let no_request = |request: &FetchRequest| -> FetchOutcome {
+            panic!("{} was requested again", request.url)
+        };
+        assert_eq!(
+            replay.fetch(request("https://example.com/a"), no_request),
+            a
+        );
+        assert_eq!(
+            replay.fetch(request("https://example.com/b"), no_request),
+            b
+        );
+        assert_eq!(replay.entries(), first.entries());
+    }
+
+    #[test]
+    pub fn test_replay_diverges() {
+        let mut first = Journal::default();
+        first.fetch(request("https://example.com/a"), |_| response("a"));
+        first.fetch(request("https://example.com/b"), |_| response("b"));
+
+        let mut replay = Journal::replaying(first.entries().to_vec());
+        let mut requested = vec![];
+        let mut perform = |request: &FetchRequest| {
+            requested.push(request.url.clone());
+            response("again")
+        };
+        replay.fetch(request("https://example.com/c"), &mut perform);
+        // everything after the step diverged is requested for real
+        replay.fetch(request("https://example.com/b"), &mut perform);
+        assert_eq!(
+            requested,
+            vec!["https://example.com/c", "https://example.com/b"]
+        );
+    }
+}
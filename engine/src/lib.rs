@@ -6,11 +6,17 @@ pub mod db_sqlite;
 pub mod dengine;
 mod engine;
 mod eventloop;
+mod fetch;
+pub mod journal;
+mod matcher;
+pub mod outbox;
 pub mod p2prpc;
 pub mod plugins;
+mod snapshot_store;
 mod v8_helpers;
+mod watchdog;
 
-use std::sync::Once;
+use std::sync::{Once, OnceLock};
 
 pub use apeiro_compiler::{apeiro_bundle_and_compile, apeiro_compile};
 pub use apeiro_internal_api::{ProcSendRequest, StepResult, StepResultStatus};
@@ -38,7 +44,14 @@ pub(crate) fn v8_init() {
     });
 }
 
+/// The compiled engine runtime. It only depends on the source it's built
+/// from, so it's compiled once.
 pub fn get_engine_runtime() -> String {
+    static COMPILED: OnceLock<String> = OnceLock::new();
+    COMPILED.get_or_init(compile_engine_runtime).clone()
+}
+
+pub fn compile_engine_runtime() -> String {
     let code = include_str!("engine_runtime.ts");
     // let code =
     // std::fs::read_to_string(std::path::Path::new("engine/src/engine_runtime.ts")).unwrap();
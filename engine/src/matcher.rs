@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use serde_json_matcher::{Explanation, ObjMatcher};
+
+/// What a `$recv` is waiting for: either a JSON Schema document (recognized
+/// by its `$schema` key) or a Mongo-style query. `true` accepts any message.
+pub(crate) enum MsgMatcher {
+    Schema(JSONSchema),
+    Query(ObjMatcher),
+}
+
+impl MsgMatcher {
+    pub(crate) fn new(def: Value) -> Result<MsgMatcher> {
+        if def == Value::Bool(true) {
+            Ok(MsgMatcher::Query(serde_json_matcher::from_json(
+                Value::Object(Default::default()),
+            )?))
+        } else if def.get("$schema").is_some() {
+            let schema = JSONSchema::compile(&def)
+                .map_err(|e| anyhow!("invalid JSON Schema in $recv: {}", e))?;
+            Ok(MsgMatcher::Schema(schema))
+        } else {
+            Ok(MsgMatcher::Query(serde_json_matcher::from_json(def)?))
+        }
+    }
+
+    pub(crate) fn matches(&self, msg: &Value) -> bool {
+        match self {
+            MsgMatcher::Schema(schema) => schema.is_valid(msg),
+            MsgMatcher::Query(query) => query.matches(msg),
+        }
+    }
+
+    /// Reasons why `msg` is rejected by a schema matcher, empty if it validates.
+    /// Queries have no notion of validation errors and always return none.
+    pub(crate) fn validation_errors(&self, msg: &Value) -> Vec<String> {
+        match self {
+            MsgMatcher::Schema(schema) => match schema.validate(msg) {
+                Ok(()) => vec![],
+                Err(errors) => errors
+                    .map(|e| {
+                        let path = e.instance_path.to_string();
+                        if path.is_empty() {
+                            e.to_string()
+                        } else {
+                            format!("{}: {}", path, e)
+                        }
+                    })
+                    .collect(),
+            },
+            MsgMatcher::Query(_) => vec![],
+        }
+    }
+
+    /// Clause-by-clause breakdown of a query matcher, see
+    /// `validation_errors` for schemas.
+    pub(crate) fn explain(&self, msg: &Value) -> Option<Explanation> {
+        match self {
+            MsgMatcher::Schema(_) => None,
+            MsgMatcher::Query(query) => Some(query.explain(msg)),
+        }
+    }
+}
+
+/// Extracts the matcher of a pending `$recv` from a proc's suspension, if
+/// the proc is suspended on one. An `either` waiting on several `$recv`s
+/// yields a query accepting what any of them would.
+pub(crate) fn recv_matcher_of(suspension: &Value) -> Option<Value> {
+    if let Some(matcher) = suspension.get("$recv") {
+        return Some(matcher.clone());
+    }
+    // a bare `$recv()`
+    if suspension == &Value::Bool(true) {
+        return Some(suspension.clone());
+    }
+    if let Some(Value::Array(alternatives)) = suspension.get("$either") {
+        return either_matcher_of(alternatives);
+    }
+
+    let is_other_wait = ["$sleep", "$generator", "$subscribe"]
+        .iter()
+        .any(|key| suspension.get(key).is_some());
+    if suspension.is_object() && !is_other_wait {
+        Some(suspension.clone())
+    } else {
+        None
+    }
+}
+
+fn either_matcher_of(alternatives: &[Value]) -> Option<Value> {
+    let matchers: Vec<&Value> = alternatives
+        .iter()
+        .filter_map(|alternative| alternative.get("$recv"))
+        .collect();
+    match matchers.as_slice() {
+        [] => None,
+        [matcher] => Some((*matcher).clone()),
+        _ if matchers.iter().any(|m| **m == Value::Bool(true)) => Some(Value::Bool(true)),
+        // schemas can't be combined into a query
+        _ if matchers.iter().any(|m| m.get("$schema").is_some()) => None,
+        _ => Some(serde_json::json!({ "$or": matchers })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_schema() {
+        let matcher = MsgMatcher::new(json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": { "amount": { "type": "number", "minimum": 0 } },
+            "required": ["amount"]
+        }))
+        .unwrap();
+        assert!(matcher.matches(&json!({"amount": 10})));
+        assert!(matcher.validation_errors(&json!({"amount": 10})).is_empty());
+
+        assert!(!matcher.matches(&json!({"amount": -1})));
+        assert!(!matcher.matches(&json!({"amount": "10"})));
+        assert!(!matcher.matches(&json!({"other": 1})));
+        let errors = matcher.validation_errors(&json!({"amount": "10"}));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("/amount: "), "{}", errors[0]);
+        assert!(matcher.explain(&json!({"amount": "10"})).is_none());
+    }
+
+    #[test]
+    pub fn test_invalid_schema() {
+        let matcher = MsgMatcher::new(json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "not a type"
+        }));
+        assert!(matcher.is_err());
+    }
+
+    #[test]
+    pub fn test_query() {
+        let matcher = MsgMatcher::new(json!({"type": "order", "amount": {"$gt": 5}})).unwrap();
+        assert!(matcher.matches(&json!({"type": "order", "amount": 10})));
+        assert!(!matcher.matches(&json!({"type": "order", "amount": 1})));
+        assert!(!matcher.matches(&json!({"type": "refund", "amount": 10})));
+        // queries don't validate
+        assert!(matcher
+            .validation_errors(&json!({"type": "refund"}))
+            .is_empty());
+        assert!(matcher.explain(&json!({"type": "refund"})).is_some());
+    }
+
+    #[test]
+    pub fn test_any() {
+        let matcher = MsgMatcher::new(json!(true)).unwrap();
+        assert!(matcher.matches(&json!({"anything": 1})));
+        assert!(matcher.matches(&json!({})));
+    }
+}
@@ -0,0 +1,120 @@
+//! Outbound posts made with `$http_post`. They are stored with the step that
+//! made them and delivered in the background, retrying with backoff, and the
+//! outcome is sent to the proc as a message it can `$recv`:
+//!
+//! ```text
+//! { "$http_post": key, "ok": true, "status": 200, "body": ..., "attempts": 1 }
+//! { "$http_post": key, "ok": false, "error": "...", "attempts": 5 }
+//! ```
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+pub const DEFAULT_BACKOFF_MS: u64 = 1_000;
+const MAX_BACKOFF_MS: u64 = 5 * 60 * 1_000;
+/// How long a delivery attempt may take before the post is considered due
+/// again, e.g. because the daemon died while it was in flight.
+pub const ATTEMPT_LEASE_MS: u64 = 60 * 1_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboundPost {
+    /// Idempotency key, sent along as the `Idempotency-Key` header. A post
+    /// with a key that is already queued is dropped.
+    pub id: String,
+    pub proc_id: String,
+    pub url: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: Value,
+    /// attempts made so far
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// delay before the first retry, doubled for every retry after it
+    pub backoff_ms: u64,
+}
+
+/// The options `$http_post` takes as its 4th argument.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostOptions {
+    pub idempotency_key: Option<String>,
+    pub retries: Option<u32>,
+    pub backoff: Option<u64>,
+}
+
+pub enum Delivery {
+    /// No (more) attempts to make, the message is for the proc.
+    Done(Value),
+    /// Try again no earlier than the given time.
+    Retry(u64),
+}
+
+impl OutboundPost {
+    /// Makes one attempt at delivering the post, `now` being when it started.
+    pub async fn attempt(&self, now: u64) -> Delivery {
+        let attempts = self.attempts + 1;
+        let mut req = reqwest::Client::new()
+            .post(&self.url)
+            .timeout(Duration::from_millis(ATTEMPT_LEASE_MS / 2))
+            .header("Idempotency-Key", &self.id);
+        for (name, value) in &self.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let res = req.json(&self.body).send().await;
+
+        let (retryable, failure) = match res {
+            Ok(res) => {
+                let status = res.status();
+                let text = res.text().await.unwrap_or_default();
+                let body = serde_json::from_str(&text).unwrap_or(Value::String(text));
+                if status.is_success() {
+                    return Delivery::Done(json!({
+                        "$http_post": self.id,
+                        "ok": true,
+                        "status": status.as_u16(),
+                        "body": body,
+                        "attempts": attempts,
+                    }));
+                }
+                let retryable = status.is_server_error()
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || status == reqwest::StatusCode::REQUEST_TIMEOUT;
+                (
+                    retryable,
+                    json!({
+                        "$http_post": self.id,
+                        "ok": false,
+                        "status": status.as_u16(),
+                        "body": body,
+                        "error": format!("request failed with status {}", status),
+                        "attempts": attempts,
+                    }),
+                )
+            }
+            Err(e) => (
+                true,
+                json!({
+                    "$http_post": self.id,
+                    "ok": false,
+                    "error": e.to_string(),
+                    "attempts": attempts,
+                }),
+            ),
+        };
+
+        if retryable && attempts < self.max_attempts {
+            Delivery::Retry(now + self.backoff_after(attempts))
+        } else {
+            Delivery::Done(failure)
+        }
+    }
+
+    fn backoff_after(&self, attempts: u32) -> u64 {
+        let factor = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.backoff_ms.saturating_mul(factor).min(MAX_BACKOFF_MS)
+    }
+}
@@ -0,0 +1,176 @@
+//! Snapshots are stored as pages: split where their content says, so that an
+//! insertion only changes the pages around it, and keyed by their hash, so
+//! that a page is stored once no matter how many steps or procs share it.
+
+use std::{
+    io::{Read, Write},
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, Result};
+
+/// How snapshots are split and compressed, stored with each of them so that
+/// one stored any other way is never misread.
+pub const FORMAT: u32 = 1;
+
+const MIN_PAGE_SIZE: usize = 16 * 1024;
+const MAX_PAGE_SIZE: usize = 256 * 1024;
+/// ends a page on average every 64KB past `MIN_PAGE_SIZE`
+const PAGE_BOUNDARY_BITS: u32 = 16;
+
+const COMPRESSION_QUALITY: u32 = 5;
+const COMPRESSION_WINDOW_BITS: u32 = 22;
+const BUFFER_SIZE: usize = 4096;
+
+pub struct Page<'a> {
+    pub hash: String,
+    pub data: &'a [u8],
+}
+
+/// Splits `snapshot` into pages, in order.
+pub fn pages(snapshot: &[u8]) -> Vec<Page<'_>> {
+    let gear = gear();
+    let mut pages = vec![];
+    let mut start = 0;
+    let mut hash: u64 = 0;
+    for (i, byte) in snapshot.iter().enumerate() {
+        // a rolling hash of the last 64 bytes, see FastCDC
+        hash = (hash << 1).wrapping_add(gear[*byte as usize]);
+        let len = i + 1 - start;
+        if (len >= MIN_PAGE_SIZE && hash >> (64 - PAGE_BOUNDARY_BITS) == 0) || len >= MAX_PAGE_SIZE
+        {
+            pages.push(page(&snapshot[start..=i]));
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < snapshot.len() {
+        pages.push(page(&snapshot[start..]));
+    }
+    pages
+}
+
+fn page(data: &[u8]) -> Page<'_> {
+    Page {
+        hash: sha256::digest(data),
+        data,
+    }
+}
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut writer = brotli::CompressorWriter::new(
+        Vec::with_capacity(data.len() / 2),
+        BUFFER_SIZE,
+        COMPRESSION_QUALITY,
+        COMPRESSION_WINDOW_BITS,
+    );
+    writer.write_all(data)?;
+    Ok(writer.into_inner())
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut page = vec![];
+    brotli::Decompressor::new(data, BUFFER_SIZE).read_to_end(&mut page)?;
+    Ok(page)
+}
+
+/// Checks a reassembled snapshot against what was recorded when it was
+/// stored, before it is given to v8, which aborts on a corrupt one.
+pub fn verify(
+    snapshot: &[u8],
+    format: Option<u32>,
+    size: Option<usize>,
+    checksum: &str,
+) -> Result<()> {
+    if format != Some(FORMAT) {
+        return Err(anyhow!("has an unknown format {:?}", format));
+    }
+    if let Some(size) = size.filter(|size| *size != snapshot.len()) {
+        return Err(anyhow!("is {} bytes rather than {}", snapshot.len(), size));
+    }
+    if sha256::digest(snapshot) != checksum {
+        return Err(anyhow!("doesn't match its checksum"));
+    }
+    Ok(())
+}
+
+/// Random values for every byte, the same in every run so that page
+/// boundaries, and with them the hashes of stored pages, stay the same.
+fn gear() -> &'static [u64; 256] {
+    static GEAR: OnceLock<[u64; 256]> = OnceLock::new();
+    GEAR.get_or_init(|| {
+        // splitmix64
+        let mut state: u64 = 0x61706569726f;
+        let mut gear = [0; 256];
+        for value in gear.iter_mut() {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            *value = z ^ (z >> 31);
+        }
+        gear
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that don't repeat, like a snapshot's.
+    fn snapshot(len: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(snapshot: &[u8]) -> Vec<u8> {
+        pages(snapshot)
+            .iter()
+            .flat_map(|page| {
+                assert_eq!(page.hash, sha256::digest(page.data));
+                decompress(&compress(page.data).unwrap()).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        // neither a multiple of the page sizes nor of the buffer size
+        let unaligned = snapshot(3 * MAX_PAGE_SIZE + BUFFER_SIZE + 17);
+        assert!(pages(&unaligned).len() > 1);
+        assert_eq!(round_trip(&unaligned), unaligned);
+
+        let small = snapshot(MIN_PAGE_SIZE - 1);
+        assert_eq!(pages(&small).len(), 1);
+        assert_eq!(round_trip(&small), small);
+
+        // no content to split on, pages end at their maximum size
+        let zeros = vec![0; 2 * MAX_PAGE_SIZE + 1];
+        let sizes: Vec<usize> = pages(&zeros).iter().map(|page| page.data.len()).collect();
+        assert_eq!(sizes, vec![MAX_PAGE_SIZE, MAX_PAGE_SIZE, 1]);
+        assert_eq!(round_trip(&zeros), zeros);
+    }
+
+    #[test]
+    pub fn test_empty() {
+        assert!(pages(&[]).is_empty());
+        assert!(decompress(&compress(&[]).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_verify() {
+        let data = snapshot(1000);
+        let checksum = sha256::digest(&data[..]);
+        assert!(verify(&data, Some(FORMAT), Some(1000), &checksum).is_ok());
+        assert!(verify(&data, Some(FORMAT + 1), Some(1000), &checksum).is_err());
+        assert!(verify(&data, Some(FORMAT), Some(999), &checksum).is_err());
+        assert!(verify(&data[1..], Some(FORMAT), None, &checksum).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Keeps a step within its module's limits. A step that runs for too long or
+//! grows its heap too much has its execution terminated, which user code
+//! can't catch, so that a runaway proc can't hang or OOM the whole daemon.
+
+use std::{
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use apeiro_internal_api::ModuleLimits;
+
+pub const DEFAULT_MAX_HEAP_MB: u64 = 512;
+pub const DEFAULT_MAX_STEP_MS: u64 = 30 * 1_000;
+
+struct WatchdogState {
+    isolate: v8::IsolateHandle,
+    max_heap: usize,
+    /// why the step was terminated, if it was
+    tripped: Mutex<Option<String>>,
+    /// set once user code is done running, after which nothing is terminated
+    done: Mutex<bool>,
+    done_cond: Condvar,
+    heap_done: AtomicBool,
+}
+
+impl WatchdogState {
+    fn trip(&self, reason: String) {
+        let mut tripped = self.tripped.lock().unwrap();
+        if tripped.is_none() {
+            *tripped = Some(reason);
+        }
+        self.isolate.terminate_execution();
+    }
+}
+
+pub struct Watchdog {
+    state: Arc<WatchdogState>,
+    deadline: Instant,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The most heap a step of a module with `limits` may use, in bytes.
+pub fn max_heap(limits: &ModuleLimits) -> usize {
+    let max_heap_mb = limits.max_heap_mb.unwrap_or(DEFAULT_MAX_HEAP_MB);
+    (max_heap_mb as usize).saturating_mul(1024 * 1024)
+}
+
+impl Watchdog {
+    /// Starts watching `isolate` until `disarm` is called.
+    pub fn arm(isolate: &mut v8::OwnedIsolate, limits: &ModuleLimits) -> Watchdog {
+        let max_step_ms = limits.max_step_ms.unwrap_or(DEFAULT_MAX_STEP_MS);
+        let deadline = Instant::now() + Duration::from_millis(max_step_ms);
+
+        let state = Arc::new(WatchdogState {
+            isolate: isolate.thread_safe_handle(),
+            max_heap: max_heap(limits),
+            tripped: Mutex::new(None),
+            done: Mutex::new(false),
+            done_cond: Condvar::new(),
+            heap_done: AtomicBool::new(false),
+        });
+
+        let data = Arc::as_ptr(&state) as *mut c_void;
+        isolate.add_gc_prologue_callback(heap_check_callback, data, v8::GCType::MARK_SWEEP_COMPACT);
+        isolate.add_near_heap_limit_callback(near_heap_limit_callback, data);
+
+        let thread = {
+            let state = state.clone();
+            std::thread::Builder::new()
+                .name("apeiro-watchdog".to_string())
+                .spawn(move || {
+                    let mut done = state.done.lock().unwrap();
+                    while !*done {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            // tripped while holding `done`, so that once
+                            // `stand_down` returns the step can't be tripped
+                            state.trip(format!(
+                                "step exceeded its time limit of {} ms",
+                                max_step_ms
+                            ));
+                            return;
+                        }
+                        done = state
+                            .done_cond
+                            .wait_timeout(done, deadline - now)
+                            .unwrap()
+                            .0;
+                    }
+                })
+                .expect("failed to start the watchdog")
+        };
+
+        Watchdog {
+            state,
+            deadline,
+            thread: Some(thread),
+        }
+    }
+
+    /// When the step is terminated for running too long.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Stops terminating the step, for once user code is done running and
+    /// the engine winds it up. Returns whether the step was terminated
+    /// before that, in which case the isolate is still unwinding.
+    pub fn stand_down(&self) -> bool {
+        // heap checks run on the isolate's thread, like this
+        self.state.heap_done.store(true, Ordering::SeqCst);
+        *self.state.done.lock().unwrap() = true;
+        self.state.done_cond.notify_all();
+        self.state.tripped.lock().unwrap().is_some()
+    }
+
+    /// Stops watching and returns why the step was terminated, if it was. The
+    /// isolate can be used again afterwards, e.g. to take its snapshot.
+    pub fn disarm(mut self, isolate: &mut v8::OwnedIsolate) -> Option<String> {
+        self.stand_down();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        let data = Arc::as_ptr(&self.state) as *mut c_void;
+        isolate.remove_gc_prologue_callback(heap_check_callback, data);
+        isolate.remove_near_heap_limit_callback(near_heap_limit_callback, 0);
+        isolate.cancel_terminate_execution();
+
+        self.state.tripped.lock().unwrap().take()
+    }
+}
+
+extern "C" fn heap_check_callback(
+    isolate: *mut v8::Isolate,
+    _type: v8::GCType,
+    _flags: v8::GCCallbackFlags,
+    data: *mut c_void,
+) {
+    let state = unsafe { &*(data as *const WatchdogState) };
+    if state.heap_done.load(Ordering::SeqCst) {
+        return;
+    }
+    let mut stats = v8::HeapStatistics::default();
+    unsafe { &mut *isolate }.get_heap_statistics(&mut stats);
+    if stats.used_heap_size() > state.max_heap {
+        state.trip(format!(
+            "step exceeded its heap limit of {} MB",
+            state.max_heap / (1024 * 1024)
+        ));
+    }
+}
+
+/// v8 aborts the process once its own heap limit is reached, which is the
+/// module's where the isolate was created with it. Terminating and granting
+/// some headroom lets the step unwind instead.
+extern "C" fn near_heap_limit_callback(
+    data: *mut c_void,
+    current_heap_limit: usize,
+    _initial_heap_limit: usize,
+) -> usize {
+    let state = unsafe { &*(data as *const WatchdogState) };
+    if !state.heap_done.load(Ordering::SeqCst) {
+        state.trip(format!(
+            "step exceeded its heap limit of {} MB",
+            state.max_heap / (1024 * 1024)
+        ));
+    }
+    current_heap_limit.saturating_mul(2)
+}
@@ -18,6 +18,35 @@ pub struct ProcDetails {
     pub compiled_src: String,
     pub engine_status: EngineStatus,
     pub state: StepResult,
+    /// the step the proc continues from
+    pub step_id: u64,
+    pub limits: ModuleLimits,
+    /// unset for procs following the daemon's default
+    pub resume_mode: Option<ResumeMode>,
+    /// the version of the module the proc runs
+    pub module_version: u32,
+    /// what happens to the proc when a step fails, its module's policy
+    pub on_error: ErrorPolicy,
+    pub retry: RetryPolicy,
+}
+
+/// What running a stored step of a proc again takes: where the step
+/// continued from, and what it was given the first time.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StepReplay {
+    /// the source of the module version the step ran
+    pub compiled_src: String,
+    /// the step it continued from, empty for a proc's first step
+    pub resumed_from: EngineStatus,
+    pub limits: ModuleLimits,
+    pub resume_mode: Option<ResumeMode>,
+    /// the message that triggered the step
+    pub msg: Option<Value>,
+    /// the step's `clock` and `seed`, unset if it was stored before they were
+    pub clock: Option<u64>,
+    pub seed: Option<u32>,
+    /// the requests the step made and their outcomes, handed to it again
+    pub journal: Option<Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -34,6 +63,12 @@ pub struct ProcSummary {
     pub suspension: Option<Value>,
     pub snapshot_size: u32,
     pub snapshot_v2_size: u32,
+    /// the version of its module the proc runs
+    #[serde(default)]
+    pub module_version: u32,
+    /// why the proc needs an operator's attention, e.g. it had to be resumed
+    /// from something other than its latest snapshot
+    pub flagged: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -45,7 +80,136 @@ pub struct ProcListOutput {
 pub struct ProcNewRequest {
     pub module_id: String,
     pub name: Option<String>,
+    /// the version of the module to run, the latest if unset
     pub version: Option<u32>,
+    /// how the proc is resumed between steps, the daemon's default if unset
+    pub resume_mode: Option<ResumeMode>,
+}
+
+/// What a proc is resumed from at the start of each step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResumeMode {
+    /// the V8 heap snapshot of the previous step, falling back to frames
+    /// and funcs for snapshots this V8 can't load
+    #[default]
+    Snapshot,
+    /// only the frames and funcs of the previous step, which don't depend on
+    /// the V8 build and so survive upgrading it
+    Frames,
+}
+
+impl ResumeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResumeMode::Snapshot => "snapshot",
+            ResumeMode::Frames => "frames",
+        }
+    }
+}
+
+impl std::str::FromStr for ResumeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshot" => Ok(ResumeMode::Snapshot),
+            "frames" => Ok(ResumeMode::Frames),
+            _ => Err(format!(
+                "unknown resume mode {}, expected snapshot or frames",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to a proc when one of its steps fails, e.g. because its code
+/// threw. The failed step is kept in the proc's history as an `ERROR` step
+/// either way, with the error and the message that caused it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// the proc stays suspended at the step before, the message is dropped
+    #[default]
+    Suspend,
+    /// the proc ends at the failed step
+    Error,
+    /// the message is delivered again as the module's `RetryPolicy` says,
+    /// the proc ends as with `Error` once it gives up
+    Retry,
+}
+
+impl ErrorPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorPolicy::Suspend => "suspend",
+            ErrorPolicy::Error => "error",
+            ErrorPolicy::Retry => "retry",
+        }
+    }
+}
+
+impl std::str::FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suspend" => Ok(ErrorPolicy::Suspend),
+            "error" => Ok(ErrorPolicy::Error),
+            "retry" => Ok(ErrorPolicy::Retry),
+            _ => Err(format!(
+                "unknown error policy {}, expected suspend, error or retry",
+                s
+            )),
+        }
+    }
+}
+
+/// How failed steps are retried under `ErrorPolicy::Retry`. Each attempt is
+/// a step of its own in the proc's history.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// how many times a message is tried in all, its first delivery included
+    pub max_attempts: u32,
+    /// how long to wait before the first retry, in ms
+    pub backoff_ms: u64,
+    /// what the wait is multiplied by for each retry after that
+    pub backoff_factor: u32,
+    /// the longest wait between two attempts, in ms
+    pub max_backoff_ms: u64,
+    /// substrings of the errors worth retrying, every error is if empty
+    pub retryable: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            backoff_ms: 1000,
+            backoff_factor: 2,
+            max_backoff_ms: 60_000,
+            retryable: vec![],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after the `attempt`th attempt failed, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> u64 {
+        let factor = u64::from(self.backoff_factor).saturating_pow(attempt.saturating_sub(1));
+        self.backoff_ms
+            .saturating_mul(factor)
+            .min(self.max_backoff_ms)
+    }
+
+    pub fn is_retryable(&self, error: &str) -> bool {
+        self.retryable.is_empty()
+            || self
+                .retryable
+                .iter()
+                .any(|retryable| error.contains(retryable.as_str()))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -53,12 +217,80 @@ pub struct ModuleEditRequest {
     pub src: String,
 }
 
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ModuleMigrateRequest {
+    /// the version to move procs onto, the module's latest if unset
+    pub version: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModuleMigrateOutput {
+    pub version: u32,
+    /// procs now running `version`
+    pub migrated: Vec<ApeiroId>,
+    /// suspended procs left on their version, and why
+    pub incompatible: Vec<ProcMigrateRejection>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProcMigrateRejection {
+    pub proc_id: ApeiroId,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModuleCheckRequest {
+    /// the proposed new source of the module
+    pub src: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModuleCheckOutput {
+    /// the version `src` was compared with, the module's latest
+    pub version: u32,
+    /// `fnhash`es of the fns only `src` has
+    pub added_fns: Vec<String>,
+    /// `fnhash`es of the fns `src` no longer has
+    pub removed_fns: Vec<String>,
+    /// `fnhash`es of the fns whose `$pc`s differ
+    pub changed_fns: Vec<String>,
+    /// every suspended proc of the module
+    pub procs: Vec<ProcCompatibility>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProcCompatibility {
+    pub proc_id: ApeiroId,
+    pub module_version: u32,
+    /// whether its current frames can resume under `src`
+    pub compatible: bool,
+    /// why they can't
+    pub problems: Vec<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ModuleNewRequest {
     pub name: Option<String>,
     pub src: String,
     pub singleton: Option<bool>,
     pub src_is_compiled: Option<bool>,
+    pub limits: Option<ModuleLimits>,
+    /// what happens to its procs when a step fails, `Retry` if `retry` is
+    /// set and `Suspend` otherwise
+    pub on_error: Option<ErrorPolicy>,
+    /// how failed steps are retried, the defaults of `RetryPolicy` if unset
+    pub retry: Option<RetryPolicy>,
+}
+
+/// What a single step of a module's procs may use. A step going over either
+/// limit is terminated and its proc is crashed; unset limits use the engine's
+/// defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleLimits {
+    /// heap size in MB
+    pub max_heap_mb: Option<u64>,
+    /// wall-clock time in ms
+    pub max_step_ms: Option<u64>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -66,6 +298,65 @@ pub struct ProcSendRequest {
     pub msg: Value,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MboxMessage {
+    pub id: String,
+    pub msg: Value,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProcMboxOutput {
+    pub msgs: Vec<MboxMessage>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProcMboxPurgeOutput {
+    pub purged: usize,
+}
+
+/// A step of a proc, as listed by its history.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StepSummary {
+    pub step_id: u64,
+    /// the step this one continued from, none for a proc's first step
+    pub parent_step_id: Option<u64>,
+    /// the message that triggered the step, none for a proc's first step
+    pub msg: Option<Value>,
+    pub step_result: StepResult,
+    /// when the step ran, in ms since the epoch
+    pub clock: Option<u64>,
+    /// whether the proc is at this step, i.e. continues from it
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProcHistoryOutput {
+    pub steps: Vec<StepSummary>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StepDetails {
+    #[serde(flatten)]
+    pub summary: StepSummary,
+    pub frames: Option<Value>,
+    pub funcs: Option<Value>,
+    pub journal: Option<Value>,
+    /// false once the step's snapshot was pruned, rewinding to it then
+    /// resumes the proc from its frames
+    pub has_snapshot: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProcRewindRequest {
+    pub step_id: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ProcForkRequest {
+    /// the step the fork starts from, the proc's current one if unset
+    pub at_step: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Eq)]
 pub enum StepResultStatus {
     #[default]
@@ -91,6 +382,14 @@ pub struct EngineStatus {
     pub frames: Option<Value>,
     pub funcs: Option<Value>,
     pub snapshot: Option<Vec<u8>>,
+    /// version of the V8 that made `snapshot`, which only it can load
+    pub v8_version: Option<String>,
+    /// what `Date.now()` returned during the step, in ms since the epoch
+    pub clock: Option<u64>,
+    /// seed of the step's `Math.random()`
+    pub seed: Option<u32>,
+    /// the step's requests and their outcomes, in order
+    pub journal: Option<Value>,
 }
 
 
@@ -103,12 +402,41 @@ pub struct ProcStatus {
     pub val: Option<serde_json::Value>,
     pub suspension: Option<serde_json::Value>,
     pub executing: bool,
+    /// the steps that failed since the proc last stepped, e.g. the attempts
+    /// at a message that is being retried
+    #[serde(default)]
+    pub failed_attempts: Vec<StepSummary>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct ProcStatusDebug {
     pub frames: Option<Value>,
     pub funcs: Option<Value>,
+    #[serde(default)]
+    pub rejected_msgs: Vec<MboxRejection>,
+}
+
+/// A pending mailbox message that the proc's current `$recv` schema rejects.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct MboxRejection {
+    pub id: String,
+    pub msg: Value,
+    pub errors: Vec<String>,
+}
+
+/// Whether a proc's current suspension would accept a candidate message.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct ProcExplainOutput {
+    pub accepted: bool,
+    pub suspension: Option<Value>,
+    /// Per-clause breakdown of a query matcher, see
+    /// `serde_json_matcher::Explanation`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Value>,
+    /// Why a JSON Schema matcher rejected the message, or why the proc can't
+    /// take messages at all.
+    #[serde(default)]
+    pub errors: Vec<String>,
 }
 
 impl ProcStatus {
@@ -127,6 +455,7 @@ impl ProcStatus {
             val: step_result.val,
             suspension: step_result.suspension,
             executing,
+            failed_attempts: vec![],
         }
     }
 }
@@ -142,8 +471,18 @@ pub struct ModuleSummary {
     pub src: String,
     pub compiled_src: String,
     pub name: String,
+    /// the version `src` is from, the latest unless another was asked for;
+    /// editing the module adds a new one
+    #[serde(default)]
+    pub version: u32,
     pub singleton: Option<u32>,
     pub procs: Vec<String>,
+    #[serde(default)]
+    pub limits: ModuleLimits,
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+    #[serde(default)]
+    pub retry: RetryPolicy,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
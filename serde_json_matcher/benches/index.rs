@@ -0,0 +1,52 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use serde_json::{json, Value};
+use serde_json_matcher::{from_json, MatcherIndex, ObjMatcher};
+
+/// One subscription per proc, each waiting on its own order id, plus a few
+/// that can't be bucketed.
+fn subscriptions(n: usize) -> Vec<(String, Value)> {
+    (0..n)
+        .map(|i| {
+            let matcher = if i % 100 == 0 {
+                json!({"total": {"$gt": 1000}})
+            } else {
+                json!({"type": "order.paid", "order_id": format!("order-{}", i)})
+            };
+            (format!("proc-{}", i), matcher)
+        })
+        .collect()
+}
+
+fn bench_routing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route_event");
+    for n in [100, 1_000, 10_000].iter() {
+        let subs = subscriptions(*n);
+        let event =
+            json!({"type": "order.paid", "order_id": format!("order-{}", n / 2 + 1), "total": 10});
+
+        let linear: Vec<(String, ObjMatcher)> = subs
+            .iter()
+            .map(|(pid, def)| (pid.clone(), from_json(def.clone()).unwrap()))
+            .collect();
+        group.bench_with_input(BenchmarkId::new("linear", n), &event, |b, event| {
+            b.iter(|| {
+                linear
+                    .iter()
+                    .filter(|(_, matcher)| matcher.matches(black_box(event)))
+                    .count()
+            })
+        });
+
+        let mut index = MatcherIndex::new();
+        for (pid, def) in subs {
+            index.insert(pid, def).unwrap();
+        }
+        group.bench_with_input(BenchmarkId::new("index", n), &event, |b, event| {
+            b.iter(|| index.matching(black_box(event)).len())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_routing);
+criterion_main!(benches);
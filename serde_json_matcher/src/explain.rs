@@ -0,0 +1,290 @@
+//! Explains why a matcher accepts or rejects a value, as a tree mirroring
+//! the query: one node per operator or document, each with the path it was
+//! evaluated at and whether it passed.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{resolve_path, DocumentMatcher, ObjMatcher};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// Dotted path of the field the node was evaluated against, empty for
+    /// the value itself.
+    pub path: String,
+    /// The operator (`$gt`, `$and`, ...), `$eq` for literals or `document`
+    /// for a nested query document.
+    pub operator: String,
+    /// The operator as written in the query, for leaves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<Value>,
+    /// What was found at `path`; absent if the field is missing, an array
+    /// if the path fanned out over several values.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actual: Option<Value>,
+    pub passed: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Explanation>,
+}
+
+impl ObjMatcher {
+    /// Like `matches`, but reports how each clause of the query fared.
+    /// The root's `passed` is always the same as `matches(other)`.
+    #[must_use]
+    pub fn explain(&self, other: &Value) -> Explanation {
+        self.explain_value("", other)
+    }
+
+    fn operator_name(&self) -> &'static str {
+        match self {
+            ObjMatcher::Eq(_) => "$eq",
+            ObjMatcher::In(_) => "$in",
+            ObjMatcher::Ne(_) => "$ne",
+            ObjMatcher::Nin(_) => "$nin",
+            ObjMatcher::And(_) => "$and",
+            ObjMatcher::Not(_) => "$not",
+            ObjMatcher::Or(_) => "$or",
+            ObjMatcher::Nor(_) => "$nor",
+            ObjMatcher::Gt(_) => "$gt",
+            ObjMatcher::Gte(_) => "$gte",
+            ObjMatcher::Lt(_) => "$lt",
+            ObjMatcher::Lte(_) => "$lte",
+            ObjMatcher::Exists(_) => "$exists",
+            ObjMatcher::Regex(_) => "$regex",
+            ObjMatcher::Size(_) => "$size",
+            ObjMatcher::All(_) => "$all",
+            ObjMatcher::ElemMatch(_) => "$elemMatch",
+            ObjMatcher::TypeValue(_) | ObjMatcher::TypeArray(_) => "$type",
+            ObjMatcher::Document(_) => "document",
+            ObjMatcher::Value(_) => "$eq",
+        }
+    }
+
+    fn leaf(&self, path: &str, actual: Option<Value>, passed: bool) -> Explanation {
+        Explanation {
+            path: path.to_string(),
+            operator: self.operator_name().to_string(),
+            expected: serde_json::to_value(self).ok(),
+            actual,
+            passed,
+            children: vec![],
+        }
+    }
+
+    fn node(
+        &self,
+        path: &str,
+        actual: Option<Value>,
+        passed: bool,
+        children: Vec<Explanation>,
+    ) -> Explanation {
+        Explanation {
+            path: path.to_string(),
+            operator: self.operator_name().to_string(),
+            expected: None,
+            actual,
+            passed,
+            children,
+        }
+    }
+
+    /// Mirrors `matches`.
+    fn explain_value(&self, path: &str, value: &Value) -> Explanation {
+        let passed = self.matches(value);
+        let actual = Some(value.clone());
+        let children = match self {
+            ObjMatcher::And(op) => explain_all_values(&op.val, path, value),
+            ObjMatcher::Or(op) => explain_all_values(&op.val, path, value),
+            ObjMatcher::Nor(op) => explain_all_values(&op.val, path, value),
+            ObjMatcher::In(op) => explain_all_values(&op.val, path, value),
+            ObjMatcher::Nin(op) => explain_all_values(&op.val, path, value),
+            ObjMatcher::Eq(op) => vec![op.val.explain_value(path, value)],
+            ObjMatcher::Ne(op) => vec![op.val.explain_value(path, value)],
+            ObjMatcher::Not(op) => vec![op.val.explain_value(path, value)],
+            ObjMatcher::Document(doc) => return explain_document(doc, path, actual, passed, value),
+            _ => return self.leaf(path, actual, passed),
+        };
+
+        self.node(path, actual, passed, children)
+    }
+
+    /// Mirrors `matches_field`.
+    fn explain_field(&self, path: &str, field: &[&Value]) -> Explanation {
+        let passed = self.matches_field(field);
+        let actual = field_actual(field);
+        let children = match self {
+            ObjMatcher::And(op) => explain_all_fields(&op.val, path, field),
+            ObjMatcher::Or(op) => explain_all_fields(&op.val, path, field),
+            ObjMatcher::Nor(op) => explain_all_fields(&op.val, path, field),
+            ObjMatcher::Nin(op) => explain_all_fields(&op.val, path, field),
+            ObjMatcher::Not(op) => vec![op.val.explain_field(path, field)],
+            ObjMatcher::Ne(op) => vec![op.val.explain_field(path, field)],
+            ObjMatcher::Document(doc) => return self.explain_document_field(doc, path, field),
+            _ => return self.leaf(path, actual, passed),
+        };
+
+        self.node(path, actual, passed, children)
+    }
+
+    /// A nested document is matched against every object found at the
+    /// path, explain it against the one that matched or else the first.
+    fn explain_document_field(
+        &self,
+        doc: &DocumentMatcher,
+        path: &str,
+        field: &[&Value],
+    ) -> Explanation {
+        let passed = self.matches_field(field);
+        let actual = field_actual(field);
+
+        let mut candidates = vec![];
+        for value in field {
+            match value {
+                Value::Object(_) => candidates.push(*value),
+                Value::Array(items) => candidates.extend(items.iter().filter(|v| v.is_object())),
+                _ => {}
+            }
+        }
+        let candidate = candidates
+            .iter()
+            .find(|v| self.matches_field(&[**v]))
+            .or_else(|| candidates.first());
+
+        match candidate {
+            Some(candidate) => explain_document(doc, path, actual, passed, candidate),
+            None => self.leaf(path, actual, passed),
+        }
+    }
+}
+
+fn explain_all_values(matchers: &[ObjMatcher], path: &str, value: &Value) -> Vec<Explanation> {
+    matchers
+        .iter()
+        .map(|m| m.explain_value(path, value))
+        .collect()
+}
+
+fn explain_all_fields(matchers: &[ObjMatcher], path: &str, field: &[&Value]) -> Vec<Explanation> {
+    matchers
+        .iter()
+        .map(|m| m.explain_field(path, field))
+        .collect()
+}
+
+/// Mirrors `DocumentMatcher::matches`.
+fn explain_document(
+    doc: &DocumentMatcher,
+    path: &str,
+    actual: Option<Value>,
+    passed: bool,
+    other: &Value,
+) -> Explanation {
+    let children = doc
+        .fields
+        .iter()
+        .map(|(key, matcher)| {
+            let segments: Vec<&str> = key.split('.').collect();
+            let mut field = vec![];
+            resolve_path(other, &segments, &mut field);
+
+            let child_path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            matcher.explain_field(&child_path, &field)
+        })
+        .collect();
+
+    Explanation {
+        path: path.to_string(),
+        operator: "document".to_string(),
+        expected: None,
+        actual,
+        passed,
+        children,
+    }
+}
+
+fn field_actual(field: &[&Value]) -> Option<Value> {
+    match field {
+        [] => None,
+        [value] => Some((*value).clone()),
+        values => Some(Value::Array(values.iter().map(|v| (*v).clone()).collect())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_json;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_explain_document() {
+        let matcher = from_json(json!({
+            "type": "order",
+            "order.total": {"$gt": 10, "$lt": 100},
+            "tags": {"$exists": true},
+        }))
+        .unwrap();
+        let msg = json!({"type": "order", "order": {"total": 5}});
+
+        let explanation = matcher.explain(&msg);
+        assert!(!explanation.passed);
+        assert_eq!(explanation.operator, "document");
+
+        let total = &explanation.children[0];
+        assert_eq!(total.path, "order.total");
+        assert_eq!(total.operator, "$and");
+        assert_eq!(total.actual, Some(json!(5)));
+        assert!(!total.children[0].passed);
+        assert!(total.children[1].passed);
+
+        let tags = &explanation.children[1];
+        assert_eq!(tags.path, "tags");
+        assert_eq!(tags.actual, None);
+        assert!(!tags.passed);
+
+        let ty = &explanation.children[2];
+        assert_eq!(ty.operator, "$eq");
+        assert!(ty.passed);
+    }
+
+    #[test]
+    pub fn test_explain_agrees_with_matches() {
+        let cases = vec![
+            (json!({"a": {"b": 1}}), json!({"a": [{"b": 2}, {"b": 1}]})),
+            (
+                json!({"$or": [{"a": 1}, {"b": {"$ne": 2}}]}),
+                json!({"b": 2}),
+            ),
+            (json!({"a": {"$not": {"$gt": 1}}}), json!({"a": [0, 5]})),
+            (json!({"a": {"$nin": [1, 2]}}), json!({"a": 3})),
+            (json!({"$type": "number"}), json!(1)),
+            (json!("hello"), json!("hello")),
+        ];
+        for (query, msg) in cases {
+            let matcher = from_json(query.clone()).unwrap();
+            assert_eq!(
+                matcher.explain(&msg).passed,
+                matcher.matches(&msg),
+                "{} on {}",
+                query,
+                msg
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_explain_nested_document() {
+        let matcher = from_json(json!({"a": {"b": {"$gt": 1}}})).unwrap();
+        let explanation = matcher.explain(&json!({"a": {"b": 0}}));
+
+        let a = &explanation.children[0];
+        assert_eq!(a.operator, "document");
+        assert_eq!(a.children[0].path, "a.b");
+        assert_eq!(a.children[0].operator, "$gt");
+        assert_eq!(a.children[0].expected, Some(json!({"$gt": 1})));
+        assert!(!a.children[0].passed);
+    }
+}
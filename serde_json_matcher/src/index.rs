@@ -0,0 +1,327 @@
+//! An index over many matchers, answering "which matchers accept this value"
+//! without evaluating every one of them.
+//!
+//! Queries that pin a field to a scalar (`{"type": "order"}`,
+//! `{"type": {"$eq": "order"}}`, or the same inside a top-level `$and`) are
+//! bucketed by that field and value, so a lookup only evaluates the matchers
+//! in the buckets hit by the value's own fields. Everything else is kept on a
+//! fallback list that is evaluated for every lookup.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use serde_json::{Map, Value};
+
+use crate::{from_json, resolve_path, ObjMatcher};
+
+#[derive(Debug)]
+struct Entry<K> {
+    key: K,
+    def: Value,
+    matcher: ObjMatcher,
+    slot: Option<(String, String)>,
+}
+
+#[derive(Debug)]
+pub struct MatcherIndex<K> {
+    next_id: u64,
+    entries: HashMap<u64, Entry<K>>,
+    by_key: HashMap<K, Vec<u64>>,
+    /// path -> canonical scalar -> entries pinning that path to that scalar
+    buckets: HashMap<String, HashMap<String, HashSet<u64>>>,
+    fallback: HashSet<u64>,
+}
+
+impl<K: Clone + Eq + Hash> Default for MatcherIndex<K> {
+    fn default() -> Self {
+        MatcherIndex::new()
+    }
+}
+
+impl<K: Clone + Eq + Hash> MatcherIndex<K> {
+    pub fn new() -> MatcherIndex<K> {
+        MatcherIndex {
+            next_id: 0,
+            entries: HashMap::new(),
+            by_key: HashMap::new(),
+            buckets: HashMap::new(),
+            fallback: HashSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Registers `def` under `key`. Registering the same matcher twice for
+    /// the same key is a no-op.
+    pub fn insert(&mut self, key: K, def: Value) -> Result<(), serde_json::Error> {
+        if let Some(ids) = self.by_key.get(&key) {
+            if ids.iter().any(|id| self.entries[id].def == def) {
+                return Ok(());
+            }
+        }
+
+        let matcher = from_json(def.clone())?;
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let slot = self.pick_slot(&def);
+        match &slot {
+            Some((path, scalar)) => {
+                self.buckets
+                    .entry(path.clone())
+                    .or_default()
+                    .entry(scalar.clone())
+                    .or_default()
+                    .insert(id);
+            }
+            None => {
+                self.fallback.insert(id);
+            }
+        }
+
+        self.by_key.entry(key.clone()).or_default().push(id);
+        self.entries.insert(
+            id,
+            Entry {
+                key,
+                def,
+                matcher,
+                slot,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Drops every matcher registered under `key`.
+    pub fn remove(&mut self, key: &K) {
+        let ids = match self.by_key.remove(key) {
+            Some(ids) => ids,
+            None => return,
+        };
+
+        for id in ids {
+            let entry = match self.entries.remove(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match entry.slot {
+                Some((path, scalar)) => {
+                    let mut path_empty = false;
+                    if let Some(by_scalar) = self.buckets.get_mut(&path) {
+                        let mut bucket_empty = false;
+                        if let Some(bucket) = by_scalar.get_mut(&scalar) {
+                            bucket.remove(&id);
+                            bucket_empty = bucket.is_empty();
+                        }
+                        if bucket_empty {
+                            by_scalar.remove(&scalar);
+                        }
+                        path_empty = by_scalar.is_empty();
+                    }
+                    if path_empty {
+                        self.buckets.remove(&path);
+                    }
+                }
+                None => {
+                    self.fallback.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// All registered `(key, matcher)` pairs, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Value)> {
+        self.entries.values().map(|entry| (&entry.key, &entry.def))
+    }
+
+    /// Keys with at least one matcher accepting `value`.
+    pub fn matching(&self, value: &Value) -> HashSet<K> {
+        let mut candidates: Vec<u64> = self.fallback.iter().cloned().collect();
+
+        for (path, by_scalar) in &self.buckets {
+            let segments: Vec<&str> = path.split('.').collect();
+            let mut found = vec![];
+            resolve_path(value, &segments, &mut found);
+
+            let mut seen = HashSet::new();
+            for field_value in found {
+                let elements = match field_value {
+                    Value::Array(items) => items.iter().collect(),
+                    other => vec![other],
+                };
+                for element in elements {
+                    if let Some(scalar) = canonical_scalar(element) {
+                        if !seen.insert(scalar.clone()) {
+                            continue;
+                        }
+                        if let Some(bucket) = by_scalar.get(&scalar) {
+                            candidates.extend(bucket.iter().cloned());
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut result = HashSet::new();
+        for id in candidates {
+            let entry = &self.entries[&id];
+            if !result.contains(&entry.key) && entry.matcher.matches(value) {
+                result.insert(entry.key.clone());
+            }
+        }
+        result
+    }
+
+    /// Of the scalar pins in `def`, the one whose bucket is currently the
+    /// smallest, so that matchers spread over the most selective fields.
+    fn pick_slot(&self, def: &Value) -> Option<(String, String)> {
+        let mut pins = vec![];
+        if let Value::Object(query) = def {
+            collect_pins(query, &mut pins);
+        }
+
+        pins.into_iter().min_by_key(|(path, scalar)| {
+            self.buckets
+                .get(path)
+                .and_then(|by_scalar| by_scalar.get(scalar))
+                .map_or(0, |bucket| bucket.len())
+        })
+    }
+}
+
+/// Fields a query requires to equal a given scalar. Only plain documents
+/// (no operators besides a lone `$and`) are considered, since those are
+/// evaluated field by field.
+fn collect_pins(query: &Map<String, Value>, pins: &mut Vec<(String, String)>) {
+    if query.len() == 1 {
+        if let Some(Value::Array(clauses)) = query.get("$and") {
+            for clause in clauses {
+                if let Value::Object(clause) = clause {
+                    collect_pins(clause, pins);
+                }
+            }
+            return;
+        }
+    }
+
+    if query.keys().any(|k| k.starts_with('$')) {
+        return;
+    }
+
+    for (path, matcher) in query {
+        let scalar = match matcher {
+            Value::Object(op) if op.len() == 1 => op.get("$eq").and_then(canonical_scalar),
+            other => canonical_scalar(other),
+        };
+        if let Some(scalar) = scalar {
+            pins.push((path.clone(), scalar));
+        }
+    }
+}
+
+/// `null` is left out since it also matches missing fields.
+fn canonical_scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::Bool(_) | Value::Number(_) | Value::String(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sorted(set: HashSet<&str>) -> Vec<&str> {
+        let mut v: Vec<&str> = set.into_iter().collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    pub fn test_equality_buckets() {
+        let mut index = MatcherIndex::new();
+        index.insert("a", json!({"type": "order"})).unwrap();
+        index
+            .insert("b", json!({"type": {"$eq": "order"}, "total": {"$gt": 10}}))
+            .unwrap();
+        index.insert("c", json!({"type": "refund"})).unwrap();
+        index.insert("d", json!({"total": {"$gt": 10}})).unwrap();
+
+        assert_eq!(index.fallback.len(), 1);
+        assert_eq!(
+            sorted(index.matching(&json!({"type": "order", "total": 20}))),
+            vec!["a", "b", "d"]
+        );
+        assert_eq!(
+            sorted(index.matching(&json!({"type": "refund", "total": 5}))),
+            vec!["c"]
+        );
+        assert!(index.matching(&json!({"kind": "order"})).is_empty());
+    }
+
+    #[test]
+    pub fn test_nested_and_array_fields() {
+        let mut index = MatcherIndex::new();
+        index
+            .insert(
+                "a",
+                json!({"$and": [{"order.status": "paid"}, {"order.total": {"$gt": 1}}]}),
+            )
+            .unwrap();
+        index.insert("b", json!({"tags": "urgent"})).unwrap();
+        index.insert("c", json!({"tags": null})).unwrap();
+
+        assert_eq!(index.fallback.len(), 1);
+        assert_eq!(
+            sorted(index.matching(&json!({"order": {"status": "paid", "total": 2}}))),
+            vec!["a", "c"]
+        );
+        assert_eq!(
+            sorted(index.matching(&json!({"tags": ["new", "urgent"]}))),
+            vec!["b"]
+        );
+    }
+
+    #[test]
+    pub fn test_numbers_and_bools() {
+        let mut index = MatcherIndex::new();
+        index.insert("a", json!({"n": 1})).unwrap();
+        index.insert("b", json!({"ok": true})).unwrap();
+
+        assert_eq!(
+            sorted(index.matching(&json!({"n": 1, "ok": true}))),
+            vec!["a", "b"]
+        );
+        assert!(index.matching(&json!({"n": "1", "ok": "true"})).is_empty());
+    }
+
+    #[test]
+    pub fn test_remove_and_dedup() {
+        let mut index = MatcherIndex::new();
+        index.insert("a", json!({"type": "order"})).unwrap();
+        index.insert("a", json!({"type": "order"})).unwrap();
+        index.insert("a", json!({"x": {"$exists": true}})).unwrap();
+        index.insert("b", json!({"type": "order"})).unwrap();
+        assert_eq!(index.len(), 3);
+
+        index.remove(&"a");
+        assert_eq!(index.len(), 1);
+        assert!(index.fallback.is_empty());
+        assert_eq!(
+            sorted(index.matching(&json!({"type": "order", "x": 1}))),
+            vec!["b"]
+        );
+
+        index.remove(&"b");
+        assert!(index.is_empty());
+        assert!(index.buckets.is_empty());
+    }
+}
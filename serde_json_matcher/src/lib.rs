@@ -1,9 +1,21 @@
 //! Implements a matcher for `serde_json::Value`s using the Mongo Query Language.
 //!
-//! Currently supports `$eq`, `$in`, `$ne`, `$nin`, `$and`, `$not`, `$or`, `$type` and `$nor`.
+//! Currently supports `$eq`, `$in`, `$ne`, `$nin`, `$and`, `$not`, `$or`, `$nor`, `$type`,
+//! `$gt`, `$gte`, `$lt`, `$lte`, `$exists`, `$regex`, `$size`, `$all` and `$elemMatch`.
+//! Keys may be dotted paths into nested documents and arrays.
+//!
+//! [`MatcherIndex`] routes a value to many registered matchers at once, and
+//! [`ObjMatcher::explain`] reports which clauses of a query accept a value.
+
+use std::{cmp::Ordering, convert::TryFrom};
 
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+mod explain;
+mod index;
+pub use explain::Explanation;
+pub use index::MatcherIndex;
 
 trait MatchesValue {
     fn matches(&self, other: &Value) -> bool;
@@ -109,7 +121,20 @@ impl MatchesValue for NotOperator {
     }
 }
 
-// operator_struct!(NorOperator, "$nor", Vec<ObjMatcher>);
+operator_struct!(Nor, NorOperator, "$nor", Vec<ObjMatcher>);
+
+impl MatchesValue for NorOperator {
+    #[inline]
+    fn matches(&self, other: &Value) -> bool {
+        for v in &self.val {
+            if v.matches(other) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
 
 operator_struct!(Or, OrOperator, "$or", Vec<ObjMatcher>);
 
@@ -126,6 +151,147 @@ impl MatchesValue for OrOperator {
     }
 }
 
+/// Orders numbers numerically and strings lexicographically; values of
+/// different (or other) types are not comparable.
+fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+macro_rules! comparison_operator {
+    ($obj_matcher_case:ident, $struct_name:ident, $json_operator:expr, $($ordering:pat)|+) => {
+        operator_struct!($obj_matcher_case, $struct_name, $json_operator, Value);
+
+        impl MatchesValue for $struct_name {
+            #[inline]
+            fn matches(&self, other: &Value) -> bool {
+                match compare_values(other, &self.val) {
+                    Some($($ordering)|+) => true,
+                    _ => false,
+                }
+            }
+        }
+    };
+}
+
+comparison_operator!(Gt, GtOperator, "$gt", Ordering::Greater);
+comparison_operator!(
+    Gte,
+    GteOperator,
+    "$gte",
+    Ordering::Greater | Ordering::Equal
+);
+comparison_operator!(Lt, LtOperator, "$lt", Ordering::Less);
+comparison_operator!(Lte, LteOperator, "$lte", Ordering::Less | Ordering::Equal);
+
+operator_struct!(Exists, ExistsOperator, "$exists", bool);
+
+impl MatchesValue for ExistsOperator {
+    /// Without a surrounding field any value being matched exists; see
+    /// `ObjMatcher::matches_field` for the case where the field may be missing.
+    #[inline]
+    fn matches(&self, _other: &Value) -> bool {
+        self.val
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RegexOperatorDef {
+    #[serde(rename = "$regex")]
+    val: String,
+    #[serde(rename = "$options", default, skip_serializing_if = "Option::is_none")]
+    options: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RegexOperatorDef", into = "RegexOperatorDef")]
+pub struct RegexOperator {
+    def: RegexOperatorDef,
+    regex: regex::Regex,
+}
+
+impl TryFrom<RegexOperatorDef> for RegexOperator {
+    type Error = regex::Error;
+
+    fn try_from(def: RegexOperatorDef) -> Result<Self, Self::Error> {
+        let mut builder = regex::RegexBuilder::new(&def.val);
+        for option in def.options.iter().flat_map(|options| options.chars()) {
+            match option {
+                'i' => builder.case_insensitive(true),
+                'm' => builder.multi_line(true),
+                's' => builder.dot_matches_new_line(true),
+                'x' => builder.ignore_whitespace(true),
+                _ => &mut builder,
+            };
+        }
+        let regex = builder.build()?;
+        Ok(RegexOperator { def, regex })
+    }
+}
+
+impl From<RegexOperator> for RegexOperatorDef {
+    fn from(op: RegexOperator) -> RegexOperatorDef {
+        op.def
+    }
+}
+
+impl MatchesValue for RegexOperator {
+    #[inline]
+    fn matches(&self, other: &Value) -> bool {
+        match other {
+            Value::String(s) => self.regex.is_match(s),
+            _ => false,
+        }
+    }
+}
+
+operator_struct!(Size, SizeOperator, "$size", usize);
+
+impl MatchesValue for SizeOperator {
+    #[inline]
+    fn matches(&self, other: &Value) -> bool {
+        match other {
+            Value::Array(arr) => arr.len() == self.val,
+            _ => false,
+        }
+    }
+}
+
+operator_struct!(All, AllOperator, "$all", Vec<ObjMatcher>);
+
+impl MatchesValue for AllOperator {
+    #[inline]
+    fn matches(&self, other: &Value) -> bool {
+        let elements = match other {
+            Value::Array(arr) => arr.as_slice(),
+            _ => std::slice::from_ref(other),
+        };
+
+        for v in &self.val {
+            if !elements.iter().any(|element| v.matches(element)) {
+                return false;
+            }
+        }
+
+        !self.val.is_empty()
+    }
+}
+
+operator_struct!(ElemMatch, ElemMatchOperator, "$elemMatch");
+
+impl MatchesValue for ElemMatchOperator {
+    #[inline]
+    fn matches(&self, other: &Value) -> bool {
+        match other {
+            Value::Array(arr) => arr.iter().any(|element| self.val.matches(element)),
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TypeValueOperator {
     #[serde(rename = "$type")]
@@ -202,7 +368,37 @@ enum TypeOperatorMatcher {
     Number,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A query document: every key is a dotted path into the value being matched
+/// and every field found there has to match the matcher under that key.
+#[derive(Debug, Clone)]
+pub struct DocumentMatcher {
+    query: Map<String, Value>,
+    fields: Vec<(String, ObjMatcher)>,
+}
+
+impl Serialize for DocumentMatcher {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.query.serialize(serializer)
+    }
+}
+
+impl MatchesValue for DocumentMatcher {
+    fn matches(&self, other: &Value) -> bool {
+        for (key, matcher) in &self.fields {
+            let path: Vec<&str> = key.split('.').collect();
+            let mut field = vec![];
+            resolve_path(other, &path, &mut field);
+
+            if !matcher.matches_field(&field) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum ObjMatcher {
     Eq(EqOperator),
@@ -212,43 +408,198 @@ pub enum ObjMatcher {
     And(AndOperator),
     Not(NotOperator),
     Or(OrOperator),
+    Nor(NorOperator),
+    Gt(GtOperator),
+    Gte(GteOperator),
+    Lt(LtOperator),
+    Lte(LteOperator),
+    Exists(ExistsOperator),
+    Regex(RegexOperator),
+    Size(SizeOperator),
+    All(AllOperator),
+    ElemMatch(ElemMatchOperator),
     TypeValue(TypeValueOperator),
     TypeArray(TypeArrayOperator),
+    Document(DocumentMatcher),
     Value(Value),
 }
 
+/// Nested matchers (`$not`, `$elemMatch`, `$in`, ...) are parsed like the
+/// top-level query.
+impl<'de> Deserialize<'de> for ObjMatcher {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parse(Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
 impl ObjMatcher {
     #[must_use]
     pub fn matches(&self, other: &Value) -> bool {
         MatchesValue::matches(self, other)
     }
+
+    /// Matches the values a document path resolved to (see `resolve_path`).
+    /// No values means the field is missing, which only `$exists` tells
+    /// apart from `null`. Arrays match if the array itself or any of its
+    /// elements match, and negations (`$ne`, `$nin`, `$not`, `$nor`) hold
+    /// only if no value or element matches.
+    fn matches_field(&self, field: &[&Value]) -> bool {
+        match self {
+            ObjMatcher::Exists(op) => field.is_empty() != op.val,
+            ObjMatcher::And(op) => op.val.iter().all(|v| v.matches_field(field)),
+            ObjMatcher::Or(op) => op.val.iter().any(|v| v.matches_field(field)),
+            ObjMatcher::Nor(op) => !op.val.iter().any(|v| v.matches_field(field)),
+            ObjMatcher::Not(op) => !op.val.matches_field(field),
+            ObjMatcher::Ne(op) => !op.val.matches_field(field),
+            ObjMatcher::Nin(op) => !op.val.iter().any(|v| v.matches_field(field)),
+            // nested documents are matched recursively, like the top-level one
+            ObjMatcher::Document(doc) => {
+                any_field_value(field, |v| v.is_object() && doc.matches(v))
+            }
+            ObjMatcher::Value(value) => any_field_value(field, |v| value == v),
+            _ => any_field_value(field, |v| self.matches(v)),
+        }
+    }
 }
 
-fn try_into_operator(value: Value) -> Option<ObjMatcher> {
-    if let Some(obj) = value.as_object() {
-        if obj.contains_key("$eq") {
-            return Some(ObjMatcher::Eq(serde_json::from_value(value).unwrap()));
-        } else if obj.contains_key("$in") {
-            return Some(ObjMatcher::In(serde_json::from_value(value).unwrap()));
-        } else if obj.contains_key("$ne") {
-            return Some(ObjMatcher::Ne(serde_json::from_value(value).unwrap()));
-        } else if obj.contains_key("$nin") {
-            return Some(ObjMatcher::Nin(serde_json::from_value(value).unwrap()));
-        } else if obj.contains_key("$and") {
-            return Some(ObjMatcher::And(serde_json::from_value(value).unwrap()));
-        } else if obj.contains_key("$not") {
-            return Some(ObjMatcher::Not(serde_json::from_value(value).unwrap()));
-        } else if obj.contains_key("$or") {
-            return Some(ObjMatcher::Or(serde_json::from_value(value).unwrap()));
-        } else if obj.contains_key("$type") {
-            if let Ok(v) = serde_json::from_value::<TypeArrayOperator>(value.clone()) {
-                return Some(ObjMatcher::TypeArray(v));
-            } else if let Ok(v) = serde_json::from_value::<TypeValueOperator>(value) {
-                return Some(ObjMatcher::TypeValue(v));
+/// Collects the values found at a dotted `path` (e.g. `order.items.0.sku`).
+/// Numeric segments index into arrays, other segments reach into every
+/// object element of an array.
+fn resolve_path<'a>(value: &'a Value, path: &[&str], found: &mut Vec<&'a Value>) {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            found.push(value);
+            return;
+        }
+    };
+
+    match value {
+        Value::Object(obj) => {
+            if let Some(v) = obj.get(*segment) {
+                resolve_path(v, rest, found);
             }
         }
+        Value::Array(arr) => {
+            if let Ok(index) = segment.parse::<usize>() {
+                if let Some(v) = arr.get(index) {
+                    resolve_path(v, rest, found);
+                }
+            } else {
+                for element in arr.iter().filter(|element| element.is_object()) {
+                    resolve_path(element, path, found);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn any_field_value<F: Fn(&Value) -> bool>(field: &[&Value], pred: F) -> bool {
+    if field.is_empty() {
+        return pred(&Value::Null);
+    }
+
+    field.iter().any(|v| {
+        pred(v)
+            || match v {
+                Value::Array(arr) => arr.iter().any(&pred),
+                _ => false,
+            }
+    })
+}
+
+fn invalid(msg: String) -> serde_json::Error {
+    serde::de::Error::custom(msg)
+}
+
+/// Turns a query into a matcher tree. Objects whose keys are all operators
+/// are operator expressions, other objects are query documents, anything
+/// else is a literal. Malformed operators and invalid `$regex` patterns are
+/// reported here, so matching never has to re-parse the query.
+fn parse(value: Value) -> Result<ObjMatcher, serde_json::Error> {
+    match value {
+        Value::Object(obj) if !obj.is_empty() && obj.keys().all(|k| k.starts_with('$')) => {
+            try_into_operator(obj)
+        }
+        Value::Object(query) => {
+            let mut fields = Vec::with_capacity(query.len());
+            for (key, matcher) in &query {
+                if key.starts_with('$') {
+                    return Err(invalid(format!(
+                        "operator {} can't be mixed with fields",
+                        key
+                    )));
+                }
+                fields.push((key.clone(), parse(matcher.clone())?));
+            }
+            Ok(ObjMatcher::Document(DocumentMatcher { query, fields }))
+        }
+        value => Ok(ObjMatcher::Value(value)),
+    }
+}
+
+/// `{"$gt": 1, "$lt": 5}` is shorthand for `{"$and": [{"$gt": 1}, {"$lt": 5}]}`.
+fn split_operators(obj: &Map<String, Value>) -> Result<ObjMatcher, serde_json::Error> {
+    let mut operators = vec![];
+    for (key, val) in obj {
+        if key == "$options" && obj.contains_key("$regex") {
+            continue;
+        }
+        let mut single = Map::new();
+        single.insert(key.clone(), val.clone());
+        if key == "$regex" {
+            if let Some(options) = obj.get("$options") {
+                single.insert("$options".to_string(), options.clone());
+            }
+        }
+        operators.push(try_into_operator(single)?);
+    }
+
+    if operators.len() == 1 {
+        Ok(operators.pop().unwrap())
+    } else {
+        Ok(ObjMatcher::And(AndOperator { val: operators }))
+    }
+}
+
+fn try_into_operator(obj: Map<String, Value>) -> Result<ObjMatcher, serde_json::Error> {
+    let is_regex_with_options =
+        obj.len() == 2 && obj.contains_key("$regex") && obj.contains_key("$options");
+    if obj.len() > 1 && !is_regex_with_options {
+        return split_operators(&obj);
     }
-    None
+
+    let key = if is_regex_with_options {
+        "$regex".to_string()
+    } else {
+        obj.keys().next().cloned().unwrap_or_default()
+    };
+    let is_type_array = obj.get("$type").map_or(false, Value::is_array);
+    let value = Value::Object(obj);
+    Ok(match key.as_str() {
+        "$eq" => ObjMatcher::Eq(serde_json::from_value(value)?),
+        "$in" => ObjMatcher::In(serde_json::from_value(value)?),
+        "$ne" => ObjMatcher::Ne(serde_json::from_value(value)?),
+        "$nin" => ObjMatcher::Nin(serde_json::from_value(value)?),
+        "$and" => ObjMatcher::And(serde_json::from_value(value)?),
+        "$not" => ObjMatcher::Not(serde_json::from_value(value)?),
+        "$or" => ObjMatcher::Or(serde_json::from_value(value)?),
+        "$nor" => ObjMatcher::Nor(serde_json::from_value(value)?),
+        "$gt" => ObjMatcher::Gt(serde_json::from_value(value)?),
+        "$gte" => ObjMatcher::Gte(serde_json::from_value(value)?),
+        "$lt" => ObjMatcher::Lt(serde_json::from_value(value)?),
+        "$lte" => ObjMatcher::Lte(serde_json::from_value(value)?),
+        "$exists" => ObjMatcher::Exists(serde_json::from_value(value)?),
+        "$regex" => ObjMatcher::Regex(serde_json::from_value(value)?),
+        "$size" => ObjMatcher::Size(serde_json::from_value(value)?),
+        "$all" => ObjMatcher::All(serde_json::from_value(value)?),
+        "$elemMatch" => ObjMatcher::ElemMatch(serde_json::from_value(value)?),
+        "$type" if is_type_array => ObjMatcher::TypeArray(serde_json::from_value(value)?),
+        "$type" => ObjMatcher::TypeValue(serde_json::from_value(value)?),
+        "$options" => return Err(invalid("$options without $regex".to_string())),
+        _ => return Err(invalid(format!("unknown operator {}", key))),
+    })
 }
 
 impl MatchesValue for ObjMatcher {
@@ -261,51 +612,36 @@ impl MatchesValue for ObjMatcher {
             ObjMatcher::And(op) => op.matches(other),
             ObjMatcher::Not(op) => op.matches(other),
             ObjMatcher::Or(op) => op.matches(other),
+            ObjMatcher::Nor(op) => op.matches(other),
+            ObjMatcher::Gt(op) => op.matches(other),
+            ObjMatcher::Gte(op) => op.matches(other),
+            ObjMatcher::Lt(op) => op.matches(other),
+            ObjMatcher::Lte(op) => op.matches(other),
+            ObjMatcher::Exists(op) => op.matches(other),
+            ObjMatcher::Regex(op) => op.matches(other),
+            ObjMatcher::Size(op) => op.matches(other),
+            ObjMatcher::All(op) => op.matches(other),
+            ObjMatcher::ElemMatch(op) => op.matches(other),
             ObjMatcher::TypeValue(op) => op.matches(other),
             ObjMatcher::TypeArray(op) => op.matches(other),
-            ObjMatcher::Value(value) => match try_into_operator(value.clone()) {
-                Some(obj_matcher) => obj_matcher.matches(other),
-                None => match value {
-                    Value::Number(n) => match other {
-                        Value::Number(n2) => n == n2,
-                        _ => false,
-                    },
-                    Value::Object(o) => {
-                        for (key, value) in o {
-                            if let Some(obj_matcher) = try_into_operator(value.clone()) {
-                                if !obj_matcher.matches(&other[key]) {
-                                    return false;
-                                }
-                            } else if value != &other[key] {
-                                return false;
-                            }
-                        }
-                        true
-                    }
-                    _ => {
-                        todo!("not implemented value match {:?}", other)
-                    }
+            ObjMatcher::Document(doc) => doc.matches(other),
+            ObjMatcher::Value(value) => match value {
+                Value::Number(n) => match other {
+                    Value::Number(n2) => n == n2,
+                    _ => false,
                 },
+                _ => value == other,
             },
         }
     }
 }
 
 pub fn from_str(s: &str) -> Result<ObjMatcher, serde_json::Error> {
-    let v: Value = serde_json::from_str(s)?;
-    if let Some(obj_matcher) = try_into_operator(v.clone()) {
-        Ok(obj_matcher)
-    } else {
-        Ok(ObjMatcher::Value(v))
-    }
+    parse(serde_json::from_str(s)?)
 }
 
 pub fn from_json(v: Value) -> Result<ObjMatcher, serde_json::Error> {
-    if let Some(obj_matcher) = try_into_operator(v.clone()) {
-        Ok(obj_matcher)
-    } else {
-        Ok(ObjMatcher::Value(v))
-    }
+    parse(v)
 }
 
 #[cfg(test)]
@@ -432,4 +768,243 @@ mod tests {
         let val = json!({"x": "hello"});
         assert!(!matcher.matches(&val));
     }
+
+    #[test]
+    pub fn test_nor() {
+        let matcher = from_str(r#"{"$nor": [ {"a":1}, {"b":1} ]}"#).unwrap();
+        assert!(matcher.matches(&json!({"a": 2, "b": 2})));
+        assert!(!matcher.matches(&json!({"a": 1})));
+        assert!(!matcher.matches(&json!({"b": 1})));
+    }
+
+    #[test]
+    pub fn test_comparison() {
+        let matcher = from_str(r#"{"amount":{"$gt":100}}"#).unwrap();
+        assert!(matcher.matches(&json!({"amount": 101})));
+        assert!(matcher.matches(&json!({"amount": 100.5})));
+        assert!(!matcher.matches(&json!({"amount": 100})));
+        assert!(!matcher.matches(&json!({"amount": "101"})));
+        assert!(!matcher.matches(&json!({"hello": "world"})));
+
+        let matcher = from_str(r#"{"amount":{"$gte":100}}"#).unwrap();
+        assert!(matcher.matches(&json!({"amount": 100})));
+        assert!(!matcher.matches(&json!({"amount": 99})));
+
+        let matcher = from_str(r#"{"amount":{"$lt":100}}"#).unwrap();
+        assert!(matcher.matches(&json!({"amount": -1})));
+        assert!(!matcher.matches(&json!({"amount": 100})));
+
+        let matcher = from_str(r#"{"name":{"$lte":"bob"}}"#).unwrap();
+        assert!(matcher.matches(&json!({"name": "alice"})));
+        assert!(matcher.matches(&json!({"name": "bob"})));
+        assert!(!matcher.matches(&json!({"name": "carol"})));
+    }
+
+    #[test]
+    pub fn test_multiple_operators() {
+        let matcher = from_str(r#"{"amount":{"$gt":1, "$lt":5}}"#).unwrap();
+        assert!(matcher.matches(&json!({"amount": 3})));
+        assert!(!matcher.matches(&json!({"amount": 1})));
+        assert!(!matcher.matches(&json!({"amount": 5})));
+    }
+
+    #[test]
+    pub fn test_exists() {
+        let matcher = from_str(r#"{"a":{"$exists":true}}"#).unwrap();
+        assert!(matcher.matches(&json!({"a": 1})));
+        assert!(matcher.matches(&json!({"a": null})));
+        assert!(!matcher.matches(&json!({"b": 1})));
+
+        let matcher = from_str(r#"{"a":{"$exists":false}}"#).unwrap();
+        assert!(!matcher.matches(&json!({"a": 1})));
+        assert!(matcher.matches(&json!({"b": 1})));
+
+        let matcher = from_str(r#"{"a":{"$not":{"$exists":true}}}"#).unwrap();
+        assert!(matcher.matches(&json!({"b": 1})));
+    }
+
+    #[test]
+    pub fn test_regex() {
+        let matcher = from_str(r#"{"email":{"$regex":"@example\\.com$"}}"#).unwrap();
+        assert!(matcher.matches(&json!({"email": "joe@example.com"})));
+        assert!(!matcher.matches(&json!({"email": "joe@example.org"})));
+        assert!(!matcher.matches(&json!({"email": 1})));
+
+        let matcher = from_str(r#"{"name":{"$regex":"^JO", "$options": "i"}}"#).unwrap();
+        assert!(matcher.matches(&json!({"name": "joe"})));
+        assert!(!matcher.matches(&json!({"name": "moe"})));
+
+        assert!(from_str(r#"{"name":{"$regex":"("}}"#).is_err());
+        assert!(from_str(r#"{"name":{"$not":{"$regex":"("}}}"#).is_err());
+    }
+
+    #[test]
+    pub fn test_size() {
+        let matcher = from_str(r#"{"a":{"$size":2}}"#).unwrap();
+        assert!(matcher.matches(&json!({"a": [1, 2]})));
+        assert!(!matcher.matches(&json!({"a": [1]})));
+        assert!(!matcher.matches(&json!({"a": "ab"})));
+    }
+
+    #[test]
+    pub fn test_all() {
+        let matcher = from_str(r#"{"tags":{"$all":["a", "b"]}}"#).unwrap();
+        assert!(matcher.matches(&json!({"tags": ["b", "c", "a"]})));
+        assert!(!matcher.matches(&json!({"tags": ["a", "c"]})));
+        assert!(!matcher.matches(&json!({"tags": "a"})));
+
+        let matcher = from_str(r#"{"tags":{"$all":["a"]}}"#).unwrap();
+        assert!(matcher.matches(&json!({"tags": "a"})));
+    }
+
+    #[test]
+    pub fn test_elem_match() {
+        let matcher = from_str(r#"{"items":{"$elemMatch":{"qty":{"$gt":5}, "sku":"x"}}}"#).unwrap();
+        assert!(
+            matcher.matches(&json!({"items": [{"qty": 1, "sku": "x"}, {"qty": 6, "sku": "x"}]}))
+        );
+        assert!(
+            !matcher.matches(&json!({"items": [{"qty": 1, "sku": "x"}, {"qty": 6, "sku": "y"}]}))
+        );
+        assert!(!matcher.matches(&json!({"items": {"qty": 6, "sku": "x"}})));
+    }
+
+    #[test]
+    pub fn test_nested_multiple_operators() {
+        let matcher = from_str(r#"{"scores":{"$elemMatch":{"$gte":80, "$lt":85}}}"#).unwrap();
+        assert!(!matcher.matches(&json!({"scores": [90]})));
+        assert!(matcher.matches(&json!({"scores": [90, 82]})));
+
+        let matcher = from_str(r#"{"a":{"$not":{"$gt":1, "$lt":5}}}"#).unwrap();
+        assert!(matcher.matches(&json!({"a": 10})));
+        assert!(!matcher.matches(&json!({"a": 3})));
+
+        let matcher = from_str(r#"{"a":{"$in":[{"$gt":1, "$lt":5}, 10]}}"#).unwrap();
+        assert!(matcher.matches(&json!({"a": 3})));
+        assert!(matcher.matches(&json!({"a": 10})));
+        assert!(!matcher.matches(&json!({"a": 7})));
+    }
+
+    #[test]
+    pub fn test_malformed_operators() {
+        assert!(from_str(r#"{"a":{"$gt":1, "$size":"two"}}"#).is_err());
+        assert!(from_str(r#"{"a":{"$in":1}}"#).is_err());
+        assert!(from_str(r#"{"a":{"$exists":"yes"}}"#).is_err());
+        assert!(from_str(r#"{"a":{"$not":{"$size":-1}}}"#).is_err());
+        assert!(from_str(r#"{"a":{"$bogus":1}}"#).is_err());
+        assert!(from_str(r#"{"a":{"$options":"i"}}"#).is_err());
+        assert!(from_str(r#"{"a":{"$gt":1, "b":2}}"#).is_err());
+
+        let matcher: ObjMatcher =
+            serde_json::from_value(json!({"a": {"$gt": 1, "$lt": 5}})).unwrap();
+        assert!(matcher.matches(&json!({"a": 3})));
+        assert!(!matcher.matches(&json!({"a": 5})));
+        assert!(serde_json::from_value::<ObjMatcher>(json!({"a": {"$regex": "("}})).is_err());
+    }
+
+    #[test]
+    pub fn test_scalars() {
+        let matcher = from_str(r#""hello""#).unwrap();
+        assert!(matcher.matches(&json!("hello")));
+        assert!(!matcher.matches(&json!("world")));
+        assert!(!matcher.matches(&json!(1)));
+
+        let matcher = from_str(r#"true"#).unwrap();
+        assert!(matcher.matches(&json!(true)));
+        assert!(!matcher.matches(&json!(false)));
+
+        let matcher = from_str(r#"null"#).unwrap();
+        assert!(matcher.matches(&json!(null)));
+        assert!(!matcher.matches(&json!({})));
+
+        let matcher = from_str(r#"{"a":{"$in":["x", true, null]}}"#).unwrap();
+        assert!(matcher.matches(&json!({"a": "x"})));
+        assert!(matcher.matches(&json!({"a": true})));
+        assert!(matcher.matches(&json!({"b": 1})));
+        assert!(!matcher.matches(&json!({"a": "y"})));
+    }
+
+    #[test]
+    pub fn test_dotted_path() {
+        let msg =
+            json!({"order": {"id": 7, "items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 3}]}});
+
+        assert!(from_str(r#"{"order.id": 7}"#).unwrap().matches(&msg));
+        assert!(!from_str(r#"{"order.id": 8}"#).unwrap().matches(&msg));
+        assert!(from_str(r#"{"order.items.0.sku": "a"}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(!from_str(r#"{"order.items.1.sku": "a"}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(from_str(r#"{"order.items.sku": "b"}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(from_str(r#"{"order.items.qty": {"$gt": 2}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(!from_str(r#"{"order.items.qty": {"$gt": 3}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(from_str(r#"{"order.missing": {"$exists": false}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(!from_str(r#"{"order.items.5.sku": {"$exists": true}}"#)
+            .unwrap()
+            .matches(&msg));
+    }
+
+    #[test]
+    pub fn test_nested_document() {
+        let msg = json!({"order": {"status": "paid", "customer": {"tier": "gold", "age": 40}}});
+
+        let matcher = from_str(r#"{"order": {"customer": {"tier": "gold"}}}"#).unwrap();
+        assert!(matcher.matches(&msg));
+
+        let matcher =
+            from_str(r#"{"order": {"status": "paid", "customer": {"age": {"$gte": 18}}}}"#)
+                .unwrap();
+        assert!(matcher.matches(&msg));
+
+        let matcher = from_str(r#"{"order": {"customer": {"tier": "silver"}}}"#).unwrap();
+        assert!(!matcher.matches(&msg));
+
+        let matcher = from_str(r#"{"order": {"status": {"x": 1}}}"#).unwrap();
+        assert!(!matcher.matches(&msg));
+    }
+
+    #[test]
+    pub fn test_array_any_element() {
+        let msg = json!({"tags": ["red", "green"], "scores": [3, 9]});
+
+        assert!(from_str(r#"{"tags": "green"}"#).unwrap().matches(&msg));
+        assert!(!from_str(r#"{"tags": "blue"}"#).unwrap().matches(&msg));
+        assert!(from_str(r#"{"tags": ["red", "green"]}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(from_str(r#"{"tags": {"$in": ["blue", "red"]}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(from_str(r#"{"tags": {"$regex": "^gr"}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(from_str(r#"{"scores": {"$gt": 5}}"#).unwrap().matches(&msg));
+        assert!(from_str(r#"{"scores": {"$size": 2}}"#)
+            .unwrap()
+            .matches(&msg));
+
+        // negations hold only if no element matches
+        assert!(!from_str(r#"{"tags": {"$ne": "red"}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(from_str(r#"{"tags": {"$ne": "blue"}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(!from_str(r#"{"tags": {"$nin": ["red"]}}"#)
+            .unwrap()
+            .matches(&msg));
+        assert!(!from_str(r#"{"scores": {"$not": {"$gt": 5}}}"#)
+            .unwrap()
+            .matches(&msg));
+    }
 }